@@ -0,0 +1,59 @@
+use super::AudioError;
+
+/// How interleaved device channels are turned into file channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", tag = "mode")]
+pub enum ChannelMode {
+    /// Keep every device channel, interleaved as delivered.
+    All,
+    /// Keep a single device channel (0-based).
+    Channel { index: u16 },
+    /// Average all device channels into mono.
+    Mix,
+}
+
+/// The first channel only, which is what Kiklet recorded before this was a setting.
+impl Default for ChannelMode {
+    fn default() -> Self {
+        ChannelMode::Channel { index: 0 }
+    }
+}
+
+impl ChannelMode {
+    /// Channel count of the written file for a device with `channels_in` channels.
+    pub fn output_channels(self, channels_in: u16) -> Result<u16, AudioError> {
+        let channels_in = channels_in.max(1);
+        match self {
+            ChannelMode::All => Ok(channels_in),
+            ChannelMode::Channel { index } if index >= channels_in => {
+                Err(AudioError::ChannelOutOfRange {
+                    index,
+                    channels: channels_in,
+                })
+            }
+            ChannelMode::Channel { .. } | ChannelMode::Mix => Ok(1),
+        }
+    }
+
+    /// Appends the mapped samples of `data` to `out`; trailing partial frames are ignored.
+    pub fn map_into(self, channels_in: u16, data: &[f32], out: &mut Vec<f32>) {
+        let channels_in = channels_in.max(1) as usize;
+        match self {
+            ChannelMode::All => {
+                let whole = data.len() - data.len() % channels_in;
+                out.extend_from_slice(&data[..whole]);
+            }
+            ChannelMode::Channel { index } => {
+                let index = index as usize;
+                out.extend(data.chunks_exact(channels_in).map(|frame| frame[index]));
+            }
+            ChannelMode::Mix => {
+                let scale = 1.0 / channels_in as f32;
+                out.extend(
+                    data.chunks_exact(channels_in)
+                        .map(|frame| frame.iter().sum::<f32>() * scale),
+                );
+            }
+        }
+    }
+}
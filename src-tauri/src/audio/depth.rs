@@ -0,0 +1,46 @@
+/// Sample format written to the recording file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SampleDepth {
+    /// Smallest files; plenty for speech.
+    #[default]
+    Int16,
+    /// Keeps the full range of 24-bit interfaces.
+    Int24,
+    /// Lossless for float devices, with headroom above 0 dBFS.
+    Float32,
+}
+
+impl SampleDepth {
+    pub fn bits_per_sample(self) -> u16 {
+        match self {
+            SampleDepth::Int16 => 16,
+            SampleDepth::Int24 => 24,
+            SampleDepth::Float32 => 32,
+        }
+    }
+
+    pub fn wav_spec(self, channels: u16, sample_rate: u32) -> hound::WavSpec {
+        hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: self.bits_per_sample(),
+            sample_format: match self {
+                SampleDepth::Float32 => hound::SampleFormat::Float,
+                SampleDepth::Int16 | SampleDepth::Int24 => hound::SampleFormat::Int,
+            },
+        }
+    }
+}
+
+pub(crate) fn f32_to_i16(s: f32) -> i16 {
+    // Mirror of cpal's i16 -> f32 scaling so 16-bit input round-trips exactly.
+    (s * 32768.0)
+        .round()
+        .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+pub(crate) fn f32_to_i24(s: f32) -> i32 {
+    // Scaling by a power of two is exact in f32, so 24-bit input round-trips too.
+    (s * 8_388_608.0).round().clamp(-8_388_608.0, 8_388_607.0) as i32
+}
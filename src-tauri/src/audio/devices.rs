@@ -0,0 +1,61 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+
+use super::AudioError;
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportedInputConfig {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputDeviceInfo {
+    /// cpal 0.15 has no stable device identifiers, so the device name doubles as the id.
+    pub id: String,
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<SupportedInputConfig>,
+}
+
+pub fn list_input_devices() -> Result<Vec<InputDeviceInfo>, AudioError> {
+    let host = cpal::default_host();
+    let default_name = host.default_input_device().and_then(|d| d.name().ok());
+
+    let mut out = Vec::new();
+    for device in host.input_devices()? {
+        let Ok(name) = device.name() else { continue };
+        // Devices that vanish or refuse to report configs are still worth listing.
+        let configs = device
+            .supported_input_configs()
+            .map(|configs| {
+                configs
+                    .map(|c| SupportedInputConfig {
+                        channels: c.channels(),
+                        min_sample_rate: c.min_sample_rate().0,
+                        max_sample_rate: c.max_sample_rate().0,
+                        sample_format: c.sample_format().to_string(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        out.push(InputDeviceInfo {
+            id: name.clone(),
+            is_default: default_name.as_deref() == Some(name.as_str()),
+            name,
+            configs,
+        });
+    }
+    Ok(out)
+}
+
+pub fn find_input_device(id: &str) -> Result<cpal::Device, AudioError> {
+    let host = cpal::default_host();
+    host.input_devices()?
+        .find(|d| d.name().map(|n| n == id).unwrap_or(false))
+        .ok_or_else(|| AudioError::InputDeviceNotFound(id.to_string()))
+}
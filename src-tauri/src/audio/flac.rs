@@ -0,0 +1,843 @@
+//! Streaming FLAC encoder, plus the parts of the format storage needs to read back.
+//!
+//! Deliberately small: fixed block size, independent channels and fixed predictors
+//! with partitioned Rice coding. That gets most of the way to the reference encoder on
+//! speech, keeps the writer thread cheap, and never holds more than one block in memory.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::AudioError;
+
+/// Samples per channel in every frame but the last; the reference encoder's default.
+pub const BLOCK_SIZE: usize = 4096;
+const MAGIC: &[u8; 4] = b"fLaC";
+const STREAMINFO_LEN: usize = 34;
+/// The STREAMINFO body starts after the magic and its 4-byte block header.
+const STREAMINFO_OFFSET: u64 = 8;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 6;
+/// 15 is the escape code, which we never need with parameters this large.
+const MAX_RICE_PARAM: u32 = 14;
+const FRAME_SYNC: [u8; 2] = [0xFF, 0xF8];
+
+const fn crc8_table() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC8: [u8; 256] = crc8_table();
+static CRC16: [u16; 256] = crc16_table();
+
+/// Frame header checksum.
+pub(crate) fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &b| CRC8[(crc ^ b) as usize])
+}
+
+/// Whole-frame checksum; feed bytes one at a time with `crc16_update` when scanning.
+pub(crate) fn crc16_update(crc: u16, b: u8) -> u16 {
+    (crc << 8) ^ CRC16[((crc >> 8) as u8 ^ b) as usize]
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &b| crc16_update(crc, b))
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// Appends the low `n` bits of `value`, most significant first; `n` is at most 32.
+    fn write(&mut self, value: u64, n: u32) {
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (value & ((1u64 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1u64 << self.bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, n: u32) {
+        self.write(value as u64, n);
+    }
+
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    fn write_utf8_number(&mut self, n: u64) {
+        if n < 0x80 {
+            self.write(n, 8);
+            return;
+        }
+        // A k-byte sequence carries 5k + 1 bits.
+        let mut len = 2;
+        while n >= 1u64 << (5 * len + 1) {
+            len += 1;
+        }
+        let prefix = (0xFF00u64 >> len) & 0xFF;
+        self.write(prefix | (n >> (6 * (len - 1))), 8);
+        for i in (0..len - 1).rev() {
+            self.write(0x80 | ((n >> (6 * i)) & 0x3F), 8);
+        }
+    }
+}
+
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+/// Prediction error of the fixed predictor of `order`, for samples from `order` on.
+fn fixed_residual(x: &[i32], order: usize, out: &mut Vec<i64>) {
+    out.clear();
+    out.extend((order..x.len()).map(|i| {
+        let s = |k: usize| x[i - k] as i64;
+        match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - 2 * s(1) + s(2),
+            3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+            _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+        }
+    }));
+}
+
+/// Partition order and per-partition Rice parameters, with an estimate of their cost.
+struct RicePlan {
+    partition_order: u32,
+    params: Vec<u32>,
+    bits: u64,
+}
+
+fn best_rice_param(sum: u64, len: u64) -> (u32, u64) {
+    (0..=MAX_RICE_PARAM)
+        .map(|k| (k, len * (k as u64 + 1) + (sum >> k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, u64::MAX))
+}
+
+fn plan_rice(residual: &[i64], block_len: usize, order: usize) -> RicePlan {
+    let mut best: Option<RicePlan> = None;
+    for p in 0..=MAX_PARTITION_ORDER {
+        let parts = 1usize << p;
+        if !block_len.is_multiple_of(parts) || block_len / parts <= order {
+            break;
+        }
+        let mut params = Vec::with_capacity(parts);
+        let mut bits = 0u64;
+        let mut start = 0;
+        for i in 0..parts {
+            let len = block_len / parts - if i == 0 { order } else { 0 };
+            let sum: u64 = residual[start..start + len]
+                .iter()
+                .map(|&r| zigzag(r))
+                .sum();
+            let (k, cost) = best_rice_param(sum, len as u64);
+            params.push(k);
+            bits += 4 + cost;
+            start += len;
+        }
+        if best.as_ref().is_none_or(|b| bits < b.bits) {
+            best = Some(RicePlan {
+                partition_order: p,
+                params,
+                bits,
+            });
+        }
+    }
+    best.unwrap_or(RicePlan {
+        partition_order: 0,
+        params: vec![MAX_RICE_PARAM],
+        bits: u64::MAX,
+    })
+}
+
+fn write_subframe(w: &mut BitWriter, x: &[i32], bps: u32, scratch: &mut Vec<i64>) {
+    if x.iter().all(|&s| s == x[0]) {
+        w.write(0, 8);
+        w.write_signed(x[0] as i64, bps);
+        return;
+    }
+
+    let verbatim_bits = x.len() as u64 * bps as u64;
+    let mut best: Option<(usize, RicePlan, u64)> = None;
+    for order in 0..=MAX_FIXED_ORDER.min(x.len() - 1) {
+        fixed_residual(x, order, scratch);
+        let plan = plan_rice(scratch, x.len(), order);
+        let bits = plan.bits.saturating_add(order as u64 * bps as u64);
+        if best.as_ref().is_none_or(|&(_, _, b)| bits < b) {
+            best = Some((order, plan, bits));
+        }
+    }
+
+    match best {
+        Some((order, plan, bits)) if bits < verbatim_bits => {
+            w.write(0b0001_0000 | ((order as u64) << 1), 8);
+            for &s in &x[..order] {
+                w.write_signed(s as i64, bps);
+            }
+            fixed_residual(x, order, scratch);
+            w.write(0, 2);
+            w.write(plan.partition_order as u64, 4);
+            let parts = 1usize << plan.partition_order;
+            let mut start = 0;
+            for (i, &k) in plan.params.iter().enumerate() {
+                let len = x.len() / parts - if i == 0 { order } else { 0 };
+                w.write(k as u64, 4);
+                for &r in &scratch[start..start + len] {
+                    let u = zigzag(r);
+                    w.write_unary(u >> k);
+                    w.write(u, k);
+                }
+                start += len;
+            }
+        }
+        _ => {
+            w.write(0b0000_0010, 8);
+            for &s in x {
+                w.write_signed(s as i64, bps);
+            }
+        }
+    }
+}
+
+fn sample_rate_code(rate: u32) -> u64 {
+    match rate {
+        88_200 => 1,
+        176_400 => 2,
+        192_000 => 3,
+        8_000 => 4,
+        16_000 => 5,
+        22_050 => 6,
+        24_000 => 7,
+        32_000 => 8,
+        44_100 => 9,
+        48_000 => 10,
+        96_000 => 11,
+        // Taken from STREAMINFO.
+        _ => 0,
+    }
+}
+
+fn sample_size_code(bps: u32) -> u64 {
+    match bps {
+        8 => 1,
+        12 => 2,
+        16 => 4,
+        20 => 5,
+        24 => 6,
+        _ => 0,
+    }
+}
+
+/// Writes FLAC to `out` as samples arrive, one frame per `BLOCK_SIZE` samples per channel.
+///
+/// `checkpoint` patches STREAMINFO with what has been encoded so far, so a file cut
+/// short by a crash still reports a sensible length; `finalize` writes the last,
+/// shorter frame.
+pub struct FlacWriter<W: Write + Seek> {
+    out: W,
+    channels: usize,
+    bits_per_sample: u32,
+    sample_rate: u32,
+    /// Interleaved samples waiting for a full block.
+    pending: Vec<i32>,
+    planar: Vec<i32>,
+    scratch: Vec<i64>,
+    frame_number: u64,
+    total_samples: u64,
+    min_frame_size: u32,
+    max_frame_size: u32,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    /// `bits_per_sample` is 8, 16 or 24; samples are signed integers in that range.
+    pub fn new(
+        mut out: W,
+        channels: u16,
+        bits_per_sample: u16,
+        sample_rate: u32,
+    ) -> io::Result<Self> {
+        let channels = channels.clamp(1, 8) as usize;
+        out.write_all(MAGIC)?;
+        // Last metadata block, type 0 (STREAMINFO).
+        out.write_all(&[0x80, 0, 0, STREAMINFO_LEN as u8])?;
+        let mut writer = Self {
+            out,
+            channels,
+            bits_per_sample: bits_per_sample as u32,
+            sample_rate,
+            pending: Vec::with_capacity(BLOCK_SIZE * channels),
+            planar: Vec::with_capacity(BLOCK_SIZE),
+            scratch: Vec::with_capacity(BLOCK_SIZE),
+            frame_number: 0,
+            total_samples: 0,
+            min_frame_size: 0,
+            max_frame_size: 0,
+        };
+        let info = writer.streaminfo();
+        writer.out.write_all(&info)?;
+        Ok(writer)
+    }
+
+    pub fn write_sample(&mut self, sample: i32) -> io::Result<()> {
+        self.pending.push(sample);
+        if self.pending.len() == BLOCK_SIZE * self.channels {
+            self.write_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Makes everything encoded so far durable-ready and describes it in STREAMINFO.
+    /// Samples still waiting for a full block are not included.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        let info = self.streaminfo();
+        self.out.write_all(&info)?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()
+    }
+
+    pub fn finalize(mut self) -> io::Result<()> {
+        // Drop a trailing partial frame, as the WAV writer would.
+        let whole = self.pending.len() - self.pending.len() % self.channels;
+        self.pending.truncate(whole);
+        if !self.pending.is_empty() {
+            self.write_frame()?;
+        }
+        self.checkpoint()
+    }
+
+    fn streaminfo(&self) -> [u8; STREAMINFO_LEN] {
+        let mut w = BitWriter::default();
+        w.write(BLOCK_SIZE as u64, 16);
+        w.write(BLOCK_SIZE as u64, 16);
+        w.write(self.min_frame_size as u64, 24);
+        w.write(self.max_frame_size as u64, 24);
+        w.write(self.sample_rate as u64, 20);
+        w.write(self.channels as u64 - 1, 3);
+        w.write(self.bits_per_sample as u64 - 1, 5);
+        w.write(self.total_samples >> 32, 4);
+        w.write(self.total_samples, 32);
+        // No MD5 signature: all zeros means "not computed".
+        for _ in 0..4 {
+            w.write(0, 32);
+        }
+        let mut out = [0u8; STREAMINFO_LEN];
+        out.copy_from_slice(&w.bytes);
+        out
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let block_len = self.pending.len() / self.channels;
+        let bps = self.bits_per_sample;
+        let mut w = BitWriter::default();
+
+        let block_code = match block_len {
+            BLOCK_SIZE => 12,
+            n if n <= 256 => 6,
+            _ => 7,
+        };
+        // Sync code, a reserved bit, then fixed-blocksize strategy.
+        w.write(0x3FFE, 14);
+        w.write(0, 1);
+        w.write(0, 1);
+        w.write(block_code, 4);
+        w.write(sample_rate_code(self.sample_rate), 4);
+        // Independent channels.
+        w.write(self.channels as u64 - 1, 4);
+        w.write(sample_size_code(bps), 3);
+        w.write(0, 1);
+        w.write_utf8_number(self.frame_number);
+        match block_code {
+            6 => w.write(block_len as u64 - 1, 8),
+            7 => w.write(block_len as u64 - 1, 16),
+            _ => {}
+        }
+        let header_crc = crc8(&w.bytes);
+        w.write(header_crc as u64, 8);
+
+        for ch in 0..self.channels {
+            self.planar.clear();
+            self.planar
+                .extend(self.pending.iter().skip(ch).step_by(self.channels));
+            write_subframe(&mut w, &self.planar, bps, &mut self.scratch);
+        }
+        w.align();
+        let frame_crc = crc16(&w.bytes);
+        w.write(frame_crc as u64, 16);
+
+        self.out.write_all(&w.bytes)?;
+        let size = w.bytes.len() as u32;
+        self.min_frame_size = if self.frame_number == 0 {
+            size
+        } else {
+            self.min_frame_size.min(size)
+        };
+        self.max_frame_size = self.max_frame_size.max(size);
+        self.frame_number += 1;
+        self.total_samples += block_len as u64;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+/// Longest frame header we write or accept: sync, codes, 7-byte frame number,
+/// 16-bit block size, 16-bit sample rate and the CRC.
+pub(crate) const MAX_FRAME_HEADER_LEN: usize = 16;
+
+/// The parts of a frame header needed to walk a stream frame by frame.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameHeader {
+    pub frame_number: u64,
+    pub block_len: u64,
+    pub len: usize,
+}
+
+/// Parses a fixed-block-size frame header at the start of `bytes`, checking its CRC.
+pub(crate) fn parse_frame_header(bytes: &[u8]) -> Option<FrameHeader> {
+    if bytes.len() < 5 || bytes[0..2] != FRAME_SYNC {
+        return None;
+    }
+    let block_code = bytes[2] >> 4;
+    let rate_code = bytes[2] & 0x0F;
+
+    let first = bytes[4];
+    let extra = match first.leading_ones() {
+        0 => 0,
+        n @ 2..=7 => n as usize - 1,
+        _ => return None,
+    };
+    let mut frame_number = (first & (0x7F >> extra)) as u64;
+    let mut i = 5;
+    for _ in 0..extra {
+        let b = *bytes.get(i)?;
+        if b & 0xC0 != 0x80 {
+            return None;
+        }
+        frame_number = frame_number << 6 | (b & 0x3F) as u64;
+        i += 1;
+    }
+
+    let block_len = match block_code {
+        1 => 192,
+        2..=5 => 576 << (block_code - 2),
+        6 => {
+            i += 1;
+            *bytes.get(i - 1)? as u64 + 1
+        }
+        7 => {
+            i += 2;
+            u16::from_be_bytes([*bytes.get(i - 2)?, *bytes.get(i - 1)?]) as u64 + 1
+        }
+        8..=15 => 256 << (block_code - 8),
+        _ => return None,
+    };
+    i += match rate_code {
+        12 => 1,
+        13 | 14 => 2,
+        15 => return None,
+        _ => 0,
+    };
+
+    let crc = *bytes.get(i)?;
+    if crc8(&bytes[..i]) != crc {
+        return None;
+    }
+    Some(FrameHeader {
+        frame_number,
+        block_len,
+        len: i + 1,
+    })
+}
+
+/// Offset of the first frame, after the magic and every metadata block.
+pub(crate) fn first_frame_offset<R: Read + Seek>(r: &mut R) -> io::Result<u64> {
+    let mut magic = [0u8; 4];
+    r.seek(SeekFrom::Start(0))?;
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a FLAC file"));
+    }
+    let mut pos = 4u64;
+    loop {
+        let mut header = [0u8; 4];
+        r.seek(SeekFrom::Start(pos))?;
+        r.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        pos += 4 + len;
+        if header[0] & 0x80 != 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+/// Sets the sample count in STREAMINFO and marks the frame sizes as unknown,
+/// for a stream whose frames have been cut short after the fact.
+pub(crate) fn rewrite_total_samples<F: Read + Write + Seek>(
+    f: &mut F,
+    total_samples: u64,
+) -> io::Result<()> {
+    // Min/max frame size (3 bytes each) start 4 bytes into STREAMINFO.
+    f.seek(SeekFrom::Start(STREAMINFO_OFFSET + 4))?;
+    f.write_all(&[0; 6])?;
+    // Byte 13 shares its high nibble with the sample format; the count takes the low one.
+    let mut b13 = [0u8; 1];
+    f.seek(SeekFrom::Start(STREAMINFO_OFFSET + 13))?;
+    f.read_exact(&mut b13)?;
+    b13[0] = (b13[0] & 0xF0) | ((total_samples >> 32) & 0x0F) as u8;
+    f.seek(SeekFrom::Start(STREAMINFO_OFFSET + 13))?;
+    f.write_all(&b13)?;
+    f.write_all(&(total_samples as u32).to_be_bytes())?;
+    Ok(())
+}
+
+/// What storage needs from a FLAC file without decoding it.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    /// Samples per channel; 0 when the encoder did not know.
+    pub total_samples: u64,
+}
+
+impl StreamInfo {
+    pub fn duration_sec(&self) -> f64 {
+        self.total_samples as f64 / self.sample_rate.max(1) as f64
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads the STREAMINFO block, which every FLAC file starts with.
+pub fn read_stream_info(path: &Path) -> io::Result<StreamInfo> {
+    let mut head = [0u8; STREAMINFO_OFFSET as usize + STREAMINFO_LEN];
+    File::open(path)?.read_exact(&mut head)?;
+    if &head[0..4] != MAGIC || head[4] & 0x7F != 0 {
+        return Err(invalid("not a FLAC file"));
+    }
+    let b = &head[STREAMINFO_OFFSET as usize..];
+    let sample_rate = (b[10] as u32) << 12 | (b[11] as u32) << 4 | (b[12] as u32) >> 4;
+    let channels = ((b[12] >> 1) & 0x07) as u16 + 1;
+    let bits_per_sample = (((b[12] & 0x01) << 4) | (b[13] >> 4)) as u16 + 1;
+    let total_samples =
+        ((b[13] & 0x0F) as u64) << 32 | u32::from_be_bytes([b[14], b[15], b[16], b[17]]) as u64;
+    Ok(StreamInfo {
+        sample_rate,
+        channels,
+        bits_per_sample,
+        total_samples,
+    })
+}
+
+/// Losslessly re-encodes an integer PCM WAV as FLAC, then decodes the result and checks
+/// it sample for sample, so a caller may delete the WAV once this returns `Ok`.
+///
+/// Float WAVs are refused: FLAC only stores integers, so converting them would lose precision.
+pub fn transcode_wav(src: &Path, dst: &Path) -> Result<(), AudioError> {
+    let mut reader = hound::WavReader::open(src)?;
+    let spec = reader.spec();
+    if spec.sample_format != hound::SampleFormat::Int
+        || !matches!(spec.bits_per_sample, 8 | 16 | 24)
+    {
+        let kind = match spec.sample_format {
+            hound::SampleFormat::Float => "float",
+            hound::SampleFormat::Int => "integer",
+        };
+        return Err(AudioError::FlacUnsupportedSamples(format!(
+            "{}-bit {kind}",
+            spec.bits_per_sample
+        )));
+    }
+
+    let out = BufWriter::new(File::create(dst)?);
+    let mut writer = FlacWriter::new(out, spec.channels, spec.bits_per_sample, spec.sample_rate)?;
+    for sample in reader.samples::<i32>() {
+        writer.write_sample(sample?)?;
+    }
+    writer.finalize()?;
+    verify_against_wav(src, dst)
+}
+
+/// Decodes `flac` with an independent decoder and checks it holds exactly the samples
+/// of `wav`, less a trailing partial frame, which the encoder drops.
+pub fn verify_against_wav(wav: &Path, flac: &Path) -> Result<(), AudioError> {
+    let mismatch = |what: String| AudioError::FlacMismatch(what);
+    let mut reader = hound::WavReader::open(wav)?;
+    let spec = reader.spec();
+    let mut decoder = claxon::FlacReader::open(flac).map_err(|e| mismatch(e.to_string()))?;
+    let info = decoder.streaminfo();
+    if (info.sample_rate, info.channels, info.bits_per_sample)
+        != (
+            spec.sample_rate,
+            spec.channels as u32,
+            spec.bits_per_sample as u32,
+        )
+    {
+        return Err(mismatch(format!(
+            "stream is {} Hz, {} channels, {}-bit",
+            info.sample_rate, info.channels, info.bits_per_sample
+        )));
+    }
+
+    let mut expected = reader.samples::<i32>();
+    let mut count = 0u64;
+    for decoded in decoder.samples() {
+        let decoded = decoded.map_err(|e| mismatch(e.to_string()))?;
+        match expected.next().transpose()? {
+            Some(sample) if sample == decoded => count += 1,
+            Some(_) => return Err(mismatch(format!("sample {count} differs"))),
+            None => return Err(mismatch(format!("more than {count} samples"))),
+        }
+    }
+    let left = expected.count() as u64;
+    let frames = count / spec.channels.max(1) as u64;
+    if left >= spec.channels as u64 || info.samples.is_some_and(|n| n != frames) {
+        return Err(mismatch(format!("{count} samples decoded, {left} missing")));
+    }
+    Ok(())
+}
+
+/// Deterministic quiet mono noise for tests, as 16-bit samples.
+#[cfg(test)]
+pub(crate) fn test_noise(frames: usize) -> Vec<i32> {
+    (0..frames)
+        .map(|i| ((i * 7_919) % 4_001) as i32 - 2_000)
+        .collect()
+}
+
+/// Writes `test_noise` as a mono 16-bit 48 kHz FLAC file and returns the samples.
+#[cfg(test)]
+pub(crate) fn write_test_file(path: &Path, frames: usize) -> Vec<i32> {
+    let samples = test_noise(frames);
+    let mut writer = FlacWriter::new(File::create(path).unwrap(), 1, 16, 48_000).unwrap();
+    for &s in &samples {
+        writer.write_sample(s).unwrap();
+    }
+    writer.finalize().unwrap();
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Deterministic test signal: a sine with noise on top, plus both extremes of the range.
+    fn signal(frames: usize, channels: usize, bps: u32) -> Vec<i32> {
+        let max = (1i64 << (bps - 1)) - 1;
+        let min = -(1i64 << (bps - 1));
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        let mut noise = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % 2001) as f64 / 1000.0 - 1.0
+        };
+        (0..frames * channels)
+            .map(|i| {
+                let (frame, ch) = (i / channels, i % channels);
+                match frame % 997 {
+                    0 => max as i32,
+                    1 => min as i32,
+                    _ => {
+                        let t = frame as f64 / 48_000.0;
+                        let tone = (t * 440.0 * (ch + 1) as f64 * std::f64::consts::TAU).sin();
+                        let v = (tone * 0.6 + noise() * 0.05) * max as f64;
+                        v.round().clamp(min as f64, max as f64) as i32
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn encode(samples: &[i32], channels: u16, bps: u16) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = FlacWriter::new(&mut out, channels, bps, 48_000).unwrap();
+        for &s in samples {
+            writer.write_sample(s).unwrap();
+        }
+        writer.finalize().unwrap();
+        out.into_inner()
+    }
+
+    fn decode(bytes: Vec<u8>) -> (claxon::metadata::StreamInfo, Vec<i32>) {
+        let mut reader = claxon::FlacReader::new(Cursor::new(bytes)).unwrap();
+        let info = reader.streaminfo();
+        let samples = reader.samples().collect::<Result<Vec<_>, _>>().unwrap();
+        (info, samples)
+    }
+
+    fn assert_round_trip(frames: usize, channels: u16, bps: u16) {
+        let samples = signal(frames, channels as usize, bps as u32);
+        let (info, decoded) = decode(encode(&samples, channels, bps));
+        assert_eq!(info.channels, channels as u32);
+        assert_eq!(info.bits_per_sample, bps as u32);
+        assert_eq!(info.samples, Some(frames as u64));
+        assert!(
+            decoded == samples,
+            "{channels} ch, {bps}-bit, {frames} frames"
+        );
+    }
+
+    #[test]
+    fn round_trips_16_and_24_bit_mono_and_stereo() {
+        for bps in [16, 24] {
+            for channels in [1, 2] {
+                assert_round_trip(BLOCK_SIZE * 3 + 1234, channels, bps);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_8_bit() {
+        assert_round_trip(BLOCK_SIZE + 17, 1, 8);
+    }
+
+    #[test]
+    fn round_trips_odd_block_sizes() {
+        // Short last blocks use an 8-bit size below 257 frames and a 16-bit one above.
+        for frames in [
+            1,
+            2,
+            5,
+            255,
+            256,
+            257,
+            1000,
+            BLOCK_SIZE - 1,
+            BLOCK_SIZE,
+            BLOCK_SIZE + 1,
+        ] {
+            assert_round_trip(frames, 2, 16);
+            assert_round_trip(frames, 1, 24);
+        }
+    }
+
+    #[test]
+    fn round_trips_constant_and_full_scale_blocks() {
+        let silence = vec![0; BLOCK_SIZE * 2];
+        assert_eq!(decode(encode(&silence, 2, 16)).1, silence);
+        // Alternating extremes leave nothing to predict, so they go out verbatim.
+        let square: Vec<i32> = (0..BLOCK_SIZE + 300)
+            .map(|i| {
+                if i % 2 == 0 {
+                    (1 << 23) - 1
+                } else {
+                    -(1 << 23)
+                }
+            })
+            .collect();
+        assert_eq!(decode(encode(&square, 1, 24)).1, square);
+    }
+
+    #[test]
+    fn frame_headers_walk_the_stream() {
+        let frames = BLOCK_SIZE * 2 + 100;
+        let bytes = encode(&signal(frames, 2, 16), 2, 16);
+        let mut cursor = Cursor::new(&bytes);
+        let mut at = first_frame_offset(&mut cursor).unwrap() as usize;
+        let mut headers = Vec::new();
+        while at < bytes.len() {
+            let header = parse_frame_header(&bytes[at..]).expect("frame header");
+            headers.push(header);
+            // Find the next sync code after this header; good enough for three frames.
+            at = (at + header.len..bytes.len())
+                .find(|&i| {
+                    bytes[i..].starts_with(&FRAME_SYNC) && parse_frame_header(&bytes[i..]).is_some()
+                })
+                .unwrap_or(bytes.len());
+        }
+        let numbers: Vec<u64> = headers.iter().map(|h| h.frame_number).collect();
+        let lens: Vec<u64> = headers.iter().map(|h| h.block_len).collect();
+        assert_eq!(numbers, [0, 1, 2]);
+        assert_eq!(lens, [BLOCK_SIZE as u64, BLOCK_SIZE as u64, 100]);
+    }
+
+    #[test]
+    fn transcode_checks_its_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let wav = dir.path().join("a.wav");
+        let flac = dir.path().join("a.flac");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48_000,
+            bits_per_sample: 24,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&wav, spec).unwrap();
+        for s in signal(BLOCK_SIZE + 5, 2, 24) {
+            writer.write_sample(s).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        transcode_wav(&wav, &flac).unwrap();
+        let info = read_stream_info(&flac).unwrap();
+        assert_eq!(info.total_samples, BLOCK_SIZE as u64 + 5);
+
+        // Flip a bit in the last frame's audio; the check must notice.
+        let mut bytes = std::fs::read(&flac).unwrap();
+        let n = bytes.len();
+        bytes[n - 10] ^= 0x01;
+        std::fs::write(&flac, bytes).unwrap();
+        assert!(verify_against_wav(&wav, &flac).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::Path;
+
+use super::{opus, SampleDepth};
+
+/// Container and codec of the recording file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputFormat {
+    /// Uncompressed PCM; opens anywhere.
+    #[default]
+    Wav,
+    /// Lossless and roughly half the size. FLAC has no float samples, so
+    /// `SampleDepth::Float32` is written as 24-bit.
+    Flac,
+    /// Lossy Opus in Ogg, a small fraction of WAV for speech. Mono or stereo only;
+    /// `SampleDepth` does not apply and rates Opus can't take are resampled to 48 kHz.
+    Opus,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Wav => "wav",
+            OutputFormat::Flac => "flac",
+            OutputFormat::Opus => "opus",
+        }
+    }
+
+    /// The file rate actually used when `rate` is wanted.
+    pub fn file_sample_rate(self, rate: u32) -> u32 {
+        match self {
+            OutputFormat::Opus if !opus::SUPPORTED_RATES.contains(&rate) => opus::GRANULE_RATE,
+            _ => rate,
+        }
+    }
+
+    /// The depth actually written when `depth` is requested.
+    pub fn effective_depth(self, depth: SampleDepth) -> SampleDepth {
+        match (self, depth) {
+            (OutputFormat::Flac, SampleDepth::Float32) => SampleDepth::Int24,
+            _ => depth,
+        }
+    }
+
+    /// Most frames one file can hold. WAV sizes are 32-bit; the margin leaves room
+    /// for the header and the metadata chunks added after the audio.
+    pub fn max_file_frames(self, spec: &hound::WavSpec) -> Option<u64> {
+        const WAV_MARGIN: u64 = 1 << 20;
+        match self {
+            OutputFormat::Wav => {
+                let frame_bytes = spec.channels.max(1) as u64 * (spec.bits_per_sample as u64 / 8);
+                Some((u32::MAX as u64 - WAV_MARGIN) / frame_bytes.max(1))
+            }
+            OutputFormat::Flac | OutputFormat::Opus => None,
+        }
+    }
+
+    /// The format a recording file is in, judged by its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "wav" => Some(OutputFormat::Wav),
+            "flac" => Some(OutputFormat::Flac),
+            "opus" => Some(OutputFormat::Opus),
+            _ => None,
+        }
+    }
+}
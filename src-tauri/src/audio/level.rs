@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Linear levels in `[0.0, 1.0]` over the blocks seen since the last read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputLevel {
+    pub rms: f32,
+    pub peak: f32,
+}
+
+/// Lock-free meter fed from the audio callback and read from any other thread.
+///
+/// Non-negative `f32`s order the same as their bit patterns, so `fetch_max`
+/// on the raw bits keeps the loudest block since the last `take`.
+#[derive(Debug, Default)]
+pub struct LevelMeter {
+    rms_bits: AtomicU32,
+    peak_bits: AtomicU32,
+    /// The window most recently closed by `take`, for readers that must not start a new one.
+    latest_rms_bits: AtomicU32,
+    latest_peak_bits: AtomicU32,
+}
+
+impl LevelMeter {
+    pub fn update(&self, samples: &[f32]) {
+        if samples.is_empty() {
+            return;
+        }
+        let mut peak = 0.0f32;
+        let mut sum_sq = 0.0f32;
+        for &s in samples {
+            peak = peak.max(s.abs());
+            sum_sq += s * s;
+        }
+        let rms = (sum_sq / samples.len() as f32).sqrt();
+        self.rms_bits
+            .fetch_max(rms.min(1.0).to_bits(), Ordering::Relaxed);
+        self.peak_bits
+            .fetch_max(peak.min(1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the levels accumulated so far and starts a new window.
+    pub fn take(&self) -> InputLevel {
+        let rms_bits = self.rms_bits.swap(0, Ordering::Relaxed);
+        let peak_bits = self.peak_bits.swap(0, Ordering::Relaxed);
+        self.latest_rms_bits.store(rms_bits, Ordering::Relaxed);
+        self.latest_peak_bits.store(peak_bits, Ordering::Relaxed);
+        InputLevel {
+            rms: f32::from_bits(rms_bits),
+            peak: f32::from_bits(peak_bits),
+        }
+    }
+
+    /// The levels returned by the last `take`; the current window is left alone.
+    pub fn latest(&self) -> InputLevel {
+        InputLevel {
+            rms: f32::from_bits(self.latest_rms_bits.load(Ordering::Relaxed)),
+            peak: f32::from_bits(self.latest_peak_bits.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latest_does_not_start_a_new_window() {
+        let meter = LevelMeter::default();
+        meter.update(&[0.5, -0.5]);
+        assert_eq!(meter.latest(), InputLevel::default());
+
+        let first = meter.take();
+        assert_eq!(first.peak, 0.5);
+        meter.update(&[0.25]);
+        assert_eq!(meter.latest(), first);
+        assert_eq!(meter.latest(), first);
+        assert_eq!(meter.take().peak, 0.25);
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::{debug_log, AudioError, StopReason};
+
+const MB: u64 = 1024 * 1024;
+/// How often a running session looks at free disk space.
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Guardrails so a recording left running can't fill the disk.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RecordingLimits {
+    /// Recorded audio after which the session stops itself; paused time doesn't count.
+    /// `None` for no limit.
+    pub max_duration_minutes: Option<u32>,
+    /// Free space on the recordings volume below which recording won't start, and a
+    /// running one stops. 0 turns the check off.
+    pub min_free_space_mb: u64,
+}
+
+impl Default for RecordingLimits {
+    fn default() -> Self {
+        Self {
+            max_duration_minutes: None,
+            min_free_space_mb: 500,
+        }
+    }
+}
+
+impl RecordingLimits {
+    pub fn max_duration_sec(&self) -> Option<u64> {
+        self.max_duration_minutes
+            .filter(|&m| m > 0)
+            .map(|m| m as u64 * 60)
+    }
+
+    /// Refuses to start on a volume that is already below the threshold. A volume
+    /// whose free space can't be read is given the benefit of the doubt.
+    pub fn check_free_space(&self, dir: &Path) -> Result<(), AudioError> {
+        match self.low_disk_space(dir) {
+            Some(StopReason::LowDiskSpace {
+                available_mb,
+                required_mb,
+            }) => Err(AudioError::InsufficientDiskSpace {
+                available_mb,
+                required_mb,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn low_disk_space(&self, dir: &Path) -> Option<StopReason> {
+        if self.min_free_space_mb == 0 {
+            return None;
+        }
+        let available = match fs2::available_space(dir) {
+            Ok(available) => available,
+            Err(err) => {
+                debug_log(&format!("cannot read free space: {err}"));
+                return None;
+            }
+        };
+        (available < self.min_free_space_mb.saturating_mul(MB)).then_some(
+            StopReason::LowDiskSpace {
+                available_mb: available / MB,
+                required_mb: self.min_free_space_mb,
+            },
+        )
+    }
+}
+
+/// Checks a running session against its limits; disk space only every few seconds.
+pub(crate) struct LimitWatch {
+    limits: RecordingLimits,
+    dir: PathBuf,
+    last_disk_check: Instant,
+}
+
+impl LimitWatch {
+    pub fn new(limits: RecordingLimits, dir: PathBuf, now: Instant) -> Self {
+        Self {
+            limits,
+            dir,
+            last_disk_check: now,
+        }
+    }
+
+    /// Returns why the session has to stop, if it has. `recorded_sec` is audio captured so far.
+    pub fn check(&mut self, recorded_sec: f64, now: Instant) -> Option<StopReason> {
+        if let Some(limit_sec) = self.limits.max_duration_sec() {
+            if recorded_sec >= limit_sec as f64 {
+                return Some(StopReason::MaxDuration { limit_sec });
+            }
+        }
+        if now.duration_since(self.last_disk_check) < DISK_CHECK_INTERVAL {
+            return None;
+        }
+        self.last_disk_check = now;
+        self.limits.low_disk_space(&self.dir)
+    }
+}
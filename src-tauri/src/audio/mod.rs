@@ -1,18 +1,47 @@
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    mpsc, Arc, Mutex,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc, Arc,
 };
+use std::time::{Duration, Instant};
 use time::format_description::FormatItem;
 use time::macros::format_description;
 
+pub mod channels;
+pub mod depth;
+pub mod devices;
+pub mod flac;
+mod format;
+pub mod level;
+pub mod limits;
+pub mod opus;
+pub mod preroll;
+mod resample;
+pub mod silence;
+pub mod source;
+mod writer;
+
+pub use channels::ChannelMode;
+pub use depth::SampleDepth;
+pub use devices::{list_input_devices, InputDeviceInfo};
+pub use format::OutputFormat;
+pub use level::{InputLevel, LevelMeter};
+pub use limits::RecordingLimits;
+pub use preroll::{ArmedInput, PreRoll};
+pub use silence::SilenceAutoStop;
+pub use source::{
+    AudioSource, CpalSource, SourceError, SourceFormat, SyntheticSource, WavFileSource, Waveform,
+};
+
 #[derive(Debug, thiserror::Error)]
 pub enum AudioError {
     #[error("no default input device available")]
     NoDefaultInputDevice,
+    #[error("input device \"{0}\" is not available")]
+    InputDeviceNotFound(String),
+    #[error("failed to enumerate input devices: {0}")]
+    Devices(#[from] cpal::DevicesError),
     #[error("failed to query default input config: {0}")]
     DefaultInputConfig(#[from] cpal::DefaultStreamConfigError),
     #[error("failed to build input stream: {0}")]
@@ -23,16 +52,171 @@ pub enum AudioError {
     Io(#[from] std::io::Error),
     #[error("wav error: {0}")]
     Wav(#[from] hound::Error),
+    #[error("failed to set up resampler: {0}")]
+    ResamplerInit(#[from] rubato::ResamplerConstructionError),
+    #[error("resampling failed: {0}")]
+    Resample(#[from] rubato::ResampleError),
     #[error("unsupported input sample format")]
     UnsupportedSampleFormat,
+    #[error("input channel {index} requested but the device only has {channels}")]
+    ChannelOutOfRange { index: u16, channels: u16 },
+    #[error("{0} samples cannot be stored losslessly as FLAC")]
+    FlacUnsupportedSamples(String),
+    #[error("FLAC output does not match its source: {0}")]
+    FlacMismatch(String),
+    #[error("opus error: {0}")]
+    Opus(#[from] audiopus::Error),
+    #[error("Opus recordings are mono or stereo, not {0} channels")]
+    OpusUnsupportedChannels(u16),
+    #[error("only {available_mb} MB free on the recordings disk, {required_mb} MB required")]
+    InsufficientDiskSpace { available_mb: u64, required_mb: u64 },
+}
+
+/// How often a running session reports its input level.
+const LEVEL_INTERVAL: Duration = Duration::from_millis(100);
+/// A source that delivers nothing for this long is treated as gone, error or not.
+const STALL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Why a session ended without anyone calling `stop`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    tag = "kind"
+)]
+pub enum StopReason {
+    Silence { timeout_sec: u64 },
+    InputFailed { message: String },
+    MaxDuration { limit_sec: u64 },
+    LowDiskSpace { available_mb: u64, required_mb: u64 },
+}
+
+impl StopReason {
+    /// Whether the user should hear about this as an error rather than a normal stop.
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            StopReason::InputFailed { .. } | StopReason::LowDiskSpace { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::Silence { timeout_sec } => {
+                write!(f, "no sound for {}", fmt_span(*timeout_sec))
+            }
+            StopReason::InputFailed { message } => write!(f, "input failed: {message}"),
+            StopReason::MaxDuration { limit_sec } => {
+                write!(f, "reached the maximum length of {}", fmt_span(*limit_sec))
+            }
+            StopReason::LowDiskSpace {
+                available_mb,
+                required_mb,
+            } => write!(
+                f,
+                "disk almost full ({available_mb} MB free, {required_mb} MB required)"
+            ),
+        }
+    }
+}
+
+fn fmt_span(secs: u64) -> String {
+    if secs >= 60 && secs.is_multiple_of(60) {
+        format!("{} min", secs / 60)
+    } else {
+        format!("{secs} s")
+    }
+}
+
+/// Things a running session reports from its own thread.
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Level(InputLevel),
+    /// The session has already finalized its file; call `stop` to collect the result.
+    StoppedItself {
+        filename: String,
+        reason: StopReason,
+    },
+}
+
+pub type SessionEventCallback = Arc<dyn Fn(SessionEvent) + Send + Sync + 'static>;
+
+/// Per-recording choices, usually derived from `Settings`.
+#[derive(Clone, Default)]
+pub struct RecordingOptions {
+    pub output_format: OutputFormat,
+    /// Only used for `OutputFormat::Opus`; `None` means `opus::DEFAULT_BITRATE_KBPS`.
+    pub opus_bitrate_kbps: Option<u32>,
+    pub channel_mode: ChannelMode,
+    pub sample_depth: SampleDepth,
+    /// File sample rate; `None` keeps the device rate. Converted in the writer if the device can't do it.
+    pub sample_rate: Option<u32>,
+    pub silence_auto_stop: Option<SilenceAutoStop>,
+    /// Longest a single file may get before the recording continues in `<stem>_part2`
+    /// and so on. WAV rolls over short of its 4 GiB limit regardless.
+    pub max_segment_sec: Option<u32>,
+    pub limits: RecordingLimits,
+    /// Called from the session thread; keep it cheap.
+    pub on_event: Option<SessionEventCallback>,
+}
+
+/// A stretch of wall-clock time, in seconds from the start of the session, with no audio captured.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PausedSpan {
+    pub start_sec: f64,
+    pub end_sec: f64,
+}
+
+/// A point the user flagged while recording.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Marker {
+    /// Frames from the start of the file, at the file's rate.
+    pub frame: u64,
+    /// `frame` in seconds of recorded audio; time spent paused doesn't count.
+    pub position_sec: f64,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+/// One file of a finished recording.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FinishedSegment {
+    pub filename: String,
+    pub frames: u64,
+    pub duration_sec: f64,
+    pub size_bytes: u64,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct FinishedRecording {
+    /// The first file; see `segments` for the rest.
     pub filename: String,
+    /// RFC 3339 with the local offset at the start.
     pub created_at: String,
+    /// Totals over all segments.
     pub duration_sec: f64,
     pub size_bytes: u64,
+    /// Every file in order, the first included; a single one unless the recording rolled over.
+    pub segments: Vec<FinishedSegment>,
+    pub channels: u16,
+    /// Rate of the file on disk.
+    pub sample_rate: u32,
+    /// Rate the input delivered; differs from `sample_rate` when the writer resampled.
+    pub device_sample_rate: u32,
+    /// Frames dropped because the writer thread fell behind; non-zero means audible gaps.
+    pub overrun_frames: u64,
+    /// Time from start to stop including pauses; `duration_sec` is the audio actually recorded.
+    pub wall_clock_sec: f64,
+    pub paused_spans: Vec<PausedSpan>,
+    pub markers: Vec<Marker>,
+    /// Set when the session ended itself rather than through `stop`.
+    pub stop_reason: Option<StopReason>,
+    /// Name of the input device, when the source knows it.
+    pub input_device: Option<String>,
 }
 
 fn debug_log(msg: &str) {
@@ -41,39 +225,127 @@ fn debug_log(msg: &str) {
     }
 }
 
-fn filename_format() -> &'static [FormatItem<'static>] {
+pub(crate) fn filename_format() -> &'static [FormatItem<'static>] {
     format_description!("[year]-[month]-[day]_[hour]-[minute]-[second]")
 }
 
-fn created_at_format() -> &'static [FormatItem<'static>] {
-    format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]")
+/// Sidecar written next to a recording while it is open and removed once it is indexed.
+/// Leftover markers at startup point at files a crash never finalized.
+pub fn in_progress_marker_path(recording_path: &Path) -> PathBuf {
+    let mut marker = recording_path.as_os_str().to_owned();
+    marker.push(".inprogress");
+    PathBuf::from(marker)
 }
 
-fn now_local_fallback_utc() -> time::OffsetDateTime {
-    time::OffsetDateTime::now_local().unwrap_or_else(|_| time::OffsetDateTime::now_utc())
+/// Part `part` (counting from 2) of the recording whose first file is `first`:
+/// `<stem>_part<part>.<ext>`.
+pub fn segment_path(first: &Path, part: u32) -> PathBuf {
+    let stem = first.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!("{stem}_part{part}");
+    if let Some(ext) = first.extension() {
+        name.push('.');
+        name.push_str(&ext.to_string_lossy());
+    }
+    first.with_file_name(name)
 }
 
-fn f32_to_i16(s: f32) -> i16 {
-    let clamped = s.clamp(-1.0, 1.0);
-    (clamped * i16::MAX as f32) as i16
+/// Splits a file stem written by `segment_path` into the first part's stem and the part number.
+pub fn parse_segment_stem(stem: &str) -> Option<(&str, u32)> {
+    let (first, part) = stem.rsplit_once("_part")?;
+    let part: u32 = part.parse().ok()?;
+    (part >= 2 && !first.is_empty()).then_some((first, part))
 }
 
-fn u16_to_i16(s: u16) -> i16 {
-    (s as i32 - 32768) as i16
+/// Creates `<stem>.<ext>` for a new recording, or `<stem>-2.<ext>` and so on when a
+/// recording started within the same second already uses that stem in any format.
+/// `create_new` makes the claim atomic, so nothing is ever overwritten.
+fn claim_recording_file(
+    dir: &Path,
+    stem: &str,
+    format: OutputFormat,
+) -> Result<(String, PathBuf, File), AudioError> {
+    let formats = [OutputFormat::Wav, OutputFormat::Flac, OutputFormat::Opus];
+    for n in 1u32.. {
+        let candidate = match n {
+            1 => stem.to_string(),
+            n => format!("{stem}-{n}"),
+        };
+        if formats
+            .iter()
+            .any(|f| dir.join(format!("{candidate}.{}", f.extension())).exists())
+        {
+            continue;
+        }
+        let filename = format!("{candidate}.{}", format.extension());
+        let path = dir.join(&filename);
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => return Ok((filename, path, file)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    unreachable!("ran out of recording file names")
 }
 
-pub fn start_recording(recordings_dir: &Path) -> Result<RecordingSession, AudioError> {
-    RecordingSession::start(recordings_dir)
+/// Records from `device_id`, or the system default input when `None`.
+pub fn start_recording(
+    recordings_dir: &Path,
+    device_id: Option<&str>,
+    options: RecordingOptions,
+) -> Result<RecordingSession, AudioError> {
+    let mut source = CpalSource::open(device_id)?;
+    if let Some(rate) = options.sample_rate {
+        source = source.prefer_sample_rate(rate);
+    }
+    RecordingSession::start(recordings_dir, source, options)
 }
 
 pub fn stop_recording(active: RecordingSession) -> Result<FinishedRecording, AudioError> {
     active.stop()
 }
 
+/// Listens to `device_id` for `window` and reports how loud it was, without recording.
+pub fn probe_input_level(
+    device_id: Option<&str>,
+    window: Duration,
+) -> Result<InputLevel, AudioError> {
+    let source = CpalSource::open(device_id)?;
+    let meter = Arc::new(LevelMeter::default());
+    let meter_cb = Arc::clone(&meter);
+    let stream = source.start(
+        Box::new(move |data: &[f32]| meter_cb.update(data)),
+        Box::new(|err: SourceError| debug_log(&format!("probe stream error: {}", err.message))),
+    )?;
+    std::thread::sleep(window);
+    drop(stream);
+    Ok(meter.take())
+}
+
+enum Control {
+    Stop,
+    /// A non-fatal source error; kept to explain a stall if one follows.
+    Warning(String),
+    Failed(String),
+}
+
 pub struct RecordingSession {
     filename: String,
     created_at: String,
-    stop_tx: mpsc::Sender<()>,
+    started: Instant,
+    paused: Arc<AtomicBool>,
+    meter: Arc<LevelMeter>,
+    paused_since: Option<Instant>,
+    paused_spans: Vec<PausedSpan>,
+    /// Input frames that reached the writer, for placing markers.
+    recorded_frames: Arc<AtomicU64>,
+    sample_rate: u32,
+    device_sample_rate: u32,
+    markers: Vec<Marker>,
+    stop_tx: mpsc::Sender<Control>,
     join: Option<std::thread::JoinHandle<Result<FinishedRecording, AudioError>>>,
 }
 
@@ -86,153 +358,334 @@ impl RecordingSession {
         &self.created_at
     }
 
-    pub fn start(recordings_dir: &Path) -> Result<Self, AudioError> {
+    /// The level the session last reported. Reading it does not disturb the
+    /// window the session's own reporting and silence detection are filling.
+    pub fn input_level(&self) -> InputLevel {
+        self.meter.latest()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_since.is_some()
+    }
+
+    /// Stops samples from reaching the file; the WAV stays open. Returns false if already paused.
+    pub fn pause(&mut self) -> bool {
+        if self.is_paused() {
+            return false;
+        }
+        self.paused.store(true, Ordering::Relaxed);
+        self.paused_since = Some(Instant::now());
+        true
+    }
+
+    /// Returns false if the session was not paused.
+    pub fn resume(&mut self) -> bool {
+        let Some(since) = self.paused_since.take() else {
+            return false;
+        };
+        self.paused.store(false, Ordering::Relaxed);
+        self.close_paused_span(since, Instant::now());
+        true
+    }
+
+    /// Marks the current position in the file. While paused, that is where recording resumes.
+    pub fn add_marker(&mut self, label: Option<String>) -> Marker {
+        let recorded = self.recorded_frames.load(Ordering::Relaxed);
+        let frame = (recorded as u128 * self.sample_rate as u128
+            / self.device_sample_rate.max(1) as u128) as u64;
+        let marker = Marker {
+            frame,
+            position_sec: frame as f64 / self.sample_rate.max(1) as f64,
+            label,
+        };
+        self.markers.push(marker.clone());
+        marker
+    }
+
+    fn close_paused_span(&mut self, since: Instant, until: Instant) {
+        self.paused_spans.push(PausedSpan {
+            start_sec: since.duration_since(self.started).as_secs_f64(),
+            end_sec: until.duration_since(self.started).as_secs_f64(),
+        });
+    }
+
+    pub fn start<S: AudioSource>(
+        recordings_dir: &Path,
+        source: S,
+        options: RecordingOptions,
+    ) -> Result<Self, AudioError> {
         std::fs::create_dir_all(recordings_dir)?;
+        options.limits.check_free_space(recordings_dir)?;
 
-        let now = now_local_fallback_utc();
+        let now = crate::clock::now_local();
         let stem = now
             .format(filename_format())
             .unwrap_or_else(|_| "recording".into());
-        let created_at = now
-            .format(created_at_format())
-            .unwrap_or_else(|_| "unknown".into());
-        let filename = format!("{stem}.wav");
-        let path = recordings_dir.join(&filename);
+        let created_at = crate::clock::format_rfc3339(now);
+        let (filename, path, file) =
+            claim_recording_file(recordings_dir, &stem, options.output_format)?;
+        if let Err(err) = std::fs::write(in_progress_marker_path(&path), b"") {
+            let _ = std::fs::remove_file(&path);
+            return Err(err.into());
+        }
 
-        let (stop_tx, stop_rx) = mpsc::channel::<()>();
-        let (ready_tx, ready_rx) = mpsc::channel::<Result<(), AudioError>>();
+        let (stop_tx, stop_rx) = mpsc::channel::<Control>();
+        let control_tx = stop_tx.clone();
+        // Reports the file and device rates once the writer is up.
+        let (ready_tx, ready_rx) = mpsc::channel::<Result<(u32, u32), AudioError>>();
 
         let filename_thread = filename.clone();
         let created_at_thread = created_at.clone();
+        let paused = Arc::new(AtomicBool::new(false));
+        let paused_cb = Arc::clone(&paused);
+        let paused_thread = Arc::clone(&paused);
+        let meter = Arc::new(LevelMeter::default());
+        let meter_cb = Arc::clone(&meter);
+        let meter_thread = Arc::clone(&meter);
+        let delivered = Arc::new(AtomicU64::new(0));
+        let delivered_cb = Arc::clone(&delivered);
+        let recorded_frames = Arc::new(AtomicU64::new(0));
+        let recorded_cb = Arc::clone(&recorded_frames);
+        let recorded_thread = Arc::clone(&recorded_frames);
+        let limits = options.limits;
+        let dir_thread = recordings_dir.to_path_buf();
+        let on_event = options.on_event.clone();
+        let silence_auto_stop = options.silence_auto_stop;
+        let input_device = source.device_name();
 
         let join = std::thread::spawn(move || -> Result<FinishedRecording, AudioError> {
-            let host = cpal::default_host();
-            let device = host
-                .default_input_device()
-                .ok_or(AudioError::NoDefaultInputDevice)?;
-            let supported = device.default_input_config()?;
-
-            let sample_rate = supported.sample_rate().0;
-            let channels_in = supported.channels().max(1) as usize;
-
-            // Minimal, widely supported: 16-bit PCM WAV, mono (take first channel).
-            let wav_spec = hound::WavSpec {
-                channels: 1,
-                sample_rate,
-                bits_per_sample: 16,
-                sample_format: hound::SampleFormat::Int,
+            let run = || -> Result<_, AudioError> {
+                let format = source.format();
+                let device_rate = format.sample_rate;
+                let output_format = options.output_format;
+                let sample_rate =
+                    output_format.file_sample_rate(options.sample_rate.unwrap_or(device_rate));
+                let channels_in = format.channels.max(1);
+                let channel_mode = options.channel_mode;
+                let channels = channel_mode.output_channels(channels_in)?;
+                if output_format == OutputFormat::Opus && channels > 2 {
+                    return Err(AudioError::OpusUnsupportedChannels(channels));
+                }
+
+                let wav_spec = output_format
+                    .effective_depth(options.sample_depth)
+                    .wav_spec(channels, sample_rate);
+
+                let rollover = [
+                    options
+                        .max_segment_sec
+                        .map(|sec| sec as u64 * sample_rate as u64),
+                    output_format.max_file_frames(&wav_spec),
+                ]
+                .into_iter()
+                .flatten()
+                .min()
+                .map(|max_frames| writer::Rollover {
+                    first_path: path.clone(),
+                    max_frames,
+                });
+
+                let (mut ring, writer_thread) = writer::WriterThread::spawn(
+                    file,
+                    wav_spec,
+                    output_format,
+                    options
+                        .opus_bitrate_kbps
+                        .unwrap_or(opus::DEFAULT_BITRATE_KBPS),
+                    device_rate,
+                    source.buffered_frames(),
+                    rollover,
+                )?;
+                let mut mapped: Vec<f32> = Vec::new();
+
+                // Runs on the device's real-time thread: map channels and hand off, nothing else.
+                let on_data = Box::new(move |data: &[f32]| {
+                    delivered_cb.fetch_add(1, Ordering::Relaxed);
+                    mapped.clear();
+                    channel_mode.map_into(channels_in, data, &mut mapped);
+                    // Keep metering while paused so a muted mic is noticed before resuming.
+                    meter_cb.update(&mapped);
+                    if paused_cb.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    // Only what the writer got: frames lost to an overrun aren't in the file.
+                    let pushed = ring.push_frames(&mapped);
+                    recorded_cb.fetch_add(pushed as u64, Ordering::Relaxed);
+                });
+                let on_error = Box::new(move |err: SourceError| {
+                    debug_log(&format!("stream error: {}", err.message));
+                    let msg = if err.fatal {
+                        Control::Failed(err.message)
+                    } else {
+                        Control::Warning(err.message)
+                    };
+                    let _ = control_tx.send(msg);
+                });
+
+                let stream = match source.start(on_data, on_error) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = writer_thread.finish();
+                        return Err(e);
+                    }
+                };
+                Ok((stream, writer_thread, sample_rate, device_rate, channels))
             };
 
-            let file = File::create(&path)?;
-            let writer = hound::WavWriter::new(BufWriter::new(file), wav_spec)?;
-            let writer = Arc::new(Mutex::new(Some(writer)));
-            let samples_written = Arc::new(AtomicU64::new(0));
-
-            let writer_cb = Arc::clone(&writer);
-            let samples_written_cb = Arc::clone(&samples_written);
-
-            let stream_config: cpal::StreamConfig = supported.clone().into();
-            let err_fn = move |err| {
-                debug_log(&format!("stream error: {err}"));
-            };
-
-            let stream = match supported.sample_format() {
-                cpal::SampleFormat::I16 => device.build_input_stream(
-                    &stream_config,
-                    move |data: &[i16], _: &cpal::InputCallbackInfo| {
-                        let mut guard = match writer_cb.lock() {
-                            Ok(g) => g,
-                            Err(_) => return,
-                        };
-                        let Some(w) = guard.as_mut() else { return };
-
-                        for i in (0..data.len()).step_by(channels_in) {
-                            if let Ok(()) = w.write_sample(data[i]) {
-                                samples_written_cb.fetch_add(1, Ordering::Relaxed);
-                            }
-                        }
-                    },
-                    err_fn,
-                    None,
-                )?,
-                cpal::SampleFormat::U16 => device.build_input_stream(
-                    &stream_config,
-                    move |data: &[u16], _: &cpal::InputCallbackInfo| {
-                        let mut guard = match writer_cb.lock() {
-                            Ok(g) => g,
-                            Err(_) => return,
-                        };
-                        let Some(w) = guard.as_mut() else { return };
-
-                        for i in (0..data.len()).step_by(channels_in) {
-                            if let Ok(()) = w.write_sample(u16_to_i16(data[i])) {
-                                samples_written_cb.fetch_add(1, Ordering::Relaxed);
-                            }
-                        }
-                    },
-                    err_fn,
-                    None,
-                )?,
-                cpal::SampleFormat::F32 => device.build_input_stream(
-                    &stream_config,
-                    move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                        let mut guard = match writer_cb.lock() {
-                            Ok(g) => g,
-                            Err(_) => return,
-                        };
-                        let Some(w) = guard.as_mut() else { return };
-
-                        for i in (0..data.len()).step_by(channels_in) {
-                            if let Ok(()) = w.write_sample(f32_to_i16(data[i])) {
-                                samples_written_cb.fetch_add(1, Ordering::Relaxed);
-                            }
-                        }
-                    },
-                    err_fn,
-                    None,
-                )?,
-                _ => return Err(AudioError::UnsupportedSampleFormat),
+            let (stream, writer_thread, sample_rate, device_rate, channels) = match run() {
+                Ok(v) => v,
+                Err(e) => {
+                    // Nothing was recorded; do not leave an empty file for recovery to find.
+                    let _ = std::fs::remove_file(&path);
+                    let _ = std::fs::remove_file(in_progress_marker_path(&path));
+                    let _ = ready_tx.send(Err(e));
+                    return Err(AudioError::Io(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "recording thread failed to initialize",
+                    )));
+                }
             };
 
-            stream.play()?;
-
-            let _ = ready_tx.send(Ok(()));
-
-            // Block until stop is requested.
-            let _ = stop_rx.recv();
+            let _ = ready_tx.send(Ok((sample_rate, device_rate)));
+
+            // Meter, watch for silence and for a dead input until stop is requested.
+            let mut silence =
+                silence_auto_stop.map(|c| silence::SilenceDetector::new(c, Instant::now()));
+            let mut limit_watch = limits::LimitWatch::new(limits, dir_thread, Instant::now());
+            let mut stop_reason = None;
+            let mut last_warning = None;
+            let mut last_delivered = delivered.load(Ordering::Relaxed);
+            let mut last_progress = Instant::now();
+            loop {
+                match stop_rx.recv_timeout(LEVEL_INTERVAL) {
+                    Ok(Control::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    Ok(Control::Warning(message)) => {
+                        last_warning = Some(message);
+                        continue;
+                    }
+                    Ok(Control::Failed(message)) => {
+                        stop_reason = Some(StopReason::InputFailed { message });
+                        break;
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                }
+
+                let now = Instant::now();
+                let delivered_now = delivered.load(Ordering::Relaxed);
+                if delivered_now != last_delivered {
+                    last_delivered = delivered_now;
+                    last_progress = now;
+                } else if now.duration_since(last_progress) >= STALL_TIMEOUT {
+                    let message = last_warning
+                        .take()
+                        .unwrap_or_else(|| "the device stopped delivering audio".to_string());
+                    stop_reason = Some(StopReason::InputFailed { message });
+                    break;
+                }
+
+                let level = meter_thread.take();
+                if let Some(on_event) = &on_event {
+                    on_event(SessionEvent::Level(level));
+                }
+                if let Some(detector) = silence.as_mut() {
+                    let paused = paused_thread.load(Ordering::Relaxed);
+                    if detector.observe(level, paused, now) {
+                        stop_reason = silence_auto_stop.map(|c| StopReason::Silence {
+                            timeout_sec: c.timeout_sec,
+                        });
+                        break;
+                    }
+                }
+                let recorded_sec =
+                    recorded_thread.load(Ordering::Relaxed) as f64 / device_rate.max(1) as f64;
+                if let Some(reason) = limit_watch.check(recorded_sec, now) {
+                    stop_reason = Some(reason);
+                    break;
+                }
+            }
 
-            // Dropping the stream stops the callback; we only finalize after that.
+            // Dropping the stream stops the callback; the writer drains what is left, then finalizes.
             drop(stream);
+            let stats = writer_thread.finish()?;
+            if stats.overrun_frames > 0 {
+                debug_log(&format!(
+                    "dropped {} frames to overruns",
+                    stats.overrun_frames
+                ));
+            }
 
-            let samples = samples_written.load(Ordering::Relaxed);
             let duration_sec = if sample_rate == 0 {
                 0.0
             } else {
-                samples as f64 / sample_rate as f64
+                stats.frames_written as f64 / sample_rate as f64
             };
 
-            let mut guard = writer.lock().map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::Other, "wav writer lock poisoned")
-            })?;
-            if let Some(w) = guard.take() {
-                w.finalize()?;
+            let mut segments = Vec::with_capacity(stats.segment_frames.len());
+            for (i, &frames) in stats.segment_frames.iter().enumerate() {
+                let segment = match i {
+                    0 => path.clone(),
+                    _ => segment_path(&path, i as u32 + 1),
+                };
+                segments.push(FinishedSegment {
+                    filename: segment
+                        .file_name()
+                        .map(|n| n.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                    frames,
+                    duration_sec: frames as f64 / sample_rate.max(1) as f64,
+                    size_bytes: std::fs::metadata(&segment)?.len(),
+                });
+            }
+            let size_bytes = segments.iter().map(|s| s.size_bytes).sum();
+            if segments.len() > 1 {
+                debug_log(&format!(
+                    "recording rolled over into {} files",
+                    segments.len()
+                ));
             }
 
-            let size_bytes = std::fs::metadata(&path)?.len();
-
-            Ok(FinishedRecording {
+            let finished = FinishedRecording {
                 filename: filename_thread,
                 created_at: created_at_thread,
                 duration_sec,
                 size_bytes,
-            })
+                segments,
+                channels,
+                sample_rate,
+                device_sample_rate: device_rate,
+                overrun_frames: stats.overrun_frames,
+                // Filled in by `stop`, which owns the pause and marker bookkeeping.
+                wall_clock_sec: 0.0,
+                paused_spans: Vec::new(),
+                markers: Vec::new(),
+                stop_reason: stop_reason.clone(),
+                input_device,
+            };
+
+            if let (Some(reason), Some(on_event)) = (stop_reason, &on_event) {
+                debug_log(&format!("stopped itself: {reason}"));
+                on_event(SessionEvent::StoppedItself {
+                    filename: finished.filename.clone(),
+                    reason,
+                });
+            }
+            Ok(finished)
         });
 
         // If initialization failed, return the exact error and avoid leaving a running thread.
         match ready_rx.recv() {
-            Ok(Ok(())) => Ok(Self {
+            Ok(Ok((sample_rate, device_sample_rate))) => Ok(Self {
                 filename,
                 created_at,
+                started: Instant::now(),
+                paused,
+                meter,
+                paused_since: None,
+                paused_spans: Vec::new(),
+                recorded_frames,
+                sample_rate,
+                device_sample_rate,
+                markers: Vec::new(),
                 stop_tx,
                 join: Some(join),
             }),
@@ -245,17 +698,33 @@ impl RecordingSession {
     }
 
     pub fn stop(mut self) -> Result<FinishedRecording, AudioError> {
-        let _ = self.stop_tx.send(());
+        let stopped = Instant::now();
+        let _ = self.stop_tx.send(Control::Stop);
+        if let Some(since) = self.paused_since.take() {
+            self.close_paused_span(since, stopped);
+        }
         let join = self
             .join
             .take()
             .ok_or_else(|| AudioError::Io(std::io::Error::new(std::io::ErrorKind::Other, "missing join handle")))?;
-        join.join().map_err(|_| {
+        let mut finished = join.join().map_err(|_| {
             AudioError::Io(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "recording thread panicked",
             ))
-        })?
+        })??;
+        finished.wall_clock_sec = stopped.duration_since(self.started).as_secs_f64();
+        finished.paused_spans = std::mem::take(&mut self.paused_spans);
+        finished.markers = std::mem::take(&mut self.markers);
+        Ok(finished)
+    }
+}
+
+impl Drop for RecordingSession {
+    /// A session dropped without `stop` still finalizes its file in the background;
+    /// the source's error callback holds a sender, so disconnection alone would not end it.
+    fn drop(&mut self) {
+        let _ = self.stop_tx.send(Control::Stop);
     }
 }
 
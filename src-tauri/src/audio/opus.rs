@@ -0,0 +1,426 @@
+//! Opus in Ogg for compact voice notes, plus the stream facts storage reads back.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use ogg::reading::{OggReadError, PageParser};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+
+use super::AudioError;
+
+/// Opus always decodes at 48 kHz; granule positions count samples at this rate.
+pub const GRANULE_RATE: u32 = 48_000;
+/// Rates the encoder accepts directly; anything else is resampled to 48 kHz.
+pub const SUPPORTED_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+/// Transparent for speech at a fraction of WAV's size.
+pub const DEFAULT_BITRATE_KBPS: u32 = 24;
+/// 20 ms, the usual trade-off between overhead and latency.
+const FRAME_MS: u32 = 20;
+/// Largest packet libopus will produce.
+const MAX_PACKET: usize = 4000;
+const VENDOR: &str = "Kiklet";
+
+/// Fixed part of a page header, before the segment table.
+const PAGE_HEADER_LEN: usize = 27;
+/// Header, full segment table and 255 segments of 255 bytes.
+const MAX_PAGE_LEN: usize = PAGE_HEADER_LEN + 255 + 255 * 255;
+
+/// One Ogg page whose checksum the `ogg` crate has verified.
+pub(crate) struct Page {
+    /// -1 (`u64::MAX`) on a page where no packet ends.
+    pub granule: u64,
+    pub serial: u32,
+    /// Header plus segment table plus body, in bytes.
+    pub len: u64,
+    pub body: Vec<u8>,
+}
+
+fn ogg_error(err: OggReadError) -> io::Error {
+    match err {
+        OggReadError::ReadError(err) => err,
+        other => invalid(&other.to_string()),
+    }
+}
+
+/// Reads the page starting at the reader's position. Fails on anything that isn't a
+/// whole page with a good checksum, including one cut short by the end of the file.
+pub(crate) fn read_page<R: Read>(r: &mut R) -> io::Result<Page> {
+    let mut header = [0u8; PAGE_HEADER_LEN];
+    r.read_exact(&mut header)?;
+    if &header[0..4] != b"OggS" {
+        return Err(invalid("no Ogg page here"));
+    }
+    let granule = u64::from_le_bytes(header[6..14].try_into().unwrap_or_default());
+    let serial = u32::from_le_bytes(header[14..18].try_into().unwrap_or_default());
+    let (mut parser, segments) = PageParser::new(header).map_err(ogg_error)?;
+    let mut table = vec![0; segments];
+    r.read_exact(&mut table)?;
+    let body_len = parser.parse_segments(table);
+    let mut body = vec![0; body_len];
+    r.read_exact(&mut body)?;
+    // Checks the CRC; the parsed page itself is of no further use here.
+    let _ = parser.parse_packet_data(body.clone()).map_err(ogg_error)?;
+    Ok(Page {
+        granule,
+        serial,
+        len: (PAGE_HEADER_LEN + segments + body_len) as u64,
+        body,
+    })
+}
+
+fn opus_head(channels: u8, pre_skip: u16, input_rate: u32) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(channels);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&input_rate.to_le_bytes());
+    // Output gain, then channel mapping family 0 (mono or stereo).
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+fn opus_tags() -> Vec<u8> {
+    let mut tags = Vec::new();
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+    tags.extend_from_slice(VENDOR.as_bytes());
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    tags
+}
+
+fn stream_serial() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+        .unwrap_or(1)
+}
+
+/// Encodes interleaved `f32` into 20 ms Opus packets inside an Ogg stream.
+///
+/// Pages end on their own every few seconds of speech; `checkpoint` forces the
+/// next one out early so a crash loses little, and every page carries the
+/// granule position the duration is read from.
+pub struct OpusWriter<W: Write> {
+    packets: PacketWriter<W>,
+    encoder: Encoder,
+    serial: u32,
+    channels: usize,
+    frame_len: usize,
+    /// 48 kHz samples per input sample.
+    granule_scale: u64,
+    pre_skip: u64,
+    pending: Vec<f32>,
+    packet: Vec<u8>,
+    input_frames: u64,
+    encoded_frames: u64,
+    end_page: bool,
+}
+
+impl<W: Write> OpusWriter<W> {
+    /// `sample_rate` must be one of `SUPPORTED_RATES` and `channels` 1 or 2.
+    pub fn new(
+        out: W,
+        channels: u16,
+        sample_rate: u32,
+        bitrate_kbps: u32,
+    ) -> Result<Self, AudioError> {
+        let opus_channels = match channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            n => return Err(AudioError::OpusUnsupportedChannels(n)),
+        };
+        let opus_rate: SampleRate = audiopus::TryFrom::try_from(sample_rate as i32)?;
+        let mut encoder = Encoder::new(opus_rate, opus_channels, Application::Voip)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(bitrate_kbps.max(6) as i32 * 1000))?;
+
+        let granule_scale = (GRANULE_RATE / sample_rate) as u64;
+        let pre_skip = encoder.lookahead()? as u64 * granule_scale;
+        let serial = stream_serial();
+        let mut packets = PacketWriter::new(out);
+        // Both headers sit on pages of their own, as the Ogg Opus mapping requires.
+        packets.write_packet(
+            opus_head(channels as u8, pre_skip as u16, sample_rate).into_boxed_slice(),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        packets.write_packet(
+            opus_tags().into_boxed_slice(),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+
+        let channels = channels as usize;
+        let frame_len = (sample_rate * FRAME_MS / 1000) as usize;
+        Ok(Self {
+            packets,
+            encoder,
+            serial,
+            channels,
+            frame_len,
+            granule_scale,
+            pre_skip,
+            pending: Vec::with_capacity(frame_len * channels * 2),
+            packet: vec![0; MAX_PACKET],
+            input_frames: 0,
+            encoded_frames: 0,
+            end_page: false,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<(), AudioError> {
+        self.pending.extend_from_slice(samples);
+        self.input_frames = self
+            .input_frames
+            .saturating_add((samples.len() / self.channels) as u64);
+        let packet_samples = self.frame_len * self.channels;
+        let whole = self.pending.len() - self.pending.len() % packet_samples;
+        let mut pending = std::mem::take(&mut self.pending);
+        for frame in pending[..whole].chunks_exact(packet_samples) {
+            self.encode(frame, false)?;
+        }
+        pending.drain(..whole);
+        self.pending = pending;
+        Ok(())
+    }
+
+    /// Ends the current page with the next packet and pushes buffered bytes to `out`.
+    pub fn checkpoint(&mut self) -> Result<(), AudioError> {
+        self.end_page = true;
+        self.packets.inner_mut().flush()?;
+        Ok(())
+    }
+
+    /// Pads with silence so the encoder's lookahead is flushed, then ends the stream.
+    /// The final granule position trims the padding back off for decoders.
+    pub fn finalize(mut self) -> Result<(), AudioError> {
+        let packet_samples = self.frame_len * self.channels;
+        let lookahead_frames = self.pre_skip.div_ceil(self.granule_scale) as usize;
+        let padded = (self.pending.len() + lookahead_frames * self.channels)
+            .div_ceil(packet_samples)
+            .max(1)
+            * packet_samples;
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.resize(padded, 0.0);
+        let count = padded / packet_samples;
+        for (i, frame) in pending.chunks_exact(packet_samples).enumerate() {
+            self.encode(frame, i + 1 == count)?;
+        }
+        self.packets.inner_mut().flush()?;
+        Ok(())
+    }
+
+    fn encode(&mut self, frame: &[f32], last: bool) -> Result<(), AudioError> {
+        let len = self.encoder.encode_float(frame, &mut self.packet)?;
+        self.encoded_frames += self.frame_len as u64;
+        let mut granule = self.encoded_frames * self.granule_scale;
+        let info = if last {
+            granule = granule.min(self.pre_skip + self.input_frames * self.granule_scale);
+            PacketWriteEndInfo::EndStream
+        } else if std::mem::take(&mut self.end_page) {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        self.packets
+            .write_packet(self.packet[..len].into(), self.serial, info, granule)?;
+        Ok(())
+    }
+}
+
+/// What storage needs from an Ogg Opus file without decoding it.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamInfo {
+    pub channels: u16,
+    /// Rate the audio had before encoding, from `OpusHead`.
+    pub input_sample_rate: u32,
+    pub pre_skip: u64,
+    /// Granule position of the last page that completes a packet.
+    pub last_granule: u64,
+}
+
+impl StreamInfo {
+    pub fn duration_sec(&self) -> f64 {
+        self.last_granule.saturating_sub(self.pre_skip) as f64 / GRANULE_RATE as f64
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads `OpusHead` from the first page and the granule position from the last.
+pub fn read_stream_info(path: &Path) -> io::Result<StreamInfo> {
+    let mut f = File::open(path)?;
+    let file_len = f.metadata()?.len();
+
+    // The first page holds only `OpusHead`, which is 19 bytes for mapping family 0.
+    let head = read_page(&mut f)?.body;
+    if head.len() < 19 || &head[0..8] != b"OpusHead" {
+        return Err(invalid("not an Ogg Opus file"));
+    }
+    let channels = head[9] as u16;
+    let pre_skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+    let input_sample_rate = u32::from_le_bytes([head[12], head[13], head[14], head[15]]);
+
+    // The last page starts within this tail, however long it is.
+    let tail_len = file_len.min(MAX_PAGE_LEN as u64 + PAGE_HEADER_LEN as u64);
+    f.seek(SeekFrom::Start(file_len - tail_len))?;
+    let mut tail = Vec::with_capacity(tail_len as usize);
+    f.read_to_end(&mut tail)?;
+    let mut last_granule = 0;
+    let mut at = tail.len();
+    while let Some(pos) = tail[..at].windows(4).rposition(|w| w == b"OggS") {
+        match read_page(&mut &tail[pos..]) {
+            Ok(page) if page.granule != u64::MAX => {
+                last_granule = page.granule;
+                break;
+            }
+            _ => at = pos,
+        }
+    }
+
+    Ok(StreamInfo {
+        channels,
+        input_sample_rate,
+        pre_skip,
+        last_granule,
+    })
+}
+
+/// Encodes `frames` of `flac::test_noise` as mono 48 kHz Opus, starting a new page
+/// every `page_frames`.
+#[cfg(test)]
+pub(crate) fn write_test_stream<W: Write>(out: W, frames: usize, page_frames: usize) {
+    let samples: Vec<f32> = super::flac::test_noise(frames)
+        .iter()
+        .map(|&s| s as f32 / 20_000.0)
+        .collect();
+    let mut writer = OpusWriter::new(out, 1, 48_000, 24).unwrap();
+    for chunk in samples.chunks(page_frames) {
+        writer.write_samples(chunk).unwrap();
+        writer.checkpoint().unwrap();
+    }
+    writer.finalize().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A second of a 440 Hz tone, encoded with a checkpoint half way through.
+    fn encode(channels: u16, sample_rate: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = OpusWriter::new(&mut out, channels, sample_rate, 24).unwrap();
+        let frames = sample_rate as usize;
+        let samples: Vec<f32> = (0..frames)
+            .flat_map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                let s = (t * 440.0 * std::f32::consts::TAU).sin() * 0.5;
+                std::iter::repeat_n(s, channels as usize)
+            })
+            .collect();
+        let (first, second) = samples.split_at(samples.len() / 2);
+        writer.write_samples(first).unwrap();
+        writer.checkpoint().unwrap();
+        writer.write_samples(second).unwrap();
+        writer.finalize().unwrap();
+        out
+    }
+
+    fn pages(bytes: &[u8]) -> Vec<Page> {
+        let mut r = bytes;
+        let mut pages = Vec::new();
+        while !r.is_empty() {
+            pages.push(read_page(&mut r).unwrap());
+        }
+        pages
+    }
+
+    #[test]
+    fn writes_a_stream_the_ogg_reader_accepts() {
+        let bytes = encode(2, 48_000);
+        let mut reader = ogg::reading::PacketReader::new(Cursor::new(&bytes));
+        let head = reader.read_packet_expected().unwrap();
+        assert!(head.first_in_stream());
+        assert_eq!(&head.data[..8], b"OpusHead");
+        let tags = reader.read_packet_expected().unwrap();
+        assert_eq!(&tags.data[..8], b"OpusTags");
+        let mut audio_packets = 0;
+        let mut last = None;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            audio_packets += 1;
+            last = Some(packet);
+        }
+        assert!(last.unwrap().last_in_stream());
+        // One second in 20 ms packets, plus the ones flushing the lookahead.
+        assert!(audio_packets > 50, "{audio_packets} packets");
+    }
+
+    #[test]
+    fn read_page_walks_every_page() {
+        let bytes = encode(1, 16_000);
+        let pages = pages(&bytes);
+        assert_eq!(pages.iter().map(|p| p.len).sum::<u64>(), bytes.len() as u64);
+        assert_eq!(&pages[0].body[..8], b"OpusHead");
+        assert_eq!(&pages[1].body[..8], b"OpusTags");
+        // The checkpoint split the audio over at least two pages.
+        assert!(pages.len() >= 4);
+        let granules: Vec<u64> = pages[2..].iter().map(|p| p.granule).collect();
+        assert!(granules.windows(2).all(|w| w[0] <= w[1]), "{granules:?}");
+    }
+
+    #[test]
+    fn read_page_rejects_a_bad_checksum() {
+        let mut bytes = encode(1, 48_000);
+        let first = read_page(&mut &bytes[..]).unwrap().len as usize;
+        bytes[first - 1] ^= 0xff;
+        assert!(read_page(&mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn read_page_rejects_a_truncated_page() {
+        let bytes = encode(1, 48_000);
+        let first = read_page(&mut &bytes[..]).unwrap().len as usize;
+        assert!(read_page(&mut &bytes[..first - 1]).is_err());
+        assert!(read_page(&mut &bytes[..10]).is_err());
+        assert!(read_page(&mut &b"not an ogg page at all, just text"[..]).is_err());
+    }
+
+    #[test]
+    fn stream_info_reads_head_and_duration() {
+        for (channels, rate) in [(1, 16_000), (2, 48_000), (1, 8_000)] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("a.opus");
+            std::fs::write(&path, encode(channels, rate)).unwrap();
+            let info = read_stream_info(&path).unwrap();
+            assert_eq!(info.channels, channels);
+            assert_eq!(info.input_sample_rate, rate);
+            assert!(info.pre_skip > 0);
+            assert!(
+                (info.duration_sec() - 1.0).abs() < 1e-3,
+                "{} s at {rate} Hz",
+                info.duration_sec()
+            );
+        }
+    }
+
+    #[test]
+    fn stream_info_skips_a_torn_last_page() {
+        let bytes = encode(1, 48_000);
+        let pages = pages(&bytes);
+        let whole: u64 = pages[..pages.len() - 1].iter().map(|p| p.len).sum();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.opus");
+        std::fs::write(&path, &bytes[..whole as usize + 40]).unwrap();
+        let info = read_stream_info(&path).unwrap();
+        assert_eq!(info.last_granule, pages[pages.len() - 2].granule);
+    }
+}
@@ -0,0 +1,324 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc, Arc,
+};
+use std::time::Duration;
+
+use super::source::{DataCallback, ErrorCallback, SourceError};
+use super::writer::push_whole_frames;
+use super::{debug_log, AudioError, AudioSource, CpalSource, SourceFormat};
+
+/// Longest pre-roll we are willing to hold in memory.
+pub const MAX_PRE_ROLL_SECONDS: u32 = 30;
+
+/// Keep the input open between recordings so a new one can start a few seconds in the past.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PreRoll {
+    pub enabled: bool,
+    pub seconds: u32,
+}
+
+impl Default for PreRoll {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            seconds: 5,
+        }
+    }
+}
+
+impl PreRoll {
+    /// The length actually used once clamped to what we allow.
+    pub fn effective_seconds(&self) -> u32 {
+        self.seconds.clamp(1, MAX_PRE_ROLL_SECONDS)
+    }
+}
+
+/// How much live audio the hand-off ring holds beyond the history.
+const HEADROOM_SECONDS: usize = 2;
+/// How often the armed thread empties the ring, trimming or forwarding it.
+const PUMP_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Messages to the armed thread, which owns the ring's read side and any attached session.
+enum Command {
+    Attach {
+        on_data: DataCallback,
+        on_error: ErrorCallback,
+    },
+    /// Forward what is left, drop the session's callbacks, then acknowledge.
+    Detach(mpsc::Sender<()>),
+    Error(SourceError),
+    Stop,
+}
+
+/// Real-time side: pushes whole frames into the ring and never locks. Frames that
+/// don't fit are dropped and counted.
+struct Capture {
+    producer: rtrb::Producer<f32>,
+    channels: usize,
+    dropped_frames: Arc<AtomicU64>,
+}
+
+impl Capture {
+    fn push(&mut self, data: &[f32]) {
+        push_whole_frames(
+            &mut self.producer,
+            self.channels,
+            data,
+            &self.dropped_frames,
+        );
+    }
+}
+
+/// Non-real-time side: while idle, keeps the newest `history` samples in the ring; while
+/// a session is attached, hands everything over to it. History and live audio come out
+/// of the same ring, so the session gets them in order without anyone holding a lock.
+struct Pump {
+    consumer: rtrb::Consumer<f32>,
+    /// Samples of history to keep while idle; a multiple of the channel count.
+    history: usize,
+    session: Option<(DataCallback, ErrorCallback)>,
+}
+
+impl Pump {
+    fn run(mut self, commands: mpsc::Receiver<Command>, dropped_frames: &AtomicU64) {
+        loop {
+            match commands.recv_timeout(PUMP_INTERVAL) {
+                Ok(Command::Attach { on_data, on_error }) => {
+                    self.session = Some((on_data, on_error));
+                }
+                Ok(Command::Detach(ack)) => {
+                    self.pump();
+                    self.session = None;
+                    let _ = ack.send(());
+                    continue;
+                }
+                Ok(Command::Error(err)) => match self.session.as_mut() {
+                    Some((_, on_error)) => on_error(err),
+                    None => debug_log(&format!("armed stream error: {}", err.message)),
+                },
+                Ok(Command::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+            self.pump();
+            let dropped = dropped_frames.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                debug_log(&format!("armed input dropped {dropped} frames"));
+            }
+        }
+    }
+
+    fn pump(&mut self) {
+        let available = self.consumer.slots();
+        match self.session.as_mut() {
+            Some((on_data, _)) => {
+                let Ok(chunk) = self.consumer.read_chunk(available) else {
+                    return;
+                };
+                // Writes are whole frames and the capacity is too, so both halves are.
+                let (a, b) = chunk.as_slices();
+                for part in [a, b] {
+                    if !part.is_empty() {
+                        on_data(part);
+                    }
+                }
+                chunk.commit_all();
+            }
+            None => {
+                let excess = available.saturating_sub(self.history);
+                if let Ok(chunk) = self.consumer.read_chunk(excess) {
+                    chunk.commit_all();
+                }
+            }
+        }
+    }
+}
+
+/// An always-open input that remembers the last few seconds until a session attaches.
+///
+/// Costs what an open microphone costs: the OS shows the mic as in use, the
+/// device callback keeps running, and the history holds
+/// `seconds * sample_rate * channels` samples of `f32`.
+pub struct ArmedInput {
+    device_id: Option<String>,
+    seconds: u32,
+    sample_rate: Option<u32>,
+    format: SourceFormat,
+    device_name: Option<String>,
+    commands: mpsc::Sender<Command>,
+    join: Option<std::thread::JoinHandle<()>>,
+}
+
+impl ArmedInput {
+    /// `sample_rate` is the preferred device rate, as in `CpalSource::prefer_sample_rate`.
+    pub fn arm(
+        device_id: Option<&str>,
+        seconds: u32,
+        sample_rate: Option<u32>,
+    ) -> Result<Self, AudioError> {
+        let seconds = seconds.clamp(1, MAX_PRE_ROLL_SECONDS);
+        let (commands, command_rx) = mpsc::channel::<Command>();
+        let (ready_tx, ready_rx) =
+            mpsc::channel::<Result<(SourceFormat, Option<String>), AudioError>>();
+
+        let device_thread = device_id.map(str::to_string);
+        let error_tx = commands.clone();
+        // cpal streams are not `Send` everywhere, so the stream lives on its own thread,
+        // which also runs the pump.
+        let join = std::thread::spawn(move || {
+            let source = match CpalSource::open(device_thread.as_deref()) {
+                Ok(source) => match sample_rate {
+                    Some(rate) => source.prefer_sample_rate(rate),
+                    None => source,
+                },
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let format = source.format();
+            let device_name = source.device_name();
+            let channels = format.channels.max(1) as usize;
+            let history = seconds as usize * format.sample_rate as usize * channels;
+            let headroom = HEADROOM_SECONDS * format.sample_rate as usize * channels;
+            let (producer, consumer) = rtrb::RingBuffer::<f32>::new(history + headroom);
+            let dropped_frames = Arc::new(AtomicU64::new(0));
+
+            let mut capture = Capture {
+                producer,
+                channels,
+                dropped_frames: Arc::clone(&dropped_frames),
+            };
+            let on_data = Box::new(move |data: &[f32]| capture.push(data));
+            let on_error = Box::new(move |err: SourceError| {
+                let _ = error_tx.send(Command::Error(err));
+            });
+
+            let stream = match source.start(on_data, on_error) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok((format, device_name)));
+            Pump {
+                consumer,
+                history,
+                session: None,
+            }
+            .run(command_rx, &dropped_frames);
+            drop(stream);
+        });
+
+        match ready_rx.recv() {
+            Ok(Ok((format, device_name))) => Ok(Self {
+                device_id: device_id.map(str::to_string),
+                seconds,
+                sample_rate,
+                format,
+                device_name,
+                commands,
+                join: Some(join),
+            }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(AudioError::Io(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "armed input thread failed to initialize",
+            ))),
+        }
+    }
+
+    /// The device this input was armed with; `None` is the system default at arming time.
+    pub fn device_id(&self) -> Option<&str> {
+        self.device_id.as_deref()
+    }
+
+    pub fn seconds(&self) -> u32 {
+        self.seconds
+    }
+
+    /// The rate preference this input was armed with, not necessarily what the device runs at.
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    /// A source that replays the buffered history, then continues live.
+    pub fn source(&self) -> ArmedSource {
+        ArmedSource {
+            format: self.format,
+            device_name: self.device_name.clone(),
+            history_frames: self.seconds as usize * self.format.sample_rate as usize,
+            commands: self.commands.clone(),
+        }
+    }
+}
+
+impl Drop for ArmedInput {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Stop);
+        if let Some(join) = self.join.take() {
+            let _ = join.join();
+        }
+    }
+}
+
+pub struct ArmedSource {
+    format: SourceFormat,
+    device_name: Option<String>,
+    history_frames: usize,
+    commands: mpsc::Sender<Command>,
+}
+
+/// Detaches the session when dropped; the armed input goes back to filling its history.
+/// Waits for the pump, so no callback runs once the drop returns.
+pub struct ArmedAttachment {
+    commands: mpsc::Sender<Command>,
+}
+
+impl Drop for ArmedAttachment {
+    fn drop(&mut self) {
+        let (ack_tx, ack_rx) = mpsc::channel();
+        if self.commands.send(Command::Detach(ack_tx)).is_ok() {
+            // Disconnects instead if the armed input stopped in the meantime.
+            let _ = ack_rx.recv();
+        }
+    }
+}
+
+impl AudioSource for ArmedSource {
+    type Stream = ArmedAttachment;
+
+    fn format(&self) -> SourceFormat {
+        self.format
+    }
+
+    fn buffered_frames(&self) -> usize {
+        self.history_frames
+    }
+
+    fn device_name(&self) -> Option<String> {
+        self.device_name.clone()
+    }
+
+    /// The pump thread replays the history into `on_data` and keeps forwarding from the
+    /// same ring, so the device thread never waits on the session.
+    fn start(
+        self,
+        on_data: DataCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Self::Stream, AudioError> {
+        self.commands
+            .send(Command::Attach { on_data, on_error })
+            .map_err(|_| {
+                AudioError::Io(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "armed input has stopped",
+                ))
+            })?;
+        Ok(ArmedAttachment {
+            commands: self.commands,
+        })
+    }
+}
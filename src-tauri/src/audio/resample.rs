@@ -0,0 +1,110 @@
+use rubato::{FftFixedIn, Resampler as _};
+
+use super::AudioError;
+
+/// Input frames per resampler call; about 20 ms at common device rates.
+const CHUNK_FRAMES: usize = 1024;
+
+/// Band-limited rate conversion for the writer thread, fed and drained as interleaved samples.
+///
+/// The filter delay is trimmed from the start and the tail is flushed on `finish`,
+/// so the output is exactly `input × to / from` frames long and lines up with the input.
+pub(crate) struct Resampler {
+    inner: FftFixedIn<f32>,
+    from: u32,
+    to: u32,
+    channels: usize,
+    /// Channel the next input sample belongs to; input may stop mid-frame.
+    next_channel: usize,
+    pending: Vec<Vec<f32>>,
+    out: Vec<Vec<f32>>,
+    interleaved: Vec<f32>,
+    skip_frames: usize,
+    frames_in: u64,
+    frames_out: u64,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channels: u16) -> Result<Self, AudioError> {
+        let channels = channels.max(1) as usize;
+        let inner = FftFixedIn::<f32>::new(from as usize, to as usize, CHUNK_FRAMES, 2, channels)?;
+        let out = inner.output_buffer_allocate(true);
+        Ok(Self {
+            skip_frames: inner.output_delay(),
+            inner,
+            from,
+            to,
+            channels,
+            next_channel: 0,
+            pending: vec![Vec::with_capacity(CHUNK_FRAMES); channels],
+            out,
+            interleaved: Vec::new(),
+            frames_in: 0,
+            frames_out: 0,
+        })
+    }
+
+    /// Queues interleaved input and hands every finished block of output to `emit`.
+    pub fn process<I, F>(&mut self, samples: I, mut emit: F) -> Result<(), AudioError>
+    where
+        I: IntoIterator<Item = f32>,
+        F: FnMut(&[f32]) -> Result<(), AudioError>,
+    {
+        for s in samples {
+            self.pending[self.next_channel].push(s);
+            self.next_channel += 1;
+            if self.next_channel == self.channels {
+                self.next_channel = 0;
+                self.frames_in += 1;
+                if self.pending[0].len() == self.inner.input_frames_next() {
+                    let (_, n) =
+                        self.inner
+                            .process_into_buffer(&self.pending, &mut self.out, None)?;
+                    self.pending.iter_mut().for_each(Vec::clear);
+                    self.emit(n, &mut emit)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes out whatever is still inside the filter.
+    pub fn finish<F>(mut self, mut emit: F) -> Result<(), AudioError>
+    where
+        F: FnMut(&[f32]) -> Result<(), AudioError>,
+    {
+        let expected = self.frames_in * self.to as u64 / self.from.max(1) as u64;
+        // The first call takes the partial chunk; the rest only drain the delay line.
+        let mut input = Some(std::mem::take(&mut self.pending)).filter(|p| !p[0].is_empty());
+        while self.frames_out < expected {
+            let (_, n) = self.inner.process_partial_into_buffer(
+                input.take().as_deref(),
+                &mut self.out,
+                None,
+            )?;
+            if n == 0 {
+                break;
+            }
+            let n = n.min((expected - self.frames_out) as usize + self.skip_frames);
+            self.emit(n, &mut emit)?;
+        }
+        Ok(())
+    }
+
+    fn emit<F>(&mut self, frames: usize, emit: &mut F) -> Result<(), AudioError>
+    where
+        F: FnMut(&[f32]) -> Result<(), AudioError>,
+    {
+        let skip = self.skip_frames.min(frames);
+        self.skip_frames -= skip;
+        if skip == frames {
+            return Ok(());
+        }
+        self.interleaved.clear();
+        for i in skip..frames {
+            self.interleaved.extend(self.out.iter().map(|ch| ch[i]));
+        }
+        self.frames_out += (frames - skip) as u64;
+        emit(&self.interleaved)
+    }
+}
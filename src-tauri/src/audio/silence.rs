@@ -0,0 +1,49 @@
+use std::time::{Duration, Instant};
+
+use super::InputLevel;
+
+/// Stop a recording on its own after a stretch with nothing above the threshold.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SilenceAutoStop {
+    pub enabled: bool,
+    /// RMS level below which a block counts as silence.
+    pub threshold_dbfs: f32,
+    pub timeout_sec: u64,
+}
+
+impl Default for SilenceAutoStop {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_dbfs: -50.0,
+            timeout_sec: 300,
+        }
+    }
+}
+
+pub(crate) struct SilenceDetector {
+    threshold: f32,
+    timeout: Duration,
+    last_sound: Instant,
+}
+
+impl SilenceDetector {
+    pub fn new(config: SilenceAutoStop, now: Instant) -> Self {
+        Self {
+            threshold: 10f32.powf(config.threshold_dbfs / 20.0),
+            timeout: Duration::from_secs(config.timeout_sec.max(1)),
+            last_sound: now,
+        }
+    }
+
+    /// Feeds one metering window; returns true once silence has lasted the whole timeout.
+    /// Paused time never counts: the user asked for that quiet.
+    pub fn observe(&mut self, level: InputLevel, paused: bool, now: Instant) -> bool {
+        if paused || level.rms >= self.threshold {
+            self.last_sound = now;
+            return false;
+        }
+        now.duration_since(self.last_sound) >= self.timeout
+    }
+}
@@ -0,0 +1,401 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use super::AudioError;
+
+/// Receives interleaved `f32` samples in `[-1.0, 1.0]`, one block at a time.
+pub type DataCallback = Box<dyn FnMut(&[f32]) + Send + 'static>;
+/// Receives errors raised by the source after it has started.
+pub type ErrorCallback = Box<dyn FnMut(SourceError) + Send + 'static>;
+
+#[derive(Debug, Clone)]
+pub struct SourceError {
+    pub message: String,
+    /// The source will not deliver any more audio (e.g. the device was unplugged).
+    pub fatal: bool,
+}
+
+impl From<cpal::StreamError> for SourceError {
+    fn from(err: cpal::StreamError) -> Self {
+        Self {
+            fatal: matches!(err, cpal::StreamError::DeviceNotAvailable),
+            message: err.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// Something the recording thread can pull audio from.
+///
+/// Sources are moved into the recording thread and started there, so the
+/// returned stream guard does not need to be `Send` (cpal streams are not on
+/// every platform). Dropping the guard must stop all further callbacks.
+pub trait AudioSource: Send + 'static {
+    type Stream;
+
+    fn format(&self) -> SourceFormat;
+
+    /// Upper bound on frames delivered in one go right after `start`, beyond
+    /// normal block sizes, so the writer can size its buffer to absorb them.
+    fn buffered_frames(&self) -> usize {
+        0
+    }
+
+    /// Human-readable name of the input, for the metadata stored with the recording.
+    fn device_name(&self) -> Option<String> {
+        None
+    }
+
+    fn start(
+        self,
+        on_data: DataCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Self::Stream, AudioError>;
+}
+
+/// Live capture from a cpal input device.
+pub struct CpalSource {
+    device: cpal::Device,
+    supported: cpal::SupportedStreamConfig,
+}
+
+impl CpalSource {
+    pub fn open(device_id: Option<&str>) -> Result<Self, AudioError> {
+        match device_id {
+            Some(id) => Self::from_device(super::devices::find_input_device(id)?),
+            None => Self::default_input(),
+        }
+    }
+
+    pub fn default_input() -> Result<Self, AudioError> {
+        let host = cpal::default_host();
+        let device = host
+            .default_input_device()
+            .ok_or(AudioError::NoDefaultInputDevice)?;
+        Self::from_device(device)
+    }
+
+    pub fn from_device(device: cpal::Device) -> Result<Self, AudioError> {
+        let supported = device.default_input_config()?;
+        Ok(Self { device, supported })
+    }
+
+    /// Switches to a config that runs natively at `rate`, keeping the default's channel
+    /// count (and sample format, if possible). Leaves the default alone if there is none;
+    /// the writer resamples in that case.
+    pub fn prefer_sample_rate(mut self, rate: u32) -> Self {
+        if self.supported.sample_rate().0 == rate {
+            return self;
+        }
+        let Ok(configs) = self.device.supported_input_configs() else {
+            return self;
+        };
+        let channels = self.supported.channels();
+        let sample_format = self.supported.sample_format();
+        let best = configs
+            .filter(|c| c.channels() == channels)
+            .filter(|c| (c.min_sample_rate().0..=c.max_sample_rate().0).contains(&rate))
+            .max_by_key(|c| c.sample_format() == sample_format);
+        if let Some(config) = best {
+            self.supported = config.with_sample_rate(cpal::SampleRate(rate));
+        }
+        self
+    }
+}
+
+fn build_cpal_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut on_data: DataCallback,
+    mut on_error: ErrorCallback,
+) -> Result<cpal::Stream, AudioError>
+where
+    T: cpal::SizedSample,
+    f32: cpal::FromSample<T>,
+{
+    // Grows to the device block size once, then gets reused.
+    let mut buf: Vec<f32> = Vec::new();
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            buf.clear();
+            buf.extend(data.iter().map(|s| s.to_sample::<f32>()));
+            on_data(&buf);
+        },
+        move |err: cpal::StreamError| on_error(err.into()),
+        None,
+    )?;
+    Ok(stream)
+}
+
+impl AudioSource for CpalSource {
+    type Stream = cpal::Stream;
+
+    fn format(&self) -> SourceFormat {
+        SourceFormat {
+            sample_rate: self.supported.sample_rate().0,
+            channels: self.supported.channels().max(1),
+        }
+    }
+
+    fn device_name(&self) -> Option<String> {
+        self.device.name().ok()
+    }
+
+    fn start(
+        self,
+        on_data: DataCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Self::Stream, AudioError> {
+        let config: cpal::StreamConfig = self.supported.clone().into();
+        let (device, format) = (&self.device, self.supported.sample_format());
+        let stream = match format {
+            cpal::SampleFormat::I8 => build_cpal_stream::<i8>(device, &config, on_data, on_error)?,
+            cpal::SampleFormat::I16 => {
+                build_cpal_stream::<i16>(device, &config, on_data, on_error)?
+            }
+            // Packed 24-bit devices are delivered as I32 by cpal's backends.
+            cpal::SampleFormat::I32 => {
+                build_cpal_stream::<i32>(device, &config, on_data, on_error)?
+            }
+            cpal::SampleFormat::I64 => {
+                build_cpal_stream::<i64>(device, &config, on_data, on_error)?
+            }
+            cpal::SampleFormat::U8 => build_cpal_stream::<u8>(device, &config, on_data, on_error)?,
+            cpal::SampleFormat::U16 => {
+                build_cpal_stream::<u16>(device, &config, on_data, on_error)?
+            }
+            cpal::SampleFormat::U32 => {
+                build_cpal_stream::<u32>(device, &config, on_data, on_error)?
+            }
+            cpal::SampleFormat::U64 => {
+                build_cpal_stream::<u64>(device, &config, on_data, on_error)?
+            }
+            cpal::SampleFormat::F32 => {
+                build_cpal_stream::<f32>(device, &config, on_data, on_error)?
+            }
+            cpal::SampleFormat::F64 => {
+                build_cpal_stream::<f64>(device, &config, on_data, on_error)?
+            }
+            _ => return Err(AudioError::UnsupportedSampleFormat),
+        };
+        stream.play()?;
+        Ok(stream)
+    }
+}
+
+/// Stops and joins a feeder thread when dropped.
+pub struct FeederStream {
+    stop: Arc<AtomicBool>,
+    join: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for FeederStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(join) = self.join.take() {
+            let _ = join.join();
+        }
+    }
+}
+
+/// Delivers 10 ms blocks produced by `fill` until it returns 0 or the stream is dropped.
+/// With `realtime` set, blocks are paced to the sample rate like a real device.
+fn spawn_feeder<F>(
+    format: SourceFormat,
+    realtime: bool,
+    mut fill: F,
+    mut on_data: DataCallback,
+) -> FeederStream
+where
+    F: FnMut(&mut [f32]) -> usize + Send + 'static,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_thread = Arc::clone(&stop);
+
+    let join = std::thread::spawn(move || {
+        let channels = format.channels.max(1) as usize;
+        let sample_rate = format.sample_rate.max(1) as u64;
+        let block_frames = (sample_rate / 100).max(1) as usize;
+        let mut buf = vec![0.0f32; block_frames * channels];
+        let started = Instant::now();
+        let mut frames_sent: u64 = 0;
+
+        while !stop_thread.load(Ordering::Relaxed) {
+            let n = fill(&mut buf);
+            if n == 0 {
+                break;
+            }
+            on_data(&buf[..n]);
+            frames_sent += (n / channels) as u64;
+
+            if realtime {
+                let due = Duration::from_micros(frames_sent * 1_000_000 / sample_rate);
+                if let Some(wait) = due.checked_sub(started.elapsed()) {
+                    std::thread::sleep(wait);
+                }
+            }
+        }
+    });
+
+    FeederStream {
+        stop,
+        join: Some(join),
+    }
+}
+
+/// Replays a WAV file as if it were an input device.
+pub struct WavFileSource {
+    format: SourceFormat,
+    samples: Vec<f32>,
+    realtime: bool,
+}
+
+impl WavFileSource {
+    pub fn open(path: &Path) -> Result<Self, AudioError> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = (1u64 << (spec.bits_per_sample.max(1) - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 / scale))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+
+        Ok(Self {
+            format: SourceFormat {
+                sample_rate: spec.sample_rate,
+                channels: spec.channels.max(1),
+            },
+            samples,
+            realtime: true,
+        })
+    }
+
+    /// Paced replay (the default) behaves like a device; unpaced replay runs as fast as possible.
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+}
+
+impl AudioSource for WavFileSource {
+    type Stream = FeederStream;
+
+    fn format(&self) -> SourceFormat {
+        self.format
+    }
+
+    fn start(
+        self,
+        on_data: DataCallback,
+        _on_error: ErrorCallback,
+    ) -> Result<Self::Stream, AudioError> {
+        let samples = self.samples;
+        let mut pos = 0usize;
+        let fill = move |buf: &mut [f32]| {
+            let n = buf.len().min(samples.len() - pos);
+            buf[..n].copy_from_slice(&samples[pos..pos + n]);
+            pos += n;
+            n
+        };
+        Ok(spawn_feeder(self.format, self.realtime, fill, on_data))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Sine { freq_hz: f32 },
+    Noise,
+    Silence,
+}
+
+/// Generates a tone, white noise or silence on every channel, forever.
+pub struct SyntheticSource {
+    format: SourceFormat,
+    waveform: Waveform,
+    amplitude: f32,
+    realtime: bool,
+}
+
+impl SyntheticSource {
+    pub fn new(format: SourceFormat, waveform: Waveform, amplitude: f32) -> Self {
+        Self {
+            format,
+            waveform,
+            amplitude: amplitude.clamp(0.0, 1.0),
+            realtime: true,
+        }
+    }
+
+    pub fn tone(format: SourceFormat, freq_hz: f32, amplitude: f32) -> Self {
+        Self::new(format, Waveform::Sine { freq_hz }, amplitude)
+    }
+
+    pub fn noise(format: SourceFormat, amplitude: f32) -> Self {
+        Self::new(format, Waveform::Noise, amplitude)
+    }
+
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+}
+
+impl AudioSource for SyntheticSource {
+    type Stream = FeederStream;
+
+    fn format(&self) -> SourceFormat {
+        self.format
+    }
+
+    fn start(
+        self,
+        on_data: DataCallback,
+        _on_error: ErrorCallback,
+    ) -> Result<Self::Stream, AudioError> {
+        let channels = self.format.channels.max(1) as usize;
+        let sample_rate = self.format.sample_rate.max(1) as f32;
+        let waveform = self.waveform;
+        let amplitude = self.amplitude;
+        let mut phase = 0.0f32;
+        // xorshift32: deterministic and dependency-free, which is all test noise needs.
+        let mut rng: u32 = 0x9e37_79b9;
+
+        let fill = move |buf: &mut [f32]| {
+            for frame in buf.chunks_mut(channels) {
+                let v = match waveform {
+                    Waveform::Sine { freq_hz } => {
+                        let v = (phase * std::f32::consts::TAU).sin();
+                        phase = (phase + freq_hz / sample_rate).fract();
+                        v
+                    }
+                    Waveform::Noise => {
+                        rng ^= rng << 13;
+                        rng ^= rng >> 17;
+                        rng ^= rng << 5;
+                        (rng as f32 / u32::MAX as f32) * 2.0 - 1.0
+                    }
+                    Waveform::Silence => 0.0,
+                };
+                frame.fill(v * amplitude);
+            }
+            buf.len()
+        };
+        Ok(spawn_feeder(self.format, self.realtime, fill, on_data))
+    }
+}
@@ -0,0 +1,545 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use super::depth::{f32_to_i16, f32_to_i24};
+use super::flac::FlacWriter;
+use super::opus::OpusWriter;
+use super::resample::Resampler;
+use super::{debug_log, in_progress_marker_path, segment_path, AudioError, OutputFormat};
+
+/// How much audio the ring can hold before the callback starts dropping frames.
+const RING_SECONDS: usize = 2;
+/// How long the writer sleeps when the ring is empty.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(20);
+/// How often the header is patched and the file synced, bounding what a crash can lose.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+type WavFileWriter = hound::WavWriter<BufWriter<File>>;
+
+/// The encoder behind the writer thread; `spec` describes the samples for both.
+enum FileWriter {
+    Wav(WavFileWriter),
+    Flac(FlacWriter<BufWriter<File>>),
+    Opus(OpusWriter<BufWriter<File>>),
+}
+
+impl FileWriter {
+    fn new(
+        file: File,
+        spec: hound::WavSpec,
+        format: OutputFormat,
+        opus_bitrate_kbps: u32,
+    ) -> Result<Self, AudioError> {
+        let out = BufWriter::new(file);
+        Ok(match format {
+            OutputFormat::Wav => FileWriter::Wav(hound::WavWriter::new(out, spec)?),
+            OutputFormat::Flac => FileWriter::Flac(FlacWriter::new(
+                out,
+                spec.channels,
+                spec.bits_per_sample,
+                spec.sample_rate,
+            )?),
+            OutputFormat::Opus => FileWriter::Opus(OpusWriter::new(
+                out,
+                spec.channels,
+                spec.sample_rate,
+                opus_bitrate_kbps,
+            )?),
+        })
+    }
+
+    fn write_samples(&mut self, spec: hound::WavSpec, samples: &[f32]) -> Result<(), AudioError> {
+        match (self, spec.sample_format, spec.bits_per_sample) {
+            (FileWriter::Wav(writer), hound::SampleFormat::Float, _) => {
+                for &s in samples {
+                    writer.write_sample(s)?;
+                }
+            }
+            (FileWriter::Wav(writer), hound::SampleFormat::Int, 24) => {
+                for &s in samples {
+                    writer.write_sample(f32_to_i24(s))?;
+                }
+            }
+            (FileWriter::Wav(writer), hound::SampleFormat::Int, _) => {
+                for &s in samples {
+                    writer.write_sample(f32_to_i16(s))?;
+                }
+            }
+            (FileWriter::Flac(writer), _, 24) => {
+                for &s in samples {
+                    writer.write_sample(f32_to_i24(s))?;
+                }
+            }
+            (FileWriter::Flac(writer), _, _) => {
+                for &s in samples {
+                    writer.write_sample(f32_to_i16(s) as i32)?;
+                }
+            }
+            (FileWriter::Opus(writer), _, _) => writer.write_samples(samples)?,
+        }
+        Ok(())
+    }
+
+    /// Rewrites the length fields so the file is playable up to here.
+    fn checkpoint(&mut self) -> Result<(), AudioError> {
+        match self {
+            FileWriter::Wav(writer) => writer.flush()?,
+            FileWriter::Flac(writer) => writer.checkpoint()?,
+            FileWriter::Opus(writer) => writer.checkpoint()?,
+        }
+        Ok(())
+    }
+
+    fn finalize(self) -> Result<(), AudioError> {
+        match self {
+            FileWriter::Wav(writer) => writer.finalize()?,
+            FileWriter::Flac(writer) => writer.finalize()?,
+            FileWriter::Opus(writer) => writer.finalize()?,
+        }
+        Ok(())
+    }
+}
+
+/// Where a recording continues once its current file is full.
+pub struct Rollover {
+    /// The first file; part `n` goes to `segment_path(first_path, n)`.
+    pub first_path: PathBuf,
+    /// Frames per file, at the file rate.
+    pub max_frames: u64,
+}
+
+/// Writes into the current file and starts the next part when it is full.
+/// Counts samples rather than frames, since ring reads can split a frame.
+struct SegmentedWriter {
+    spec: hound::WavSpec,
+    format: OutputFormat,
+    opus_bitrate_kbps: u32,
+    /// Samples per file; `u64::MAX` without a rollover.
+    max_samples: u64,
+    first_path: Option<PathBuf>,
+    current: FileWriter,
+    sync_handle: File,
+    current_samples: u64,
+    /// Frames in each part already finalized.
+    finished_frames: Vec<u64>,
+}
+
+impl SegmentedWriter {
+    fn new(
+        file: File,
+        spec: hound::WavSpec,
+        format: OutputFormat,
+        opus_bitrate_kbps: u32,
+        rollover: Option<Rollover>,
+    ) -> Result<Self, AudioError> {
+        let channels = spec.channels.max(1) as u64;
+        let (max_samples, first_path) = match rollover {
+            Some(r) => (
+                r.max_frames.max(1).saturating_mul(channels),
+                Some(r.first_path),
+            ),
+            None => (u64::MAX, None),
+        };
+        Ok(Self {
+            spec,
+            format,
+            opus_bitrate_kbps,
+            max_samples,
+            first_path,
+            sync_handle: file.try_clone()?,
+            current: FileWriter::new(file, spec, format, opus_bitrate_kbps)?,
+            current_samples: 0,
+            finished_frames: Vec::new(),
+        })
+    }
+
+    fn write_samples(&mut self, mut samples: &[f32]) -> Result<(), AudioError> {
+        while !samples.is_empty() {
+            if self.current_samples >= self.max_samples {
+                self.roll()?;
+            }
+            let room = self.max_samples - self.current_samples;
+            let (now, later) = samples.split_at((samples.len() as u64).min(room) as usize);
+            self.current.write_samples(self.spec, now)?;
+            self.current_samples += now.len() as u64;
+            samples = later;
+        }
+        Ok(())
+    }
+
+    /// Opens the next part before closing the full one, so a failure leaves the
+    /// current file in use rather than none.
+    fn roll(&mut self) -> Result<(), AudioError> {
+        let Some(first_path) = &self.first_path else {
+            return Ok(());
+        };
+        let path = segment_path(first_path, self.finished_frames.len() as u32 + 2);
+        std::fs::write(in_progress_marker_path(&path), b"")?;
+        let file = File::create(&path)?;
+        let sync_handle = file.try_clone()?;
+        let next = FileWriter::new(file, self.spec, self.format, self.opus_bitrate_kbps)?;
+
+        std::mem::replace(&mut self.current, next).finalize()?;
+        let _ = std::mem::replace(&mut self.sync_handle, sync_handle).sync_all();
+        self.finished_frames
+            .push(self.current_samples / self.spec.channels.max(1) as u64);
+        self.current_samples = 0;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<(), AudioError> {
+        self.current.checkpoint()?;
+        let _ = self.sync_handle.sync_data();
+        Ok(())
+    }
+
+    /// Frames in each part, in order.
+    fn finalize(mut self) -> Result<Vec<u64>, AudioError> {
+        self.current.finalize()?;
+        let _ = self.sync_handle.sync_all();
+        self.finished_frames
+            .push(self.current_samples / self.spec.channels.max(1) as u64);
+        Ok(self.finished_frames)
+    }
+}
+
+/// Real-time side of the ring: never locks, never touches the disk.
+pub struct RingInput {
+    producer: rtrb::Producer<f32>,
+    channels: usize,
+    overrun_frames: Arc<AtomicU64>,
+}
+
+impl RingInput {
+    /// Pushes whole frames only; whatever does not fit is counted as overrun.
+    /// Returns the frames accepted.
+    pub fn push_frames(&mut self, samples: &[f32]) -> usize {
+        push_whole_frames(
+            &mut self.producer,
+            self.channels,
+            samples,
+            &self.overrun_frames,
+        )
+    }
+}
+
+/// Pushes as many whole frames of `samples` as the ring has room for and adds the
+/// rest to `dropped_frames`. Lock-free, so it is safe to call from the audio callback.
+/// Returns the frames accepted.
+pub(super) fn push_whole_frames(
+    producer: &mut rtrb::Producer<f32>,
+    channels: usize,
+    samples: &[f32],
+    dropped_frames: &AtomicU64,
+) -> usize {
+    let frames = samples.len() / channels;
+    let fit = (producer.slots() / channels).min(frames);
+    if fit > 0 {
+        if let Ok(chunk) = producer.write_chunk_uninit(fit * channels) {
+            chunk.fill_from_iter(samples[..fit * channels].iter().copied());
+        }
+    }
+    if fit < frames {
+        dropped_frames.fetch_add((frames - fit) as u64, Ordering::Relaxed);
+    }
+    fit
+}
+
+#[derive(Debug, Clone)]
+pub struct WriterStats {
+    pub frames_written: u64,
+    pub overrun_frames: u64,
+    /// Frames in each file; more than one when the recording rolled over.
+    pub segment_frames: Vec<u64>,
+}
+
+/// Drains the ring into the WAV file on its own thread, checkpointing the header as it goes.
+pub struct WriterThread {
+    done: Arc<AtomicBool>,
+    overrun_frames: Arc<AtomicU64>,
+    join: std::thread::JoinHandle<Result<Vec<u64>, AudioError>>,
+}
+
+impl WriterThread {
+    /// Frames arrive at `input_rate` and are resampled to `spec.sample_rate` if the two differ.
+    /// `extra_frames` grows the ring beyond the usual headroom, e.g. for pre-roll pushed all at once.
+    /// For FLAC, `spec` must be an integer format (see `OutputFormat::Flac`).
+    /// With a `rollover`, `file` is its first part and later parts are created as needed.
+    pub fn spawn(
+        file: File,
+        spec: hound::WavSpec,
+        format: OutputFormat,
+        opus_bitrate_kbps: u32,
+        input_rate: u32,
+        extra_frames: usize,
+        rollover: Option<Rollover>,
+    ) -> Result<(RingInput, WriterThread), AudioError> {
+        let mut resampler = if input_rate != spec.sample_rate {
+            Some(Resampler::new(input_rate, spec.sample_rate, spec.channels)?)
+        } else {
+            None
+        };
+        let mut writer = SegmentedWriter::new(file, spec, format, opus_bitrate_kbps, rollover)?;
+        let channels = spec.channels.max(1) as usize;
+        let capacity = ((input_rate.max(1) as usize) * RING_SECONDS + extra_frames) * channels;
+        let (producer, mut consumer) = rtrb::RingBuffer::<f32>::new(capacity);
+        let done = Arc::new(AtomicBool::new(false));
+        let overrun_frames = Arc::new(AtomicU64::new(0));
+
+        let done_thread = Arc::clone(&done);
+        let join = std::thread::spawn(move || -> Result<Vec<u64>, AudioError> {
+            let mut drain = || -> Result<(), AudioError> {
+                let mut last_checkpoint = Instant::now();
+                loop {
+                    if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                        writer.checkpoint()?;
+                        last_checkpoint = Instant::now();
+                    }
+
+                    // Read the flag first: anything pushed before it was set is visible below.
+                    let finished = done_thread.load(Ordering::Acquire);
+                    let available = consumer.slots();
+                    if available == 0 {
+                        if finished {
+                            break;
+                        }
+                        std::thread::sleep(DRAIN_INTERVAL);
+                        continue;
+                    }
+
+                    let Ok(chunk) = consumer.read_chunk(available) else {
+                        continue;
+                    };
+                    let (a, b) = chunk.as_slices();
+                    match resampler.as_mut() {
+                        Some(r) => r.process(a.iter().chain(b).copied(), |block| {
+                            writer.write_samples(block)
+                        })?,
+                        None => {
+                            writer.write_samples(a)?;
+                            writer.write_samples(b)?;
+                        }
+                    }
+                    chunk.commit_all();
+                }
+
+                if let Some(r) = resampler.take() {
+                    r.finish(|block| writer.write_samples(block))?;
+                }
+                Ok(())
+            };
+
+            if let Err(err) = drain() {
+                // Best effort: a header that covers what did reach the file beats waiting
+                // for crash recovery to find it.
+                if let Err(finalize_err) = writer.finalize() {
+                    debug_log(&format!("cannot finalize after {err}: {finalize_err}"));
+                }
+                return Err(err);
+            }
+            writer.finalize()
+        });
+
+        let input = RingInput {
+            producer,
+            channels,
+            overrun_frames: Arc::clone(&overrun_frames),
+        };
+        let thread = WriterThread {
+            done,
+            overrun_frames,
+            join,
+        };
+        Ok((input, thread))
+    }
+
+    /// Call once the producer side can no longer push (the stream has been dropped).
+    pub fn finish(self) -> Result<WriterStats, AudioError> {
+        self.done.store(true, Ordering::Release);
+        let segment_frames = self.join.join().map_err(|_| {
+            AudioError::Io(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "writer thread panicked",
+            ))
+        })??;
+        Ok(WriterStats {
+            frames_written: segment_frames.iter().sum(),
+            overrun_frames: self.overrun_frames.load(Ordering::Relaxed),
+            segment_frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::parse_segment_stem;
+    use std::path::Path;
+
+    fn spec(channels: u16) -> hound::WavSpec {
+        hound::WavSpec {
+            channels,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+
+    /// Samples that differ from their neighbours, so a dropped or doubled one shows.
+    fn ramp(frames: usize, channels: u16) -> Vec<f32> {
+        (0..frames * channels as usize)
+            .map(|i| (i % 2_000) as f32 / 4_000.0 - 0.25)
+            .collect()
+    }
+
+    fn read_samples(path: &Path) -> Vec<i16> {
+        hound::WavReader::open(path)
+            .unwrap()
+            .into_samples::<i16>()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn segmented(dir: &Path, channels: u16, max_frames: Option<u64>) -> SegmentedWriter {
+        let first_path = dir.join("2026-01-02_03-04-05.wav");
+        let rollover = max_frames.map(|max_frames| Rollover {
+            first_path: first_path.clone(),
+            max_frames,
+        });
+        let file = File::create(&first_path).unwrap();
+        SegmentedWriter::new(file, spec(channels), OutputFormat::Wav, 0, rollover).unwrap()
+    }
+
+    #[test]
+    fn rolls_over_at_max_frames_even_mid_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = segmented(dir.path(), 2, Some(1_000));
+        let samples = ramp(2_500, 2);
+        // An odd chunk size, so frames arrive split across writes.
+        for chunk in samples.chunks(333) {
+            writer.write_samples(chunk).unwrap();
+        }
+        assert_eq!(writer.finalize().unwrap(), [1_000, 1_000, 500]);
+
+        let first = dir.path().join("2026-01-02_03-04-05.wav");
+        let parts = [
+            first.clone(),
+            segment_path(&first, 2),
+            segment_path(&first, 3),
+        ];
+        let mut written = Vec::new();
+        for (part, frames) in parts.iter().zip([1_000, 1_000, 500]) {
+            let read = read_samples(part);
+            assert_eq!(read.len(), frames * 2, "{}", part.display());
+            written.extend(read);
+        }
+        let expected: Vec<i16> = samples.iter().map(|&s| f32_to_i16(s)).collect();
+        assert_eq!(written, expected);
+        // Later parts are flagged while open, like the first one is by the session.
+        assert!(in_progress_marker_path(&parts[1]).exists());
+        assert!(in_progress_marker_path(&parts[2]).exists());
+    }
+
+    #[test]
+    fn a_full_last_part_does_not_open_an_empty_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = segmented(dir.path(), 1, Some(1_000));
+        writer.write_samples(&ramp(2_000, 1)).unwrap();
+        assert_eq!(writer.finalize().unwrap(), [1_000, 1_000]);
+        let first = dir.path().join("2026-01-02_03-04-05.wav");
+        assert!(!segment_path(&first, 3).exists());
+    }
+
+    #[test]
+    fn without_rollover_everything_goes_to_one_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = segmented(dir.path(), 2, None);
+        for _ in 0..4 {
+            writer.write_samples(&ramp(3_000, 2)).unwrap();
+        }
+        assert_eq!(writer.finalize().unwrap(), [12_000]);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn writer_thread_reports_frames_per_part() {
+        let dir = tempfile::tempdir().unwrap();
+        let first_path = dir.path().join("a.wav");
+        let rollover = Rollover {
+            first_path: first_path.clone(),
+            max_frames: 3_000,
+        };
+        let file = File::create(&first_path).unwrap();
+        let (mut input, thread) = WriterThread::spawn(
+            file,
+            spec(2),
+            OutputFormat::Wav,
+            0,
+            8_000,
+            0,
+            Some(rollover),
+        )
+        .unwrap();
+        let samples = ramp(7_000, 2);
+        let mut pushed = 0;
+        for chunk in samples.chunks(1_000) {
+            pushed += input.push_frames(chunk);
+        }
+        drop(input);
+        let stats = thread.finish().unwrap();
+        assert_eq!(pushed, 7_000);
+        assert_eq!(stats.segment_frames, [3_000, 3_000, 1_000]);
+        assert_eq!(stats.frames_written, 7_000);
+        assert_eq!(stats.overrun_frames, 0);
+    }
+
+    #[test]
+    fn push_frames_counts_only_what_fits() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = File::create(dir.path().join("a.wav")).unwrap();
+        let (mut input, thread) =
+            WriterThread::spawn(file, spec(1), OutputFormat::Wav, 0, 8_000, 0, None).unwrap();
+        let capacity = 8_000 * RING_SECONDS;
+        // One push larger than the ring: the writer can't drain it part way through.
+        let pushed = input.push_frames(&ramp(capacity + 500, 1));
+        drop(input);
+        let stats = thread.finish().unwrap();
+        assert_eq!(pushed, capacity);
+        assert_eq!(stats.overrun_frames, 500);
+        assert_eq!(stats.frames_written, capacity as u64);
+    }
+
+    #[test]
+    fn segment_names_round_trip() {
+        let first = Path::new("/r/2026-01-02_03-04-05 Standup.flac");
+        let part = segment_path(first, 12);
+        assert_eq!(
+            part,
+            Path::new("/r/2026-01-02_03-04-05 Standup_part12.flac")
+        );
+        let stem = part.file_stem().unwrap().to_str().unwrap();
+        assert_eq!(
+            parse_segment_stem(stem),
+            Some(("2026-01-02_03-04-05 Standup", 12))
+        );
+        assert_eq!(
+            parse_segment_stem("2026 my_party_part3"),
+            Some(("2026 my_party", 3))
+        );
+        for stem in [
+            "2026-01-02_03-04-05",
+            "2026-01-02_03-04-05_part1",
+            "2026-01-02_03-04-05_part",
+            "2026-01-02_03-04-05_partx",
+            "_part2",
+        ] {
+            assert_eq!(parse_segment_stem(stem), None, "{stem}");
+        }
+    }
+}
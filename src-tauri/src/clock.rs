@@ -0,0 +1,60 @@
+//! Wall-clock timestamps that carry their UTC offset.
+
+use std::sync::OnceLock;
+
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+static STARTUP_OFFSET: OnceLock<Option<UtcOffset>> = OnceLock::new();
+
+/// Reads the local offset while the process is still single-threaded. On Unix, `time`
+/// refuses to look it up once other threads exist, so later lookups fall back to this.
+pub fn init() {
+    STARTUP_OFFSET.get_or_init(|| UtcOffset::current_local_offset().ok());
+}
+
+/// The local offset now if it can be read, else the one seen at startup, else UTC.
+/// Whichever it is ends up in the timestamp, so the instant is never ambiguous.
+pub fn local_offset() -> UtcOffset {
+    UtcOffset::current_local_offset()
+        .ok()
+        .or_else(|| STARTUP_OFFSET.get().copied().flatten())
+        .unwrap_or_else(|| {
+            crate::debug_log("local UTC offset unknown, using UTC");
+            UtcOffset::UTC
+        })
+}
+
+pub fn now_local() -> OffsetDateTime {
+    OffsetDateTime::now_utc().to_offset(local_offset())
+}
+
+/// RFC 3339 with the offset, e.g. `2024-03-31T09:15:00+02:00`.
+pub fn format_rfc3339(t: OffsetDateTime) -> String {
+    t.replace_nanosecond(0)
+        .unwrap_or(t)
+        .format(&Rfc3339)
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+/// Reads RFC 3339, or the offset-less `YYYY-MM-DDTHH:MM:SS` older versions wrote,
+/// which is taken to be at offset `assume`.
+pub fn parse_timestamp(s: &str, assume: UtcOffset) -> Option<OffsetDateTime> {
+    if let Ok(t) = OffsetDateTime::parse(s, &Rfc3339) {
+        return Some(t);
+    }
+    let naive = format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]");
+    PrimitiveDateTime::parse(s, naive)
+        .ok()
+        .map(|t| t.assume_offset(assume))
+}
+
+/// `s` as RFC 3339, guessing the current local offset if it has none. Unreadable
+/// values are returned unchanged.
+pub fn normalize_timestamp(s: &str) -> String {
+    match parse_timestamp(s, local_offset()) {
+        Some(t) => format_rfc3339(t),
+        None => s.to_string(),
+    }
+}
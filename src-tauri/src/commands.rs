@@ -1,8 +1,25 @@
-use tauri::{AppHandle, Emitter, State};
+use tauri::{AppHandle, Emitter, Manager, State};
 
 use crate::audio;
-use crate::storage::RecordingEntry;
-use crate::{emit_recording_state, notify, set_tray_recording_state, AppState};
+use crate::settings::Settings;
+use crate::storage::{
+    non_blank, normalize_tags, LibraryGuard, RecordingEntry, RecordingQuery, TrashSettings,
+};
+use crate::{
+    emit_input_level, emit_marker_added, emit_recording_auto_stopped, emit_recording_error,
+    emit_recording_paused, emit_recording_state, notify, set_tray_level, set_tray_recording_state,
+    AppState,
+};
+
+/// One file of a recording.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentItem {
+    pub filename: String,
+    pub duration_sec: f64,
+    pub size_bytes: u64,
+    pub path: String,
+}
 
 #[derive(Debug, Clone, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -11,24 +28,102 @@ pub struct RecordingItem {
     pub filename: String,
     pub created_at: String,
     pub duration_sec: f64,
+    pub wall_clock_sec: Option<f64>,
     pub size_bytes: u64,
+    pub channels: u16,
+    pub sample_rate: Option<u32>,
+    pub device_sample_rate: Option<u32>,
+    pub recovered: bool,
+    pub title: Option<String>,
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+    pub input_device: Option<String>,
+    pub markers: Vec<audio::Marker>,
+    /// Path of the first file.
     pub path: String,
+    /// Every file in playback order; a single one unless the recording rolled over.
+    pub segments: Vec<SegmentItem>,
+}
+
+fn lock_library(state: &AppState) -> Result<LibraryGuard<'_>, String> {
+    state.storage.library.lock().map_err(|e| e.to_string())
 }
 
 fn to_item(storage: &crate::storage::Storage, e: &RecordingEntry) -> RecordingItem {
     let path = storage.recording_path(&e.filename);
+    let segments = if e.segments.is_empty() {
+        vec![SegmentItem {
+            filename: e.filename.clone(),
+            duration_sec: e.duration_sec,
+            size_bytes: e.size_bytes,
+            path: path.to_string_lossy().to_string(),
+        }]
+    } else {
+        e.segments
+            .iter()
+            .map(|s| SegmentItem {
+                filename: s.filename.clone(),
+                duration_sec: s.duration_sec,
+                size_bytes: s.size_bytes,
+                path: storage
+                    .recording_path(&s.filename)
+                    .to_string_lossy()
+                    .to_string(),
+            })
+            .collect()
+    };
     RecordingItem {
         id: e.id.clone(),
         filename: e.filename.clone(),
         created_at: e.created_at.clone(),
         duration_sec: e.duration_sec,
+        wall_clock_sec: e.wall_clock_sec,
         size_bytes: e.size_bytes,
+        channels: e.channels,
+        sample_rate: e.sample_rate,
+        device_sample_rate: e.device_sample_rate,
+        recovered: e.recovered,
+        title: e.title.clone(),
+        notes: e.notes.clone(),
+        tags: e.tags.clone(),
+        input_device: e.input_device.clone(),
+        markers: e.markers.clone(),
         path: path.to_string_lossy().to_string(),
+        segments,
     }
 }
 
 #[tauri::command]
-pub fn start_recording(app: AppHandle, state: State<'_, AppState>) -> Result<(), String> {
+pub fn start_recording(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    device_id: Option<String>,
+) -> Result<(), String> {
+    let (device_id, mut options) = {
+        let settings = state
+            .settings
+            .lock()
+            .map_err(|_| "settings mutex poisoned".to_string())?;
+        (
+            device_id.or_else(|| settings.preferred_input_device.clone()),
+            settings.recording_options(),
+        )
+    };
+    let app_events = app.clone();
+    options.on_event = Some(Arc::new(move |event| match event {
+        audio::SessionEvent::Level(level) => {
+            let _ = emit_input_level(&app_events, level);
+            let _ = set_tray_level(&app_events, Some(level));
+        }
+        audio::SessionEvent::StoppedItself { filename, .. } => {
+            // The session thread is the one `stop` joins, so finish from another thread.
+            let app = app_events.clone();
+            std::thread::spawn(move || {
+                let _ = finish_stopped_session(&app, &filename);
+            });
+        }
+    }));
+
     {
         let mut guard = state
             .active_recording
@@ -38,8 +133,36 @@ pub fn start_recording(app: AppHandle, state: State<'_, AppState>) -> Result<(),
             return Err("already recording".to_string());
         }
 
-        let active = audio::start_recording(&state.storage.recordings_dir)
-            .map_err(|e| format!("failed to start recording: {e}"))?;
+        let dir = &state.storage.recordings_dir;
+        let armed = state
+            .armed_input
+            .lock()
+            .map_err(|_| "armed input mutex poisoned".to_string())?;
+        let started = match armed.as_ref() {
+            // Same device as the armed input: take over its stream and its history.
+            Some(armed) if armed.device_id() == device_id.as_deref() => {
+                audio::RecordingSession::start(dir, armed.source(), options.clone())
+            }
+            _ => audio::start_recording(dir, device_id.as_deref(), options.clone()),
+        };
+        drop(armed);
+        let active = match started {
+            Err(e @ audio::AudioError::InputDeviceNotFound(_)) => {
+                // Headsets and docks come and go; recording from the default beats not recording.
+                let _ = notify(&app, &format!("{e}, using the default input"));
+                audio::start_recording(dir, None, options)
+            }
+            other => other,
+        };
+        let active = match active {
+            Ok(active) => active,
+            Err(e @ audio::AudioError::InsufficientDiskSpace { .. }) => {
+                // Also reached from the hotkey, where nobody sees the returned error.
+                let _ = notify(&app, &format!("Recording not started: {e}"));
+                return Err(format!("failed to start recording: {e}"));
+            }
+            Err(e) => return Err(format!("failed to start recording: {e}")),
+        };
         *guard = Some(active);
     }
 
@@ -61,46 +184,617 @@ pub fn stop_recording(app: AppHandle, state: State<'_, AppState>) -> Result<Reco
             .ok_or_else(|| "not recording".to_string())?
     };
 
-    let finished = audio::stop_recording(active).map_err(|e| format!("failed to stop: {e}"))?;
+    finish_recording(&app, &state, active)
+}
 
-    let entry = RecordingEntry {
-        id: finished.filename.trim_end_matches(".wav").to_string(),
-        filename: finished.filename,
-        created_at: finished.created_at,
-        duration_sec: finished.duration_sec,
-        size_bytes: finished.size_bytes,
+/// Collects a session that ended on its own. Checking `filename` keeps a late
+/// call from stopping a newer recording the user started in the meantime.
+fn finish_stopped_session(app: &AppHandle, filename: &str) -> Result<RecordingItem, String> {
+    let state = app.state::<AppState>();
+    let active = {
+        let mut guard = state
+            .active_recording
+            .lock()
+            .map_err(|_| "recording mutex poisoned".to_string())?;
+        match guard.as_ref() {
+            Some(active) if active.filename() == filename => guard.take(),
+            _ => None,
+        }
+        .ok_or_else(|| "session already collected".to_string())?
     };
 
+    finish_recording(app, &state, active)
+}
+
+/// Stops `active`, indexes the file and tells the UI and tray; shared by every way a recording ends.
+fn finish_recording(
+    app: &AppHandle,
+    state: &AppState,
+    active: audio::RecordingSession,
+) -> Result<RecordingItem, String> {
+    let finished = match audio::stop_recording(active) {
+        Ok(finished) => finished,
+        Err(err) => {
+            // The session is gone either way; don't leave the UI claiming it is still running.
+            let message = format!("failed to stop: {err}");
+            let _ = notify(app, &format!("Recording failed: {err}"));
+            let _ = emit_recording_error(app, &message);
+            let _ = set_tray_recording_state(app, false);
+            let _ = set_tray_level(app, None);
+            let _ = emit_recording_state(app, false);
+            let _ = emit_recording_paused(app, false);
+            return Err(message);
+        }
+    };
+
+    let mut entry = RecordingEntry::from_finished(&finished);
+    // The recording is intact without it, so a failure here is not worth failing the stop over.
+    if let Err(err) = state.storage.embed_metadata(&mut entry) {
+        crate::debug_log(&format!(
+            "failed to embed metadata in {}: {err}",
+            entry.filename
+        ));
+    }
+
+    lock_library(state)?
+        .save(&entry)
+        .map_err(|e| format!("failed to save index: {e}"))?;
+    for filename in entry.filenames() {
+        state.storage.clear_in_progress(filename);
+    }
+
+    if let Some(reason) = &finished.stop_reason {
+        let _ = emit_recording_auto_stopped(app, reason);
+    }
+    let mut message = match &finished.stop_reason {
+        Some(reason) if reason.is_error() => {
+            let _ = emit_recording_error(app, &reason.to_string());
+            format!("Recording stopped early ({reason}); audio up to that point was saved")
+        }
+        Some(reason) => format!("Recording stopped: {reason}"),
+        None => "Recording stopped".to_string(),
+    };
+    if finished.overrun_frames > 0 {
+        message.push_str(" (some audio was lost: disk too slow)");
+    }
+    let _ = notify(app, &message);
+    let _ = set_tray_recording_state(app, false);
+    let _ = set_tray_level(app, None);
+    let _ = emit_recording_state(app, false);
+    let _ = emit_recording_paused(app, false);
+
+    // Let the UI refresh without polling.
+    let _ = app.emit("recordings_updated", ());
+
+    // Settings may have changed mid-recording; the armed input was left alone until now.
+    if let Err(err) = sync_pre_roll(state) {
+        let _ = notify(app, &err);
+    }
+
+    Ok(to_item(&state.storage, &entry))
+}
+
+#[tauri::command]
+pub fn pause_recording(app: AppHandle, state: State<'_, AppState>) -> Result<(), String> {
     {
-        let mut recs = state
-            .recordings
+        let mut guard = state
+            .active_recording
             .lock()
-            .map_err(|_| "recordings mutex poisoned".to_string())?;
-        recs.push(entry.clone());
-        recs.sort_by(|a, b| b.filename.cmp(&a.filename));
-        state
-            .storage
-            .save_index(&recs)
+            .map_err(|_| "recording mutex poisoned".to_string())?;
+        let active = guard.as_mut().ok_or_else(|| "not recording".to_string())?;
+        if !active.pause() {
+            return Err("already paused".to_string());
+        }
+    }
+
+    let _ = notify(&app, "Recording paused");
+    let _ = set_tray_recording_state(&app, true);
+    let _ = emit_recording_paused(&app, true);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn resume_recording(app: AppHandle, state: State<'_, AppState>) -> Result<(), String> {
+    {
+        let mut guard = state
+            .active_recording
+            .lock()
+            .map_err(|_| "recording mutex poisoned".to_string())?;
+        let active = guard.as_mut().ok_or_else(|| "not recording".to_string())?;
+        if !active.resume() {
+            return Err("not paused".to_string());
+        }
+    }
+
+    let _ = notify(&app, "Recording resumed");
+    let _ = set_tray_recording_state(&app, true);
+    let _ = emit_recording_paused(&app, false);
+    Ok(())
+}
+
+/// Bookmarks the current position of the running recording.
+#[tauri::command]
+pub fn add_marker(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    label: Option<String>,
+) -> Result<audio::Marker, String> {
+    let marker = {
+        let mut guard = state
+            .active_recording
+            .lock()
+            .map_err(|_| "recording mutex poisoned".to_string())?;
+        let active = guard.as_mut().ok_or_else(|| "not recording".to_string())?;
+        active.add_marker(label.filter(|l| !l.trim().is_empty()))
+    };
+
+    let secs = marker.position_sec as u64;
+    let _ = notify(
+        &app,
+        &format!("Marker added at {}:{:02}", secs / 60, secs % 60),
+    );
+    let _ = emit_marker_added(&app, &marker);
+    Ok(marker)
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingPage {
+    pub items: Vec<RecordingItem>,
+    /// Recordings matching the query, across all pages.
+    pub total: u64,
+    pub offset: u32,
+}
+
+/// One page of recordings; without a query, the newest 100.
+#[tauri::command]
+pub fn list_recordings(
+    state: State<'_, AppState>,
+    query: Option<RecordingQuery>,
+) -> Result<RecordingPage, String> {
+    let query = query.unwrap_or_default();
+    let (recs, total) = lock_library(&state)?
+        .query(&query)
+        .map_err(|e| format!("failed to list recordings: {e}"))?;
+    Ok(RecordingPage {
+        items: recs.iter().map(|e| to_item(&state.storage, e)).collect(),
+        total,
+        offset: query.offset,
+    })
+}
+
+/// Replaces a recording's title, notes and tags; blank text clears a field. The files get
+/// the new values in their embedded metadata too.
+#[tauri::command]
+pub fn update_recording_metadata(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    id: String,
+    title: Option<String>,
+    notes: Option<String>,
+    tags: Vec<String>,
+) -> Result<RecordingItem, String> {
+    let item = {
+        let library = lock_library(&state)?;
+        let mut entry = library
+            .get(&id)
+            .map_err(|e| format!("failed to read library: {e}"))?
+            .ok_or_else(|| format!("no recording with id {id}"))?;
+        entry.title = non_blank(title);
+        entry.notes = non_blank(notes);
+        entry.tags = normalize_tags(&tags);
+        // The index is what the app shows, so a file that can't be rewritten doesn't block the edit.
+        if let Err(err) = state.storage.embed_metadata(&mut entry) {
+            crate::debug_log(&format!(
+                "failed to embed metadata in {}: {err}",
+                entry.filename
+            ));
+        }
+        library
+            .save(&entry)
             .map_err(|e| format!("failed to save index: {e}"))?;
+        to_item(&state.storage, &entry)
+    };
+    let _ = app.emit("recordings_updated", ());
+    Ok(item)
+}
+
+/// Renames a recording's files to `<date>_<time> <title>`, using `title` or else the
+/// recording's own. Only the file names change; the id and everything else stay.
+#[tauri::command]
+pub fn rename_recording(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    id: String,
+    title: Option<String>,
+) -> Result<RecordingItem, String> {
+    // The conversion has the old names queued.
+    if state.flac_conversion_running.load(Ordering::Acquire) {
+        return Err("FLAC conversion is running; rename once it is done".to_string());
     }
+    let result = {
+        let library = lock_library(&state)?;
+        state
+            .storage
+            .rename_recording(&library, &id, title.as_deref())
+    };
+    // Even a failed save may have moved the files; the list shows where they are.
+    let _ = app.emit("recordings_updated", ());
+    let entry = result.map_err(|e| format!("failed to rename recording: {e}"))?;
+    Ok(to_item(&state.storage, &entry))
+}
 
-    let _ = notify(&app, "Recording stopped");
-    let _ = set_tray_recording_state(&app, false);
-    let _ = emit_recording_state(&app, false);
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagItem {
+    pub tag: String,
+    /// Recordings carrying it.
+    pub count: usize,
+}
 
-    // Let the UI refresh without polling.
+/// Every tag in use, alphabetically (ignoring case), with how often it is used. Tags
+/// differing only in case are counted as one.
+#[tauri::command]
+pub fn list_tags(state: State<'_, AppState>) -> Result<Vec<TagItem>, String> {
+    let tags = lock_library(&state)?
+        .tags()
+        .map_err(|e| format!("failed to read library: {e}"))?;
+    Ok(tags
+        .into_iter()
+        .map(|(tag, count)| TagItem { tag, count })
+        .collect())
+}
+
+/// A deleted recording waiting in the trash.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashItem {
+    pub id: String,
+    pub filename: String,
+    pub created_at: String,
+    pub title: Option<String>,
+    pub duration_sec: f64,
+    pub size_bytes: u64,
+    pub deleted_at: String,
+    /// When it will be removed for good; `None` if the trash is kept until emptied.
+    pub purge_at: Option<String>,
+}
+
+fn trash_settings(state: &AppState) -> Result<TrashSettings, String> {
+    state
+        .settings
+        .lock()
+        .map(|s| s.trash)
+        .map_err(|_| "settings mutex poisoned".to_string())
+}
+
+/// Drops recordings that have outstayed the retention period.
+pub(crate) fn purge_expired_trash(state: &AppState) -> Result<usize, String> {
+    let settings = trash_settings(state)?;
+    state
+        .storage
+        .purge_expired_trash(&settings)
+        .map_err(|e| format!("failed to purge trash: {e}"))
+}
+
+/// Moves a recording's files into the trash and takes it out of the list.
+#[tauri::command]
+pub fn delete_recording(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    id: String,
+) -> Result<(), String> {
+    {
+        let library = lock_library(&state)?;
+        state
+            .storage
+            .move_to_trash(&library, &id)
+            .map_err(|e| format!("failed to delete recording: {e}"))?;
+    }
+    if let Err(err) = purge_expired_trash(&state) {
+        crate::debug_log(&err);
+    }
     let _ = app.emit("recordings_updated", ());
+    Ok(())
+}
 
+#[tauri::command]
+pub fn list_trash(state: State<'_, AppState>) -> Result<Vec<TrashItem>, String> {
+    if let Err(err) = purge_expired_trash(&state) {
+        crate::debug_log(&err);
+    }
+    let settings = trash_settings(&state)?;
+    // Held so a concurrent delete or restore can't rewrite the trash index underneath.
+    let _library = lock_library(&state)?;
+    let items = state
+        .storage
+        .load_trash()
+        .map_err(|e| format!("failed to read trash: {e}"))?;
+    Ok(items
+        .into_iter()
+        .map(|t| TrashItem {
+            purge_at: settings.purge_at(&t.deleted_at),
+            id: t.entry.id,
+            filename: t.entry.filename,
+            created_at: t.entry.created_at,
+            title: t.entry.title,
+            duration_sec: t.entry.duration_sec,
+            size_bytes: t.entry.size_bytes,
+            deleted_at: t.deleted_at,
+        })
+        .collect())
+}
+
+/// Puts a deleted recording back under its original file names.
+#[tauri::command]
+pub fn restore_recording(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    id: String,
+) -> Result<RecordingItem, String> {
+    let entry = {
+        let library = lock_library(&state)?;
+        state
+            .storage
+            .restore_from_trash(&library, &id)
+            .map_err(|e| format!("failed to restore recording: {e}"))?
+    };
+    let _ = app.emit("recordings_updated", ());
     Ok(to_item(&state.storage, &entry))
 }
 
+/// Permanently deletes everything in the trash and returns how many recordings went.
 #[tauri::command]
-pub fn list_recordings(state: State<'_, AppState>) -> Result<Vec<RecordingItem>, String> {
-    let recs = state
-        .recordings
+pub fn empty_trash(state: State<'_, AppState>) -> Result<usize, String> {
+    let _library = lock_library(&state)?;
+    state
+        .storage
+        .purge_trash(|_| true)
+        .map_err(|e| format!("failed to empty trash: {e}"))
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlacConversionProgress {
+    pub done: usize,
+    pub total: usize,
+    pub failed: usize,
+}
+
+/// Re-encodes every indexed WAV as FLAC on a background thread and returns how many files
+/// were queued; each part of a segmented recording counts.
+/// Progress arrives as `flac_conversion_progress` events; the list refreshes after each file.
+#[tauri::command]
+pub fn convert_recordings_to_flac(
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<usize, String> {
+    if state.flac_conversion_running.swap(true, Ordering::AcqRel) {
+        return Err("conversion already running".to_string());
+    }
+    let queue: Vec<String> = match lock_library(&state).and_then(|library| {
+        library
+            .all()
+            .map_err(|e| format!("failed to read library: {e}"))
+    }) {
+        Ok(recs) => recs
+            .iter()
+            .flat_map(|e| e.filenames())
+            .filter(|filename| {
+                audio::OutputFormat::from_path(Path::new(filename))
+                    == Some(audio::OutputFormat::Wav)
+            })
+            .map(str::to_string)
+            .collect(),
+        Err(err) => {
+            state
+                .flac_conversion_running
+                .store(false, Ordering::Release);
+            return Err(err);
+        }
+    };
+
+    let total = queue.len();
+    std::thread::spawn(move || {
+        let state = app.state::<AppState>();
+        let mut failed = 0;
+        for (i, filename) in queue.iter().enumerate() {
+            match convert_to_flac(&state, filename) {
+                Ok(true) => {
+                    let _ = app.emit("recordings_updated", ());
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    failed += 1;
+                    crate::debug_log(&format!("flac conversion of {filename} failed: {err}"));
+                }
+            }
+            let _ = app.emit(
+                "flac_conversion_progress",
+                FlacConversionProgress {
+                    done: i + 1,
+                    total,
+                    failed,
+                },
+            );
+        }
+        state
+            .flac_conversion_running
+            .store(false, Ordering::Release);
+        if total > 0 {
+            let message = match failed {
+                0 => format!("Converted {total} recordings to FLAC"),
+                _ => format!("Converted {} of {total} recordings to FLAC", total - failed),
+            };
+            let _ = notify(&app, &message);
+        }
+    });
+    Ok(total)
+}
+
+/// Converts one indexed WAV. The FLAC is complete on disk and checked against the WAV
+/// before the index points at it, and the WAV is only removed once the index no longer
+/// does, so a crash at any step leaves at least one playable, indexed copy. Returns false
+/// if the entry went away meanwhile.
+fn convert_to_flac(state: &AppState, filename: &str) -> Result<bool, String> {
+    let storage = &state.storage;
+    let src = storage.recording_path(filename);
+    let flac_name = Path::new(filename)
+        .with_extension(audio::OutputFormat::Flac.extension())
+        .to_string_lossy()
+        .to_string();
+    let dst = storage.recording_path(&flac_name);
+    let tmp = storage.recording_path(&format!("{flac_name}.tmp"));
+    // Never replace a file that is not ours to replace; the WAV is deleted afterwards.
+    if dst.exists() {
+        return Err(format!("{flac_name} already exists"));
+    }
+
+    if let Err(err) = audio::flac::transcode_wav(&src, &tmp) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err.to_string());
+    }
+    std::fs::rename(&tmp, &dst).map_err(|e| e.to_string())?;
+    let size_bytes = std::fs::metadata(&dst).map_err(|e| e.to_string())?.len();
+
+    {
+        let library = lock_library(state)?;
+        let found = library
+            .find_by_file(filename)
+            .map_err(|e| format!("failed to read library: {e}"))?;
+        let Some(mut entry) = found else {
+            let _ = std::fs::remove_file(&dst);
+            return Ok(false);
+        };
+        entry.replace_file(filename, &flac_name, size_bytes);
+        // The WAV's chunks don't carry over; the FLAC gets the same in Vorbis comments.
+        if let Err(err) = storage.embed_metadata(&mut entry) {
+            crate::debug_log(&format!("failed to embed metadata in {flac_name}: {err}"));
+        }
+        if let Err(err) = library.save(&entry) {
+            let _ = std::fs::remove_file(&dst);
+            return Err(format!("failed to save index: {err}"));
+        }
+    }
+
+    let _ = std::fs::remove_file(&src);
+    Ok(true)
+}
+
+/// Live level while recording; otherwise briefly opens the device to test it.
+#[tauri::command]
+pub async fn get_input_level(
+    state: State<'_, AppState>,
+    device_id: Option<String>,
+) -> Result<audio::InputLevel, String> {
+    {
+        let guard = state
+            .active_recording
+            .lock()
+            .map_err(|_| "recording mutex poisoned".to_string())?;
+        if let Some(active) = guard.as_ref() {
+            return Ok(active.input_level());
+        }
+    }
+
+    let device_id = match device_id {
+        Some(id) => Some(id),
+        None => state
+            .settings
+            .lock()
+            .map_err(|_| "settings mutex poisoned".to_string())?
+            .preferred_input_device
+            .clone(),
+    };
+    tauri::async_runtime::spawn_blocking(move || {
+        audio::probe_input_level(device_id.as_deref(), Duration::from_millis(300))
+    })
+    .await
+    .map_err(|e| format!("level probe failed: {e}"))?
+    .map_err(|e| format!("failed to read input level: {e}"))
+}
+
+#[tauri::command]
+pub fn list_input_devices() -> Result<Vec<audio::InputDeviceInfo>, String> {
+    audio::list_input_devices().map_err(|e| format!("failed to list input devices: {e}"))
+}
+
+/// Opens, reopens or closes the always-armed input to match settings.
+/// Leaves it alone while recording, since the session may be using it.
+pub(crate) fn sync_pre_roll(state: &AppState) -> Result<(), String> {
+    let recording = state
+        .active_recording
         .lock()
-        .map_err(|_| "recordings mutex poisoned".to_string())?;
-    Ok(recs.iter().map(|e| to_item(&state.storage, e)).collect())
+        .map_err(|_| "recording mutex poisoned".to_string())?
+        .is_some();
+    if recording {
+        return Ok(());
+    }
+
+    let (pre_roll, device_id, sample_rate) = {
+        let settings = state
+            .settings
+            .lock()
+            .map_err(|_| "settings mutex poisoned".to_string())?;
+        (
+            settings.pre_roll,
+            settings.preferred_input_device.clone(),
+            settings.sample_rate,
+        )
+    };
+
+    let mut armed = state
+        .armed_input
+        .lock()
+        .map_err(|_| "armed input mutex poisoned".to_string())?;
+    if !pre_roll.enabled {
+        *armed = None;
+        return Ok(());
+    }
+    if let Some(current) = armed.as_ref() {
+        if current.device_id() == device_id.as_deref()
+            && current.seconds() == pre_roll.effective_seconds()
+            && current.sample_rate() == sample_rate
+        {
+            return Ok(());
+        }
+    }
+
+    // Release the old stream before opening the device again.
+    *armed = None;
+    *armed = Some(
+        audio::ArmedInput::arm(device_id.as_deref(), pre_roll.seconds, sample_rate)
+            .map_err(|e| format!("failed to arm pre-roll: {e}"))?,
+    );
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_settings(state: State<'_, AppState>) -> Result<Settings, String> {
+    let settings = state
+        .settings
+        .lock()
+        .map_err(|_| "settings mutex poisoned".to_string())?;
+    Ok(settings.clone())
+}
+
+#[tauri::command]
+pub fn update_settings(state: State<'_, AppState>, settings: Settings) -> Result<Settings, String> {
+    settings.validate()?;
+    let mut guard = state
+        .settings
+        .lock()
+        .map_err(|_| "settings mutex poisoned".to_string())?;
+    state
+        .storage
+        .save_settings(&settings)
+        .map_err(|e| format!("failed to save settings: {e}"))?;
+    *guard = settings;
+    let updated = guard.clone();
+    drop(guard);
+
+    sync_pre_roll(&state)?;
+    Ok(updated)
 }
 
 #[tauri::command]
@@ -120,6 +814,9 @@ pub fn reveal_in_finder(path: String) -> Result<(), String> {
 }
 
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 
 fn open_path_in_file_manager(path: &Path) -> Result<(), std::io::Error> {
     #[cfg(target_os = "macos")]
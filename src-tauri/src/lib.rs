@@ -1,21 +1,33 @@
-mod audio;
+pub mod audio;
+pub mod clock;
 mod commands;
-mod storage;
+pub mod settings;
+pub mod storage;
 
+use std::sync::atomic::AtomicBool;
 use std::sync::Mutex;
 
 use tauri::{AppHandle, Emitter, Manager, Runtime, WindowEvent};
 use tauri_plugin_notification::NotificationExt;
 
-use crate::storage::{RecordingEntry, Storage};
+use crate::settings::Settings;
+use crate::storage::Storage;
 
 const MAIN_WINDOW_LABEL: &str = "main";
 const TRAY_ID: &str = "kiklet-tray";
+const TOGGLE_RECORDING_SHORTCUT: &str = "Command+Shift+Space";
+const TOGGLE_PAUSE_SHORTCUT: &str = "Command+Shift+Alt+Space";
+const ADD_MARKER_SHORTCUT: &str = "Command+Shift+Alt+M";
 
 pub struct AppState {
+    /// Owns the library database; lock it through `storage.library`.
     pub storage: Storage,
-    pub recordings: Mutex<Vec<RecordingEntry>>,
+    pub settings: Mutex<Settings>,
     pub active_recording: Mutex<Option<audio::RecordingSession>>,
+    /// Only set while pre-roll is enabled.
+    pub armed_input: Mutex<Option<audio::ArmedInput>>,
+    /// Set while `convert_recordings_to_flac` works through the library.
+    pub flac_conversion_running: AtomicBool,
 }
 
 fn debug_log(msg: &str) {
@@ -36,6 +48,61 @@ pub fn emit_recording_state(app: &AppHandle, is_recording: bool) -> Result<(), t
     app.emit("recording_state", is_recording)
 }
 
+pub fn emit_input_level(app: &AppHandle, level: audio::InputLevel) -> Result<(), tauri::Error> {
+    app.emit("input_level", level)
+}
+
+/// Shows a tiny meter next to the tray icon while recording; `None` clears it.
+pub fn set_tray_level(
+    app: &AppHandle,
+    level: Option<audio::InputLevel>,
+) -> Result<(), tauri::Error> {
+    const BARS: [&str; 5] = ["▁", "▂", "▄", "▆", "█"];
+    let Some(tray) = app.tray_by_id(TRAY_ID) else {
+        return Ok(());
+    };
+    let title = level.map(|l| {
+        // -60..0 dBFS spread over the bar glyphs.
+        let db = 20.0 * l.peak.max(1e-6).log10();
+        let idx = (((db + 60.0) / 60.0).clamp(0.0, 1.0) * (BARS.len() - 1) as f32).round();
+        BARS[idx as usize].to_string()
+    });
+    tray.set_title(title)
+}
+
+pub fn emit_recording_paused(app: &AppHandle, is_paused: bool) -> Result<(), tauri::Error> {
+    app.emit("recording_paused", is_paused)
+}
+
+pub fn emit_marker_added(app: &AppHandle, marker: &audio::Marker) -> Result<(), tauri::Error> {
+    app.emit("marker_added", marker)
+}
+
+/// Reported whenever a session ends by itself (silence, limits, failures), with why.
+pub fn emit_recording_auto_stopped(
+    app: &AppHandle,
+    reason: &audio::StopReason,
+) -> Result<(), tauri::Error> {
+    app.emit("recording_auto_stopped", reason)
+}
+
+/// Reported when a session ends because of a failure rather than a user or auto stop.
+pub fn emit_recording_error(app: &AppHandle, message: &str) -> Result<(), tauri::Error> {
+    app.emit("recording_error", message)
+}
+
+fn is_recording_paused(app: &AppHandle) -> bool {
+    app.try_state::<AppState>()
+        .and_then(|state| {
+            state
+                .active_recording
+                .lock()
+                .ok()
+                .map(|g| g.as_ref().map(|r| r.is_paused()).unwrap_or(false))
+        })
+        .unwrap_or(false)
+}
+
 fn show_main_window(app: &AppHandle) -> Result<(), tauri::Error> {
     if let Some(w) = app.get_webview_window(MAIN_WINDOW_LABEL) {
         let _ = w.show();
@@ -49,6 +116,7 @@ fn show_main_window(app: &AppHandle) -> Result<(), tauri::Error> {
             .map(|g| g.is_some())
             .unwrap_or(false);
         let _ = emit_recording_state(app, is_recording);
+        let _ = emit_recording_paused(app, is_recording_paused(app));
     }
     Ok(())
 }
@@ -56,6 +124,7 @@ fn show_main_window(app: &AppHandle) -> Result<(), tauri::Error> {
 fn build_tray_menu<R: Runtime>(
     app: &tauri::AppHandle<R>,
     is_recording: bool,
+    is_paused: bool,
 ) -> Result<tauri::menu::Menu<R>, tauri::Error> {
     use tauri::menu::{MenuBuilder, MenuItemBuilder};
 
@@ -65,16 +134,26 @@ fn build_tray_menu<R: Runtime>(
     let stop = MenuItemBuilder::with_id("stop_recording", "Stop Recording")
         .enabled(is_recording)
         .build(app)?;
+    let pause = if is_paused {
+        MenuItemBuilder::with_id("resume_recording", "Resume Recording").build(app)?
+    } else {
+        MenuItemBuilder::with_id("pause_recording", "Pause Recording")
+            .enabled(is_recording)
+            .build(app)?
+    };
+    let marker = MenuItemBuilder::with_id("add_marker", "Add Marker")
+        .enabled(is_recording)
+        .build(app)?;
     let open = MenuItemBuilder::with_id("open_kiklet", "Open Kiklet").build(app)?;
     let quit = MenuItemBuilder::with_id("quit", "Quit").build(app)?;
 
     MenuBuilder::new(app)
-        .items(&[&start, &stop, &open, &quit])
+        .items(&[&start, &pause, &stop, &marker, &open, &quit])
         .build()
 }
 
 pub fn set_tray_recording_state(app: &AppHandle, is_recording: bool) -> Result<(), tauri::Error> {
-    let menu = build_tray_menu(app, is_recording)?;
+    let menu = build_tray_menu(app, is_recording, is_recording && is_recording_paused(app))?;
     if let Some(tray) = app.tray_by_id(TRAY_ID) {
         tray.set_menu(Some(menu))?;
     }
@@ -84,7 +163,7 @@ pub fn set_tray_recording_state(app: &AppHandle, is_recording: bool) -> Result<(
 fn setup_tray(app: &AppHandle) -> Result<(), Box<dyn std::error::Error>> {
     use tauri::tray::{TrayIconBuilder, TrayIconEvent};
 
-    let menu = build_tray_menu(app, false)?;
+    let menu = build_tray_menu(app, false, false)?;
     let icon = app
         .default_window_icon()
         .cloned()
@@ -98,11 +177,20 @@ fn setup_tray(app: &AppHandle) -> Result<(), Box<dyn std::error::Error>> {
             let handle = app.app_handle();
             match id {
                 "start_recording" => {
-                    let _ = crate::commands::start_recording(handle.clone(), handle.state());
+                    let _ = crate::commands::start_recording(handle.clone(), handle.state(), None);
                 }
                 "stop_recording" => {
                     let _ = crate::commands::stop_recording(handle.clone(), handle.state());
                 }
+                "pause_recording" => {
+                    let _ = crate::commands::pause_recording(handle.clone(), handle.state());
+                }
+                "resume_recording" => {
+                    let _ = crate::commands::resume_recording(handle.clone(), handle.state());
+                }
+                "add_marker" => {
+                    let _ = crate::commands::add_marker(handle.clone(), handle.state(), None);
+                }
                 "open_kiklet" => {
                     let _ = show_main_window(&handle);
                 }
@@ -141,8 +229,9 @@ fn setup_hotkey(app: &AppHandle) -> Result<(), Box<dyn std::error::Error>> {
     use tauri_plugin_global_shortcut::GlobalShortcutExt;
     use tauri_plugin_global_shortcut::ShortcutState;
 
-    app.global_shortcut()
-        .on_shortcut("Command+Shift+Space", |app_handle, _shortcut, event| {
+    app.global_shortcut().on_shortcut(
+        TOGGLE_RECORDING_SHORTCUT,
+        |app_handle, _shortcut, event| {
             if event.state != ShortcutState::Pressed {
                 return;
             }
@@ -158,29 +247,67 @@ fn setup_hotkey(app: &AppHandle) -> Result<(), Box<dyn std::error::Error>> {
             if is_recording {
                 let _ = crate::commands::stop_recording(app_handle.clone(), state);
             } else {
-                let _ = crate::commands::start_recording(app_handle.clone(), state);
+                let _ = crate::commands::start_recording(app_handle.clone(), state, None);
+            }
+        },
+    )?;
+
+    app.global_shortcut()
+        .on_shortcut(TOGGLE_PAUSE_SHORTCUT, |app_handle, _shortcut, event| {
+            if event.state != ShortcutState::Pressed {
+                return;
+            }
+
+            // Not recording: nothing to pause, and the command reports that on its own.
+            let state = app_handle.state::<AppState>();
+            if is_recording_paused(app_handle) {
+                let _ = crate::commands::resume_recording(app_handle.clone(), state);
+            } else {
+                let _ = crate::commands::pause_recording(app_handle.clone(), state);
             }
         })?;
 
+    app.global_shortcut()
+        .on_shortcut(ADD_MARKER_SHORTCUT, |app_handle, _shortcut, event| {
+            if event.state != ShortcutState::Pressed {
+                return;
+            }
+
+            // Not recording: the command reports that on its own.
+            let state = app_handle.state::<AppState>();
+            let _ = crate::commands::add_marker(app_handle.clone(), state, None);
+        })?;
+
     Ok(())
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    clock::init();
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_notification::init())
         .plugin(tauri_plugin_global_shortcut::Builder::new().build())
         .setup(|app| {
             let storage = Storage::new(app.handle())?;
-            let recordings = storage.load_or_rebuild_index()?;
+            storage.load_or_rebuild_index()?;
+            let settings = storage.load_settings();
 
             app.manage(AppState {
                 storage,
-                recordings: Mutex::new(recordings),
+                settings: Mutex::new(settings),
                 active_recording: Mutex::new(None),
+                armed_input: Mutex::new(None),
+                flac_conversion_running: AtomicBool::new(false),
             });
 
+            if let Err(err) = crate::commands::sync_pre_roll(&app.state::<AppState>()) {
+                debug_log(&err);
+            }
+            if let Err(err) = crate::commands::purge_expired_trash(&app.state::<AppState>()) {
+                debug_log(&err);
+            }
+
             setup_tray(app.handle())?;
             setup_close_to_hide(app.handle());
             setup_hotkey(app.handle())?;
@@ -191,7 +318,22 @@ pub fn run() {
         .invoke_handler(tauri::generate_handler![
             commands::start_recording,
             commands::stop_recording,
+            commands::pause_recording,
+            commands::resume_recording,
+            commands::add_marker,
             commands::list_recordings,
+            commands::update_recording_metadata,
+            commands::rename_recording,
+            commands::list_tags,
+            commands::delete_recording,
+            commands::list_trash,
+            commands::restore_recording,
+            commands::empty_trash,
+            commands::convert_recordings_to_flac,
+            commands::list_input_devices,
+            commands::get_input_level,
+            commands::get_settings,
+            commands::update_settings,
             commands::reveal_in_finder,
             commands::open_recordings_folder
         ])
@@ -0,0 +1,77 @@
+use crate::audio::{
+    ChannelMode, OutputFormat, PreRoll, RecordingLimits, RecordingOptions, SampleDepth,
+    SilenceAutoStop,
+};
+use crate::storage::TrashSettings;
+
+/// File rates Kiklet records at, 8 kHz to 192 kHz.
+pub const SUPPORTED_SAMPLE_RATES: [u32; 13] = [
+    8_000, 11_025, 12_000, 16_000, 22_050, 24_000, 32_000, 44_100, 48_000, 88_200, 96_000, 176_400,
+    192_000,
+];
+
+/// User preferences persisted next to the recordings index.
+///
+/// Every field has a default so older or partial `settings.json` files keep loading.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    /// Input device id (see `audio::list_input_devices`); `None` follows the system default.
+    pub preferred_input_device: Option<String>,
+    /// Container for new recordings; existing files keep theirs.
+    pub output_format: OutputFormat,
+    /// Opus bitrate in kbit/s; `None` uses the default (24).
+    pub opus_bitrate_kbps: Option<u32>,
+    /// How device channels map to file channels.
+    pub channel_mode: ChannelMode,
+    /// 16-bit int by default; 24-bit int or 32-bit float keep a pro interface's full range.
+    pub sample_depth: SampleDepth,
+    /// Fixed file rate in Hz (e.g. 16000 or 48000); `None` records at whatever the device runs at.
+    pub sample_rate: Option<u32>,
+    /// Off by default; see `SilenceAutoStop` for the knobs.
+    pub silence_auto_stop: SilenceAutoStop,
+    /// Starts a new file (`..._part2`, `..._part3`, …) every this many minutes; `None` keeps
+    /// one file per recording, up to WAV's 4 GiB limit.
+    pub max_segment_minutes: Option<u32>,
+    /// Maximum length and minimum free disk space; see `RecordingLimits`.
+    pub limits: RecordingLimits,
+    /// Off by default: keeps the microphone open while idle. See `ArmedInput` for the cost.
+    pub pre_roll: PreRoll,
+    /// How long deleted recordings stay restorable; see `TrashSettings`.
+    pub trash: TrashSettings,
+}
+
+impl Settings {
+    /// Rejects values that would only fail once a recording starts.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(rate) = self.sample_rate {
+            if !SUPPORTED_SAMPLE_RATES.contains(&rate) {
+                return Err(format!(
+                    "unsupported sample rate {rate} Hz; use one of 8000, 11025, 12000, 16000, \
+                     22050, 24000, 32000, 44100, 48000, 88200, 96000, 176400 or 192000"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn recording_options(&self) -> RecordingOptions {
+        RecordingOptions {
+            output_format: self.output_format,
+            opus_bitrate_kbps: self.opus_bitrate_kbps.filter(|&k| k > 0),
+            channel_mode: self.channel_mode,
+            sample_depth: self.sample_depth,
+            // A hand-edited settings.json can still hold anything; record at the device rate then.
+            sample_rate: self
+                .sample_rate
+                .filter(|r| SUPPORTED_SAMPLE_RATES.contains(r)),
+            silence_auto_stop: Some(self.silence_auto_stop).filter(|s| s.enabled),
+            max_segment_sec: self
+                .max_segment_minutes
+                .filter(|&m| m > 0)
+                .map(|m| m.saturating_mul(60)),
+            limits: self.limits,
+            on_event: None,
+        }
+    }
+}
@@ -0,0 +1,626 @@
+//! The recordings index, kept in an SQLite database (`library.sqlite3`).
+//!
+//! Each recording is one row, so stopping, editing or deleting one writes only that
+//! row. The full entry is stored as JSON next to the columns queries need, so adding a
+//! field to `RecordingEntry` doesn't take a schema change; adding a column does, and
+//! goes at the end of `MIGRATIONS`.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+
+use super::{debug_log, RecordingEntry, StorageError};
+use crate::clock;
+
+/// Schema steps, oldest first; `PRAGMA user_version` counts how many have run.
+const MIGRATIONS: &[&str] = &[
+    // 1: recordings, their files and tags.
+    "CREATE TABLE recordings (
+        id TEXT PRIMARY KEY NOT NULL,
+        filename TEXT NOT NULL UNIQUE,
+        created_at TEXT NOT NULL,
+        -- Unix seconds of created_at; NULL when it can't be read. Orders the list.
+        created_ts INTEGER,
+        duration_sec REAL NOT NULL,
+        size_bytes INTEGER NOT NULL,
+        title TEXT,
+        notes TEXT,
+        entry TEXT NOT NULL
+    );
+    CREATE INDEX recordings_created ON recordings (created_ts DESC, filename DESC);
+    CREATE TABLE recording_files (
+        filename TEXT PRIMARY KEY NOT NULL,
+        recording_id TEXT NOT NULL REFERENCES recordings (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL
+    );
+    CREATE INDEX recording_files_recording ON recording_files (recording_id);
+    CREATE TABLE recording_tags (
+        recording_id TEXT NOT NULL REFERENCES recordings (id) ON DELETE CASCADE,
+        tag TEXT NOT NULL COLLATE NOCASE,
+        PRIMARY KEY (recording_id, tag)
+    );
+    CREATE INDEX recording_tags_tag ON recording_tags (tag);",
+];
+
+/// Newest first; recordings with an unreadable date go last.
+const ORDER_NEWEST_FIRST: &str = "ORDER BY created_ts IS NULL, created_ts DESC, filename DESC";
+
+/// What `list_recordings` can sort by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SortKey {
+    #[default]
+    CreatedAt,
+    Duration,
+    Size,
+    /// Untitled recordings sort as if their title were empty.
+    Title,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Filters, order and page for listing recordings. Every filter is optional.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RecordingQuery {
+    /// RFC 3339; recordings started at or after this instant.
+    pub from: Option<String>,
+    /// RFC 3339; recordings started before this instant.
+    pub to: Option<String>,
+    pub min_duration_sec: Option<f64>,
+    pub max_duration_sec: Option<f64>,
+    /// Recordings carrying this tag, ignoring case.
+    pub tag: Option<String>,
+    /// Matched anywhere in the title or notes, ignoring case.
+    pub text: Option<String>,
+    pub sort: SortKey,
+    pub order: SortOrder,
+    pub offset: u32,
+    /// Page size; `None` for `DEFAULT_PAGE_SIZE`. Capped at `MAX_PAGE_SIZE`.
+    pub limit: Option<u32>,
+}
+
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+#[derive(Debug)]
+pub struct Library {
+    conn: Mutex<Connection>,
+}
+
+/// Exclusive access to the library. Hold it across a read-modify-write so nothing
+/// else changes the recording in between.
+pub struct LibraryGuard<'a>(MutexGuard<'a, Connection>);
+
+impl Library {
+    /// Opens or creates the database and brings its schema up to date. A file that
+    /// isn't a readable database is moved aside and a fresh one started, to be
+    /// filled by a scan of the recordings folder.
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        let conn = match open_connection(path) {
+            Ok(conn) => conn,
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if matches!(
+                    err.code,
+                    ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt
+                ) =>
+            {
+                let aside = path.with_extension("sqlite3.corrupt");
+                debug_log(&format!(
+                    "library unreadable ({err}), moving it to {}",
+                    aside.display()
+                ));
+                std::fs::rename(path, &aside)?;
+                for suffix in ["-wal", "-shm"] {
+                    let mut sidecar = path.as_os_str().to_owned();
+                    sidecar.push(suffix);
+                    let _ = std::fs::remove_file(PathBuf::from(sidecar));
+                }
+                open_connection(path)?
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn lock(&self) -> Result<LibraryGuard<'_>, StorageError> {
+        self.conn
+            .lock()
+            .map(LibraryGuard)
+            .map_err(|_| StorageError::LockPoisoned)
+    }
+}
+
+fn open_connection(path: &Path) -> Result<Connection, rusqlite::Error> {
+    let mut conn = Connection::open(path)?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        // Written by a newer Kiklet. Its extra columns are ignored; ours are still there.
+        debug_log(&format!(
+            "library schema {version} is newer than this build"
+        ));
+        return Ok(());
+    }
+    for (i, step) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(step)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        debug_log(&format!("library schema migrated to {}", i + 1));
+    }
+    Ok(())
+}
+
+fn write_entry(conn: &Connection, entry: &RecordingEntry) -> Result<(), StorageError> {
+    let json = serde_json::to_string(entry)?;
+    let created_ts = clock::parse_timestamp(&entry.created_at, clock::local_offset())
+        .map(|t| t.unix_timestamp());
+    conn.execute(
+        "INSERT INTO recordings
+            (id, filename, created_at, created_ts, duration_sec, size_bytes, title, notes, entry)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT (id) DO UPDATE SET
+            filename = excluded.filename,
+            created_at = excluded.created_at,
+            created_ts = excluded.created_ts,
+            duration_sec = excluded.duration_sec,
+            size_bytes = excluded.size_bytes,
+            title = excluded.title,
+            notes = excluded.notes,
+            entry = excluded.entry",
+        params![
+            entry.id,
+            entry.filename,
+            entry.created_at,
+            created_ts,
+            entry.duration_sec,
+            entry.size_bytes as i64,
+            entry.title,
+            entry.notes,
+            json,
+        ],
+    )?;
+
+    conn.execute(
+        "DELETE FROM recording_files WHERE recording_id = ?1",
+        [&entry.id],
+    )?;
+    for (position, filename) in entry.filenames().into_iter().enumerate() {
+        conn.execute(
+            "INSERT INTO recording_files (filename, recording_id, position) VALUES (?1, ?2, ?3)",
+            params![filename, entry.id, position as i64],
+        )?;
+    }
+
+    conn.execute(
+        "DELETE FROM recording_tags WHERE recording_id = ?1",
+        [&entry.id],
+    )?;
+    for tag in &entry.tags {
+        conn.execute(
+            "INSERT OR IGNORE INTO recording_tags (recording_id, tag) VALUES (?1, ?2)",
+            params![entry.id, tag],
+        )?;
+    }
+    Ok(())
+}
+
+impl LibraryGuard<'_> {
+    fn entries(
+        &self,
+        sql: &str,
+        args: impl rusqlite::Params,
+    ) -> Result<Vec<RecordingEntry>, StorageError> {
+        let json: Vec<String> = self
+            .0
+            .prepare(sql)?
+            .query_map(args, |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        json.iter().map(|j| Ok(serde_json::from_str(j)?)).collect()
+    }
+
+    pub fn is_empty(&self) -> Result<bool, StorageError> {
+        let any: Option<i64> = self
+            .0
+            .query_row("SELECT 1 FROM recordings LIMIT 1", [], |row| row.get(0))
+            .optional()?;
+        Ok(any.is_none())
+    }
+
+    /// Every recording, newest first.
+    pub fn all(&self) -> Result<Vec<RecordingEntry>, StorageError> {
+        self.entries(
+            &format!("SELECT entry FROM recordings {ORDER_NEWEST_FIRST}"),
+            [],
+        )
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<RecordingEntry>, StorageError> {
+        Ok(self
+            .entries("SELECT entry FROM recordings WHERE id = ?1", [id])?
+            .pop())
+    }
+
+    /// The recording `filename` belongs to, whichever of its files that is.
+    pub fn find_by_file(&self, filename: &str) -> Result<Option<RecordingEntry>, StorageError> {
+        Ok(self
+            .entries(
+                "SELECT r.entry FROM recordings r
+                 JOIN recording_files f ON f.recording_id = r.id
+                 WHERE f.filename = ?1",
+                [filename],
+            )?
+            .pop())
+    }
+
+    /// Adds the recording, or replaces the one with the same id.
+    pub fn save(&self, entry: &RecordingEntry) -> Result<(), StorageError> {
+        let tx = self.0.unchecked_transaction()?;
+        write_entry(&tx, entry)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns whether there was such a recording.
+    pub fn remove(&self, id: &str) -> Result<bool, StorageError> {
+        Ok(self
+            .0
+            .execute("DELETE FROM recordings WHERE id = ?1", [id])?
+            > 0)
+    }
+
+    /// Swaps the whole library for `entries` in one transaction.
+    pub fn replace_all(&self, entries: &[RecordingEntry]) -> Result<(), StorageError> {
+        let tx = self.0.unchecked_transaction()?;
+        tx.execute("DELETE FROM recordings", [])?;
+        for entry in entries {
+            write_entry(&tx, entry)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// One page of the recordings matching `query`, and how many match in total.
+    pub fn query(
+        &self,
+        query: &RecordingQuery,
+    ) -> Result<(Vec<RecordingEntry>, u64), StorageError> {
+        let mut filters: Vec<&str> = Vec::new();
+        let mut args: Vec<rusqlite::types::Value> = Vec::new();
+        let instant = |value: &Option<String>, field: &str| {
+            value
+                .as_deref()
+                .map(|s| {
+                    clock::parse_timestamp(s, clock::local_offset())
+                        .map(|t| t.unix_timestamp())
+                        .ok_or_else(|| StorageError::InvalidQuery(format!("{field}: {s}")))
+                })
+                .transpose()
+        };
+        if let Some(from) = instant(&query.from, "from")? {
+            filters.push("created_ts >= ?");
+            args.push(from.into());
+        }
+        if let Some(to) = instant(&query.to, "to")? {
+            filters.push("created_ts < ?");
+            args.push(to.into());
+        }
+        if let Some(min) = query.min_duration_sec {
+            filters.push("duration_sec >= ?");
+            args.push(min.into());
+        }
+        if let Some(max) = query.max_duration_sec {
+            filters.push("duration_sec <= ?");
+            args.push(max.into());
+        }
+        if let Some(tag) = query
+            .tag
+            .as_deref()
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+        {
+            filters.push(
+                "id IN (SELECT recording_id FROM recording_tags WHERE tag = ? COLLATE NOCASE)",
+            );
+            args.push(tag.to_string().into());
+        }
+        if let Some(text) = query
+            .text
+            .as_deref()
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+        {
+            filters.push("(title LIKE ? ESCAPE '\\' OR notes LIKE ? ESCAPE '\\')");
+            let pattern = like_pattern(text);
+            args.push(pattern.clone().into());
+            args.push(pattern.into());
+        }
+        let filter = if filters.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", filters.join(" AND "))
+        };
+
+        let total: i64 = self.0.query_row(
+            &format!("SELECT COUNT(*) FROM recordings {filter}"),
+            rusqlite::params_from_iter(&args),
+            |row| row.get(0),
+        )?;
+
+        let direction = match query.order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        let order = match query.sort {
+            SortKey::CreatedAt => format!(
+                "ORDER BY created_ts IS NULL, created_ts {direction}, filename {direction}"
+            ),
+            SortKey::Duration => {
+                format!("ORDER BY duration_sec {direction}, created_ts DESC, filename DESC")
+            }
+            SortKey::Size => {
+                format!("ORDER BY size_bytes {direction}, created_ts DESC, filename DESC")
+            }
+            SortKey::Title => format!(
+                "ORDER BY COALESCE(title, '') COLLATE NOCASE {direction}, created_ts DESC, filename DESC"
+            ),
+        };
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        args.push(i64::from(limit).into());
+        args.push(i64::from(query.offset).into());
+        let page = self.entries(
+            &format!("SELECT entry FROM recordings {filter} {order} LIMIT ? OFFSET ?"),
+            rusqlite::params_from_iter(&args),
+        )?;
+        Ok((page, total as u64))
+    }
+
+    /// Every tag in use with how many recordings carry it, alphabetically ignoring case.
+    /// Spellings differing only in case count as one.
+    pub fn tags(&self) -> Result<Vec<(String, usize)>, StorageError> {
+        let tags = self
+            .0
+            .prepare(
+                "SELECT MIN(tag), COUNT(*) FROM recording_tags
+                 GROUP BY tag COLLATE NOCASE ORDER BY tag COLLATE NOCASE",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?
+            .collect::<Result<_, _>>()?;
+        Ok(tags)
+    }
+}
+
+/// `text` as a `LIKE` pattern matching it anywhere, with its own `%` and `_` taken literally.
+fn like_pattern(text: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, created_at: &str, duration_sec: f64, title: Option<&str>) -> RecordingEntry {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "filename": format!("{id}.wav"),
+            "createdAt": created_at,
+            "durationSec": duration_sec,
+            "sizeBytes": (duration_sec * 1000.0) as u64,
+            "title": title,
+        }))
+        .unwrap()
+    }
+
+    /// Four recordings over three days, 10 s to 40 s long.
+    fn library(dir: &Path) -> Library {
+        let library = Library::open(&dir.join("library.sqlite3")).unwrap();
+        let mut a = entry("a", "2024-05-01T09:00:00+02:00", 10.0, Some("Standup"));
+        a.tags = vec!["Work".into()];
+        let mut b = entry("b", "2024-05-02T09:00:00+02:00", 20.0, Some("100% done"));
+        b.notes = Some("Shipped the_release".into());
+        b.tags = vec!["work".into(), "release".into()];
+        let c = entry("c", "2024-05-02T18:00:00+02:00", 30.0, Some("1000 done"));
+        let d = entry("d", "2024-05-03T09:00:00+02:00", 40.0, None);
+        library.lock().unwrap().replace_all(&[a, b, c, d]).unwrap();
+        library
+    }
+
+    fn ids(library: &Library, query: RecordingQuery) -> (Vec<String>, u64) {
+        let (page, total) = library.lock().unwrap().query(&query).unwrap();
+        (page.into_iter().map(|e| e.id).collect(), total)
+    }
+
+    #[test]
+    fn like_pattern_takes_wildcards_literally() {
+        assert_eq!(like_pattern("standup"), "%standup%");
+        assert_eq!(like_pattern("100%"), "%100\\%%");
+        assert_eq!(like_pattern("a_b\\c"), "%a\\_b\\\\c%");
+    }
+
+    #[test]
+    fn the_default_query_lists_newest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = library(dir.path());
+        assert_eq!(
+            ids(&library, RecordingQuery::default()),
+            (vec!["d".into(), "c".into(), "b".into(), "a".into()], 4)
+        );
+    }
+
+    #[test]
+    fn date_filters_compare_instants() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = library(dir.path());
+        // 07:00 UTC is 09:00 at +02:00: `from` includes it, `to` doesn't.
+        let query = RecordingQuery {
+            from: Some("2024-05-02T07:00:00Z".into()),
+            to: Some("2024-05-03T07:00:00Z".into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&library, query), (vec!["c".into(), "b".into()], 2));
+
+        let bad = RecordingQuery {
+            from: Some("last week".into()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            library.lock().unwrap().query(&bad),
+            Err(StorageError::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn duration_bounds_are_inclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = library(dir.path());
+        let query = RecordingQuery {
+            min_duration_sec: Some(20.0),
+            max_duration_sec: Some(30.0),
+            ..Default::default()
+        };
+        assert_eq!(ids(&library, query), (vec!["c".into(), "b".into()], 2));
+    }
+
+    #[test]
+    fn tag_and_text_filters_ignore_case() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = library(dir.path());
+        let tag = |tag: &str| RecordingQuery {
+            tag: Some(tag.into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&library, tag("WORK")),
+            (vec!["b".into(), "a".into()], 2)
+        );
+        assert_eq!(ids(&library, tag(" release ")).0, ["b"]);
+        // A blank tag is no filter at all.
+        assert_eq!(ids(&library, tag("  ")).1, 4);
+
+        let text = |text: &str| RecordingQuery {
+            text: Some(text.into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&library, text("STAND")).0, ["a"]);
+        assert_eq!(ids(&library, text("the_release")).0, ["b"]);
+        assert_eq!(ids(&library, text("0%")).0, ["b"]);
+        assert_eq!(ids(&library, text("done")).0, ["c", "b"]);
+        assert!(ids(&library, text("the-release")).0.is_empty());
+
+        let both = RecordingQuery {
+            tag: Some("work".into()),
+            text: Some("done".into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&library, both), (vec!["b".into()], 1));
+    }
+
+    #[test]
+    fn sorts_by_each_key_both_ways() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = library(dir.path());
+        let sorted = |sort, order| {
+            ids(
+                &library,
+                RecordingQuery {
+                    sort,
+                    order,
+                    ..Default::default()
+                },
+            )
+            .0
+        };
+        assert_eq!(
+            sorted(SortKey::CreatedAt, SortOrder::Asc),
+            ["a", "b", "c", "d"]
+        );
+        assert_eq!(
+            sorted(SortKey::Duration, SortOrder::Desc),
+            ["d", "c", "b", "a"]
+        );
+        assert_eq!(sorted(SortKey::Size, SortOrder::Asc), ["a", "b", "c", "d"]);
+        // Untitled sorts as an empty title, so first going up.
+        assert_eq!(sorted(SortKey::Title, SortOrder::Asc), ["d", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn pages_through_the_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = library(dir.path());
+        let page = |offset, limit| {
+            ids(
+                &library,
+                RecordingQuery {
+                    offset,
+                    limit,
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(
+            page(0, Some(3)),
+            (vec!["d".into(), "c".into(), "b".into()], 4)
+        );
+        assert_eq!(page(3, Some(3)), (vec!["a".into()], 4));
+        assert_eq!(page(4, Some(3)), (vec![], 4));
+    }
+
+    #[test]
+    fn limits_are_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = Library::open(&dir.path().join("library.sqlite3")).unwrap();
+        let entries: Vec<RecordingEntry> = (0..MAX_PAGE_SIZE + 5)
+            .map(|i| entry(&format!("r{i}"), "2024-05-01T09:00:00Z", 1.0, None))
+            .collect();
+        library.lock().unwrap().replace_all(&entries).unwrap();
+        let all = |limit| {
+            let query = RecordingQuery {
+                limit,
+                ..Default::default()
+            };
+            library.lock().unwrap().query(&query).unwrap()
+        };
+        let (page, total) = all(Some(u32::MAX));
+        assert_eq!(
+            (page.len(), total),
+            (MAX_PAGE_SIZE as usize, entries.len() as u64)
+        );
+        assert_eq!(all(None).0.len(), DEFAULT_PAGE_SIZE as usize);
+    }
+
+    #[test]
+    fn tags_count_spellings_together() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = library(dir.path());
+        let tags = library.lock().unwrap().tags().unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].0.to_lowercase(), "release");
+        assert_eq!(tags[0].1, 1);
+        assert_eq!(tags[1].0.to_lowercase(), "work");
+        assert_eq!(tags[1].1, 2);
+    }
+}
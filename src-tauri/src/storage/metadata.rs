@@ -0,0 +1,411 @@
+//! Broadcast WAV `bext` and RIFF `LIST/INFO` chunks, so a recording describes
+//! itself to other tools and an index rebuilt from disk keeps what the user typed.
+//! Markers become `cue ` points, labelled in a `LIST/adtl` chunk.
+//!
+//! All of these go after `data`: they can be rewritten by truncating back to the
+//! end of the audio, without moving the audio itself.
+
+use std::fs::OpenOptions;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::StorageError;
+use crate::audio::Marker;
+
+const SOFTWARE: &str = "Kiklet";
+/// Fixed part of a `bext` chunk, before the coding history.
+const BEXT_FIXED_LEN: usize = 602;
+const BEXT_DESCRIPTION_LEN: usize = 256;
+/// Originator reference, after the description and the 32-byte originator.
+const BEXT_REFERENCE_AT: usize = BEXT_DESCRIPTION_LEN + 32;
+const BEXT_REFERENCE_LEN: usize = 32;
+/// Marks the input device in a coding history line, as BWF's free-text `T=` field.
+const CODING_HISTORY_TEXT: &str = "T=";
+
+/// What the metadata chunks carry. Empty fields are left out of the file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EmbeddedMetadata {
+    /// The recording's id, kept in the `bext` originator reference.
+    pub id: Option<String>,
+    pub title: Option<String>,
+    pub notes: Option<String>,
+    /// Kept in `IKEY`, separated by semicolons as RIFF keywords are.
+    pub tags: Vec<String>,
+    /// RFC 3339, as in the index.
+    pub created_at: Option<String>,
+    pub input_device: Option<String>,
+    pub markers: Vec<Marker>,
+}
+
+fn invalid(msg: &str) -> StorageError {
+    StorageError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        msg.to_string(),
+    ))
+}
+
+struct WavLayout {
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    /// First byte after the data chunk, including its pad byte.
+    data_end: u64,
+}
+
+fn read_chunk_header<R: Read>(r: &mut R) -> std::io::Result<([u8; 4], u64)> {
+    let mut header = [0u8; 8];
+    r.read_exact(&mut header)?;
+    let mut id = [0u8; 4];
+    id.copy_from_slice(&header[0..4]);
+    let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+    Ok((id, size))
+}
+
+fn check_riff<R: Read>(r: &mut R) -> Result<(), StorageError> {
+    let mut riff = [0u8; 12];
+    r.read_exact(&mut riff)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(invalid("not a RIFF/WAVE file"));
+    }
+    Ok(())
+}
+
+/// Walks the chunks up to the end of `data`.
+fn read_layout<R: Read + Seek>(r: &mut R, file_len: u64) -> Result<WavLayout, StorageError> {
+    check_riff(r)?;
+    let mut format = None;
+    let mut pos = 12u64;
+    while pos + 8 <= file_len {
+        r.seek(SeekFrom::Start(pos))?;
+        let (id, size) = read_chunk_header(r)?;
+        if &id == b"fmt " {
+            let mut fmt = [0u8; 16];
+            r.read_exact(&mut fmt)?;
+            format = Some((
+                u16::from_le_bytes([fmt[2], fmt[3]]),
+                u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]),
+                u16::from_le_bytes([fmt[14], fmt[15]]),
+            ));
+        } else if &id == b"data" {
+            let (channels, sample_rate, bits_per_sample) =
+                format.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+            let data_end = pos + 8 + size + (size & 1);
+            // A size past the end means the header was never finalized.
+            if pos + 8 + size > file_len {
+                return Err(invalid("data chunk runs past the end of the file"));
+            }
+            return Ok(WavLayout {
+                channels,
+                sample_rate,
+                bits_per_sample,
+                data_end,
+            });
+        }
+        pos += 8 + size + (size & 1);
+    }
+    Err(invalid("no data chunk"))
+}
+
+/// `text` cut to at most `len` bytes, on a character boundary.
+fn truncated(text: &str, len: usize) -> &[u8] {
+    let mut end = text.len().min(len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text.as_bytes()[..end]
+}
+
+fn fixed_field(out: &mut Vec<u8>, text: &str, len: usize) {
+    let bytes = truncated(text, len);
+    out.extend_from_slice(bytes);
+    out.resize(out.len() + len - bytes.len(), 0);
+}
+
+fn bext_chunk(meta: &EmbeddedMetadata, layout: &WavLayout) -> Vec<u8> {
+    let description = meta
+        .title
+        .as_deref()
+        .or(meta.notes.as_deref())
+        .unwrap_or("");
+    let (date, time) = meta
+        .created_at
+        .as_deref()
+        .and_then(|c| c.split_once('T'))
+        .unwrap_or(("", ""));
+    let time = time.get(..8).unwrap_or(time);
+    let seconds_since_midnight = time
+        .split(':')
+        .map(|part| part.parse::<u64>().ok())
+        .try_fold(0u64, |acc, part| Some(acc * 60 + part?))
+        .unwrap_or(0);
+
+    let mut body = Vec::with_capacity(BEXT_FIXED_LEN + 64);
+    fixed_field(&mut body, description, BEXT_DESCRIPTION_LEN);
+    fixed_field(&mut body, SOFTWARE, 32);
+    fixed_field(
+        &mut body,
+        meta.id.as_deref().unwrap_or(""),
+        BEXT_REFERENCE_LEN,
+    );
+    fixed_field(&mut body, date, 10);
+    fixed_field(&mut body, time, 8);
+    let time_reference = seconds_since_midnight * layout.sample_rate as u64;
+    body.extend_from_slice(&time_reference.to_le_bytes());
+    // Version 1: UMID and loudness left unset.
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.resize(BEXT_FIXED_LEN, 0);
+
+    let mode = match layout.channels {
+        1 => ",M=mono",
+        2 => ",M=stereo",
+        _ => "",
+    };
+    let mut history = format!(
+        "A=PCM,F={},W={}{mode}",
+        layout.sample_rate, layout.bits_per_sample
+    );
+    if let Some(device) = &meta.input_device {
+        // Last on the line, so commas in a device name can't end it early.
+        history.push(',');
+        history.push_str(CODING_HISTORY_TEXT);
+        history.push_str(device);
+    }
+    history.push_str("\r\n");
+    body.extend_from_slice(history.as_bytes());
+    body
+}
+
+fn info_chunk(meta: &EmbeddedMetadata) -> Vec<u8> {
+    let keywords = meta.tags.join("; ");
+    let fields = [
+        (b"INAM", meta.title.as_deref()),
+        (b"ICMT", meta.notes.as_deref()),
+        (b"IKEY", Some(keywords.as_str())),
+        (b"ICRD", meta.created_at.as_deref()),
+        (b"ISFT", Some(SOFTWARE)),
+    ];
+    let mut body = b"INFO".to_vec();
+    for (id, value) in fields {
+        let Some(value) = value.filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        // Values are NUL-terminated and the size counts the terminator.
+        let len = value.len() + 1;
+        body.extend_from_slice(id);
+        body.extend_from_slice(&(len as u32).to_le_bytes());
+        body.extend_from_slice(value.as_bytes());
+        body.push(0);
+        if len & 1 == 1 {
+            body.push(0);
+        }
+    }
+    body
+}
+
+fn cue_chunk(markers: &[Marker]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + markers.len() * 24);
+    body.extend_from_slice(&(markers.len() as u32).to_le_bytes());
+    for (i, marker) in markers.iter().enumerate() {
+        let frame = marker.frame.min(u32::MAX as u64) as u32;
+        // Cue point ids start at 1; position, then where in `data` the point sits.
+        body.extend_from_slice(&(i as u32 + 1).to_le_bytes());
+        body.extend_from_slice(&frame.to_le_bytes());
+        body.extend_from_slice(b"data");
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&frame.to_le_bytes());
+    }
+    body
+}
+
+fn adtl_chunk(markers: &[Marker]) -> Vec<u8> {
+    let mut body = b"adtl".to_vec();
+    for (i, marker) in markers.iter().enumerate() {
+        let Some(label) = marker.label.as_deref().filter(|l| !l.is_empty()) else {
+            continue;
+        };
+        let mut labl = (i as u32 + 1).to_le_bytes().to_vec();
+        labl.extend_from_slice(label.as_bytes());
+        labl.push(0);
+        push_chunk(&mut body, b"labl", &labl);
+    }
+    body
+}
+
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() & 1 == 1 {
+        out.push(0);
+    }
+}
+
+/// Replaces whatever follows the audio with fresh `bext`, `LIST/INFO` and, when
+/// there are markers, `cue ` and `LIST/adtl` chunks. The WAV must be finalized.
+pub fn write_wav(path: &Path, meta: &EmbeddedMetadata) -> Result<(), StorageError> {
+    let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = f.metadata()?.len();
+    let layout = read_layout(&mut BufReader::new(&mut f), file_len)?;
+
+    let mut chunks = Vec::new();
+    push_chunk(&mut chunks, b"bext", &bext_chunk(meta, &layout));
+    push_chunk(&mut chunks, b"LIST", &info_chunk(meta));
+    if !meta.markers.is_empty() {
+        push_chunk(&mut chunks, b"cue ", &cue_chunk(&meta.markers));
+        push_chunk(&mut chunks, b"LIST", &adtl_chunk(&meta.markers));
+    }
+    let riff_len = layout.data_end + chunks.len() as u64 - 8;
+    if riff_len > u32::MAX as u64 {
+        return Err(invalid("no room for metadata in a 4 GiB WAV"));
+    }
+
+    f.set_len(layout.data_end)?;
+    f.seek(SeekFrom::Start(layout.data_end))?;
+    {
+        let mut w = BufWriter::new(&mut f);
+        w.write_all(&chunks)?;
+        w.flush()?;
+    }
+    f.seek(SeekFrom::Start(4))?;
+    f.write_all(&(riff_len as u32).to_le_bytes())?;
+    f.sync_all()?;
+    Ok(())
+}
+
+fn text(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let value = String::from_utf8_lossy(&bytes[..end]).trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// The `(id, body)` pairs in a `LIST` body, after its four-byte type.
+fn sub_chunks(body: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 4;
+    std::iter::from_fn(move || {
+        let header = body.get(pos..pos + 8)?;
+        let size = le_u32(&header[4..]) as usize;
+        let value = body.get(pos + 8..pos + 8 + size)?;
+        pos += 8 + size + (size & 1);
+        Some((&header[..4], value))
+    })
+}
+
+fn parse_info(body: &[u8], meta: &mut EmbeddedMetadata) {
+    for (id, value) in sub_chunks(body) {
+        match id {
+            b"INAM" => meta.title = text(value),
+            b"ICMT" => meta.notes = text(value),
+            b"IKEY" => {
+                meta.tags = text(value)
+                    .map(|keywords| super::normalize_tags(keywords.split(';')))
+                    .unwrap_or_default()
+            }
+            b"ICRD" => meta.created_at = text(value),
+            _ => {}
+        }
+    }
+}
+
+/// Markers from cue points, labelled from `adtl` when it names them.
+fn parse_cues(cue: &[u8], adtl: Option<&[u8]>, sample_rate: u32) -> Vec<Marker> {
+    let labels: Vec<(u32, Option<String>)> = adtl
+        .map(|body| {
+            sub_chunks(body)
+                .filter(|(id, value)| *id == b"labl" && value.len() >= 4)
+                .map(|(_, value)| (le_u32(value), text(&value[4..])))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut markers: Vec<Marker> = cue
+        .get(4..)
+        .unwrap_or_default()
+        .chunks_exact(24)
+        .map(|point| {
+            let id = le_u32(point);
+            let frame = le_u32(&point[20..]) as u64;
+            Marker {
+                frame,
+                position_sec: frame as f64 / sample_rate.max(1) as f64,
+                label: labels
+                    .iter()
+                    .find(|(label_id, _)| *label_id == id)
+                    .and_then(|(_, label)| label.clone()),
+            }
+        })
+        .collect();
+    markers.sort_by_key(|m| m.frame);
+    markers
+}
+
+fn parse_bext(body: &[u8], meta: &mut EmbeddedMetadata) {
+    let Some(history) = body.get(BEXT_FIXED_LEN..) else {
+        return;
+    };
+    if meta.title.is_none() {
+        meta.title = text(&body[..BEXT_DESCRIPTION_LEN]);
+    }
+    meta.id = text(&body[BEXT_REFERENCE_AT..BEXT_REFERENCE_AT + BEXT_REFERENCE_LEN]);
+    // The newest line comes last.
+    let history = String::from_utf8_lossy(history);
+    meta.input_device = history
+        .lines()
+        .rev()
+        .filter_map(|line| {
+            line.split_once(CODING_HISTORY_TEXT)
+                .map(|(_, device)| device)
+        })
+        .find_map(|device| text(device.trim_end_matches(['\r', '\n']).as_bytes()));
+}
+
+/// Reads back what `write_wav` stored; chunks from other tools are read too.
+pub fn read_wav(path: &Path) -> Result<EmbeddedMetadata, StorageError> {
+    let f = std::fs::File::open(path)?;
+    let file_len = f.metadata()?.len();
+    let mut r = BufReader::new(f);
+    check_riff(&mut r)?;
+
+    let mut sample_rate = 0;
+    let mut info = None;
+    let mut adtl = None;
+    let mut bext = None;
+    let mut cue = None;
+    let mut pos = 12u64;
+    while pos + 8 <= file_len {
+        r.seek(SeekFrom::Start(pos))?;
+        let (id, size) = read_chunk_header(&mut r)?;
+        pos += 8 + size + (size & 1);
+        if !matches!(&id, b"fmt " | b"LIST" | b"bext" | b"cue ") {
+            continue;
+        }
+        if pos - (size & 1) > file_len {
+            break;
+        }
+        let mut body = vec![0u8; size as usize];
+        r.read_exact(&mut body)?;
+        match &id {
+            b"fmt " if body.len() >= 8 => sample_rate = le_u32(&body[4..]),
+            b"LIST" if body.starts_with(b"INFO") => info = Some(body),
+            b"LIST" if body.starts_with(b"adtl") => adtl = Some(body),
+            b"bext" => bext = Some(body),
+            b"cue " => cue = Some(body),
+            _ => {}
+        }
+    }
+
+    let mut meta = EmbeddedMetadata::default();
+    if let Some(body) = info {
+        parse_info(&body, &mut meta);
+    }
+    if let Some(body) = bext {
+        parse_bext(&body, &mut meta);
+    }
+    if let Some(body) = cue {
+        meta.markers = parse_cues(&body, adtl.as_deref(), sample_rate);
+    }
+    Ok(meta)
+}
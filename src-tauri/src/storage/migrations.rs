@@ -0,0 +1,200 @@
+//! Upgrades a `recordings.json` written by an older version, one step at a time.
+
+use std::collections::HashSet;
+
+use super::{new_recording_id, non_blank, normalize_tags, RecordingEntry, RecordingsIndex};
+use crate::clock;
+
+/// The last `recordings.json` version, which its import into the library expects.
+pub const INDEX_VERSION: u32 = 4;
+
+/// Brings `index` up to `INDEX_VERSION`. Returns whether anything had to change.
+pub fn migrate(index: &mut RecordingsIndex) -> bool {
+    let from = index.version;
+    if from < 2 {
+        v1_to_v2(index);
+    }
+    if from < 3 {
+        v2_to_v3(index);
+    }
+    if from < 4 {
+        v3_to_v4(index);
+    }
+    index.version = index.version.max(INDEX_VERSION);
+    from < INDEX_VERSION
+}
+
+/// Version 1 derived ids from file stems. Those stay as they are, since anything
+/// holding one must keep finding its recording; only blank and duplicate ids (a WAV
+/// and the FLAC converted from it, both picked up by a rescan) get fresh ones.
+fn v1_to_v2(index: &mut RecordingsIndex) {
+    dedupe_ids(&mut index.recordings);
+}
+
+/// Gives blank ids and every repeat of an id after its first a fresh one. Ids read
+/// back from files repeat too, e.g. when a copy of a recording sits in the folder.
+pub fn dedupe_ids(recordings: &mut [RecordingEntry]) {
+    let mut seen = HashSet::new();
+    for entry in recordings {
+        if entry.id.is_empty() || !seen.insert(entry.id.clone()) {
+            entry.id = new_recording_id();
+            seen.insert(entry.id.clone());
+        }
+    }
+}
+
+/// Version 2 and earlier stored local time without an offset. The current local offset
+/// is the best guess there is; it is wrong only for entries recorded across a DST
+/// change or in another timezone, and then by whole hours.
+fn v2_to_v3(index: &mut RecordingsIndex) {
+    let offset = clock::local_offset();
+    for entry in &mut index.recordings {
+        if let Some(t) = clock::parse_timestamp(&entry.created_at, offset) {
+            entry.created_at = clock::format_rfc3339(t);
+        }
+    }
+}
+
+/// Version 4 added tags, which start out empty, and treats title and notes as user
+/// text: blank values from earlier versions (e.g. a padded `bext` description read back
+/// by a rescan) are cleared so they don't hide the timestamp in the list.
+fn v3_to_v4(index: &mut RecordingsIndex) {
+    for entry in &mut index.recordings {
+        entry.title = non_blank(entry.title.take());
+        entry.notes = non_blank(entry.notes.take());
+        entry.tags = normalize_tags(&entry.tags);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(json: &str) -> RecordingsIndex {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn ids(index: &RecordingsIndex) -> Vec<&str> {
+        index.recordings.iter().map(|e| e.id.as_str()).collect()
+    }
+
+    #[test]
+    fn v1_keeps_stem_ids_and_replaces_blank_and_repeated_ones() {
+        let mut index = index(
+            r#"{"version": 1, "recordings": [
+                {"id": "2024-05-01_10-00-00", "filename": "2024-05-01_10-00-00.wav",
+                 "createdAt": "2024-05-01T10:00:00", "durationSec": 1.0, "sizeBytes": 10},
+                {"id": "2024-05-01_10-00-00", "filename": "2024-05-01_10-00-00.flac",
+                 "createdAt": "2024-05-01T10:00:00", "durationSec": 1.0, "sizeBytes": 5},
+                {"id": "", "filename": "2024-05-02_10-00-00.wav",
+                 "createdAt": "2024-05-02T10:00:00", "durationSec": 1.0, "sizeBytes": 10}
+            ]}"#,
+        );
+        assert!(migrate(&mut index));
+        assert_eq!(index.version, INDEX_VERSION);
+        let ids = ids(&index);
+        assert_eq!(ids[0], "2024-05-01_10-00-00");
+        assert!(ulid::Ulid::from_string(ids[1]).is_ok(), "{}", ids[1]);
+        assert!(ulid::Ulid::from_string(ids[2]).is_ok(), "{}", ids[2]);
+        assert_ne!(ids[1], ids[2]);
+        // Entries from before channels were recorded were mono.
+        assert_eq!(index.recordings[0].channels, 1);
+    }
+
+    #[test]
+    fn a_fresh_id_never_repeats_a_later_one() {
+        let mut index = index(
+            r#"{"version": 1, "recordings": [
+                {"id": "a", "filename": "a.wav", "createdAt": "2024-05-01T10:00:00",
+                 "durationSec": 1.0, "sizeBytes": 10},
+                {"id": "a", "filename": "b.wav", "createdAt": "2024-05-01T10:00:00",
+                 "durationSec": 1.0, "sizeBytes": 10},
+                {"id": "c", "filename": "c.wav", "createdAt": "2024-05-01T10:00:00",
+                 "durationSec": 1.0, "sizeBytes": 10}
+            ]}"#,
+        );
+        dedupe_ids(&mut index.recordings);
+        let ids = ids(&index);
+        assert_eq!(ids[0], "a");
+        assert_eq!(ids[2], "c");
+        let unique: HashSet<_> = ids.iter().collect();
+        assert_eq!(unique.len(), 3);
+    }
+
+    #[test]
+    fn v2_timestamps_get_the_local_offset() {
+        let mut index = index(
+            r#"{"version": 2, "recordings": [
+                {"id": "a", "filename": "a.wav", "createdAt": "2024-05-01T10:00:00",
+                 "durationSec": 1.0, "sizeBytes": 10},
+                {"id": "b", "filename": "b.wav", "createdAt": "2024-05-01T10:00:00-07:00",
+                 "durationSec": 1.0, "sizeBytes": 10},
+                {"id": "c", "filename": "c.wav", "createdAt": "yesterday",
+                 "durationSec": 1.0, "sizeBytes": 10}
+            ]}"#,
+        );
+        assert!(migrate(&mut index));
+        let created: Vec<&str> = index
+            .recordings
+            .iter()
+            .map(|e| e.created_at.as_str())
+            .collect();
+        // Same wall clock time, now with whatever offset this machine is at.
+        assert!(
+            created[0].starts_with("2024-05-01T10:00:00"),
+            "{}",
+            created[0]
+        );
+        let parsed = clock::parse_timestamp(created[0], time::UtcOffset::UTC).unwrap();
+        assert_eq!(parsed.offset(), clock::local_offset());
+        assert_eq!(created[1], "2024-05-01T10:00:00-07:00");
+        assert_eq!(created[2], "yesterday");
+    }
+
+    #[test]
+    fn v3_clears_blank_text_and_starts_tags() {
+        let mut index = index(
+            r#"{"version": 3, "recordings": [
+                {"id": "a", "filename": "a.wav", "createdAt": "2024-05-01T10:00:00Z",
+                 "durationSec": 1.0, "sizeBytes": 10, "title": "   ", "notes": " Keep me "},
+                {"id": "b", "filename": "b.wav", "createdAt": "2024-05-01T10:00:00Z",
+                 "durationSec": 1.0, "sizeBytes": 10, "title": "Standup", "notes": ""}
+            ]}"#,
+        );
+        assert!(migrate(&mut index));
+        let [a, b] = &index.recordings[..] else {
+            panic!("{:?}", index.recordings);
+        };
+        assert_eq!(a.title, None);
+        assert_eq!(a.notes.as_deref(), Some("Keep me"));
+        assert!(a.tags.is_empty());
+        assert_eq!(b.title.as_deref(), Some("Standup"));
+        assert_eq!(b.notes, None);
+        // Already RFC 3339, so the v2 step leaves it be.
+        assert_eq!(a.created_at, "2024-05-01T10:00:00Z");
+    }
+
+    #[test]
+    fn v3_tags_are_normalized_if_present() {
+        let mut index = index(
+            r#"{"version": 3, "recordings": [
+                {"id": "a", "filename": "a.wav", "createdAt": "2024-05-01T10:00:00Z",
+                 "durationSec": 1.0, "sizeBytes": 10, "tags": [" work", "Work", "", "a;b"]}
+            ]}"#,
+        );
+        migrate(&mut index);
+        assert_eq!(index.recordings[0].tags, ["work", "a b"]);
+    }
+
+    #[test]
+    fn the_current_version_is_left_alone() {
+        let json = r#"{"version": 4, "recordings": [
+            {"id": "", "filename": "a.wav", "createdAt": "2024-05-01T10:00:00+02:00",
+             "durationSec": 1.0, "sizeBytes": 10, "title": "  "}
+        ]}"#;
+        let mut index = index(json);
+        assert!(!migrate(&mut index));
+        assert_eq!(index.recordings[0].id, "");
+        assert_eq!(index.recordings[0].title.as_deref(), Some("  "));
+    }
+}
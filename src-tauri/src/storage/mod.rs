@@ -4,6 +4,25 @@ use std::path::{Path, PathBuf};
 
 use tauri::Manager;
 
+use crate::audio::{
+    flac, in_progress_marker_path, opus, parse_segment_stem, FinishedRecording, Marker,
+    OutputFormat, PausedSpan,
+};
+use crate::settings::Settings;
+
+mod library;
+mod metadata;
+mod migrations;
+mod recovery;
+mod rename;
+mod trash;
+mod vorbis;
+
+pub use library::{Library, LibraryGuard, RecordingQuery, SortKey, SortOrder};
+pub use metadata::EmbeddedMetadata;
+pub use rename::sanitize_title;
+pub use trash::{TrashSettings, TrashedRecording};
+
 #[derive(Debug, thiserror::Error)]
 pub enum StorageError {
     #[error("tauri path resolver error: {0}")]
@@ -14,35 +33,280 @@ pub enum StorageError {
     Json(#[from] serde_json::Error),
     #[error("wav error: {0}")]
     Wav(#[from] hound::Error),
+    #[error("library error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("library lock poisoned")]
+    LockPoisoned,
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("no recording with id {0}")]
+    NotFound(String),
+    #[error("{0} already exists in the recordings folder")]
+    FileExists(String),
+}
+
+/// One file of a recording that rolled over into several.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Segment {
+    pub filename: String,
+    pub duration_sec: f64,
+    pub size_bytes: u64,
+    /// Length at the recording's sample rate; places markers within the file.
+    pub frames: u64,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RecordingEntry {
+    /// Stable across renames and format conversions. A ULID for recordings made since
+    /// index version 2; older entries keep the file stem they were keyed by.
     pub id: String,
+    /// The recording's file, or its first part when it has `segments`.
     pub filename: String,
+    /// RFC 3339 with the UTC offset it was recorded at.
     pub created_at: String,
+    /// Totals over all segments.
     pub duration_sec: f64,
     pub size_bytes: u64,
+    /// Every file in order, `filename` first; empty for a recording kept in one file.
+    #[serde(default)]
+    pub segments: Vec<Segment>,
+    /// Entries written before multi-channel support were always mono.
+    #[serde(default = "default_channels")]
+    pub channels: u16,
+    /// Set when the file was rescued from a recording that never stopped cleanly.
+    #[serde(default)]
+    pub recovered: bool,
+    /// Start-to-stop time including pauses; unknown for entries rebuilt from disk.
+    #[serde(default)]
+    pub wall_clock_sec: Option<f64>,
+    #[serde(default)]
+    pub paused_spans: Vec<PausedSpan>,
+    /// Rate of the file; missing on entries indexed before it was recorded.
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+    /// Rate the input ran at; differs from `sample_rate` when the recording was resampled.
+    /// Unknown for entries rebuilt from disk.
+    #[serde(default)]
+    pub device_sample_rate: Option<u32>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// Free-form labels, trimmed and unique ignoring case; see `normalize_tags`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Name of the input it was recorded from, when known.
+    #[serde(default)]
+    pub input_device: Option<String>,
+    #[serde(default)]
+    pub markers: Vec<Marker>,
+}
+
+impl RecordingEntry {
+    /// The index entry for a recording that just stopped, under a fresh id.
+    pub fn from_finished(finished: &FinishedRecording) -> Self {
+        // A recording that never rolled over is a single file, not a one-part list.
+        let segments = if finished.segments.len() > 1 {
+            finished
+                .segments
+                .iter()
+                .map(|s| Segment {
+                    filename: s.filename.clone(),
+                    duration_sec: s.duration_sec,
+                    size_bytes: s.size_bytes,
+                    frames: s.frames,
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            id: new_recording_id(),
+            filename: finished.filename.clone(),
+            created_at: finished.created_at.clone(),
+            duration_sec: finished.duration_sec,
+            size_bytes: finished.size_bytes,
+            segments,
+            channels: finished.channels,
+            recovered: false,
+            wall_clock_sec: Some(finished.wall_clock_sec),
+            paused_spans: finished.paused_spans.clone(),
+            sample_rate: Some(finished.sample_rate),
+            device_sample_rate: Some(finished.device_sample_rate),
+            title: None,
+            notes: None,
+            tags: Vec::new(),
+            input_device: finished.input_device.clone(),
+            markers: finished.markers.clone(),
+        }
+    }
+
+    /// Every file of the recording, in order.
+    pub fn filenames(&self) -> Vec<&str> {
+        if self.segments.is_empty() {
+            vec![self.filename.as_str()]
+        } else {
+            self.segments.iter().map(|s| s.filename.as_str()).collect()
+        }
+    }
+
+    /// Points the recording's file `from` at `to`, e.g. after converting it.
+    /// Returns false if `from` is not one of its files.
+    pub fn replace_file(&mut self, from: &str, to: &str, size_bytes: u64) -> bool {
+        if self.segments.is_empty() {
+            if self.filename != from {
+                return false;
+            }
+            self.filename = to.to_string();
+            self.size_bytes = size_bytes;
+            return true;
+        }
+        let Some(segment) = self.segments.iter_mut().find(|s| s.filename == from) else {
+            return false;
+        };
+        segment.filename = to.to_string();
+        segment.size_bytes = size_bytes;
+        if self.filename == from {
+            self.filename = to.to_string();
+        }
+        self.size_bytes = self.segments.iter().map(|s| s.size_bytes).sum();
+        true
+    }
+
+    /// Forgets the files `exists` rejects. Markers inside a forgotten part go with it
+    /// and later ones move back by its length. Returns false when no file is left.
+    fn retain_files(&mut self, exists: impl Fn(&str) -> bool) -> bool {
+        if self.segments.is_empty() {
+            return exists(&self.filename);
+        }
+        let rate = self.sample_rate.unwrap_or(0).max(1) as f64;
+        let last = self.segments.len() - 1;
+        let mut start = 0;
+        let mut removed = 0;
+        let mut kept = Vec::new();
+        let mut markers = Vec::new();
+        for (i, segment) in std::mem::take(&mut self.segments).into_iter().enumerate() {
+            let end = start + segment.frames;
+            let inside = |m: &&Marker| m.frame >= start && (m.frame < end || i == last);
+            if exists(&segment.filename) {
+                markers.extend(self.markers.iter().filter(inside).map(|m| {
+                    let frame = m.frame - removed;
+                    Marker {
+                        frame,
+                        position_sec: frame as f64 / rate,
+                        label: m.label.clone(),
+                    }
+                }));
+                kept.push(segment);
+            } else {
+                removed += segment.frames;
+            }
+            start = end;
+        }
+        if kept.is_empty() {
+            return false;
+        }
+        if removed > 0 {
+            self.markers = markers;
+        }
+        self.filename = kept[0].filename.clone();
+        self.duration_sec = kept.iter().map(|s| s.duration_sec).sum();
+        self.size_bytes = kept.iter().map(|s| s.size_bytes).sum();
+        // A recording down to one file is kept like one that never rolled over.
+        if kept.len() > 1 {
+            self.segments = kept;
+        }
+        true
+    }
+
+    fn as_segment(&self) -> Segment {
+        let rate = self.sample_rate.unwrap_or(0) as f64;
+        Segment {
+            filename: self.filename.clone(),
+            duration_sec: self.duration_sec,
+            size_bytes: self.size_bytes,
+            frames: (self.duration_sec * rate).round() as u64,
+        }
+    }
+
+    /// Adds `part` (a scanned `_partN` file) to this recording, keeping parts in order.
+    fn attach_segment(&mut self, part: &RecordingEntry) {
+        if self.segments.is_empty() {
+            self.segments.push(self.as_segment());
+        }
+        let number =
+            |filename: &str| parse_segment_stem(file_stem(filename)).map_or(1, |(_, part)| part);
+        let at = self
+            .segments
+            .iter()
+            .position(|s| number(&s.filename) > number(&part.filename))
+            .unwrap_or(self.segments.len());
+        // Markers read from the part's own file count from its start.
+        let offset: u64 = self.segments[..at].iter().map(|s| s.frames).sum();
+        let rate = self.sample_rate.unwrap_or(0).max(1) as f64;
+        self.markers.extend(part.markers.iter().map(|m| {
+            let frame = m.frame + offset;
+            Marker {
+                frame,
+                position_sec: frame as f64 / rate,
+                label: m.label.clone(),
+            }
+        }));
+        self.markers.sort_by_key(|m| m.frame);
+        self.segments.insert(at, part.as_segment());
+        self.duration_sec = self.segments.iter().map(|s| s.duration_sec).sum();
+        self.size_bytes = self.segments.iter().map(|s| s.size_bytes).sum();
+        self.recovered |= part.recovered;
+    }
+
+    pub fn embedded_metadata(&self) -> EmbeddedMetadata {
+        EmbeddedMetadata {
+            id: Some(self.id.clone()),
+            title: self.title.clone(),
+            notes: self.notes.clone(),
+            tags: self.tags.clone(),
+            created_at: Some(self.created_at.clone()),
+            input_device: self.input_device.clone(),
+            markers: self.markers.clone(),
+        }
+    }
 }
 
+fn default_channels() -> u16 {
+    1
+}
+
+/// The JSON index versions before the library database used; read once to import it.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct RecordingsIndex {
     pub version: u32,
     pub recordings: Vec<RecordingEntry>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Storage {
     pub app_data_dir: PathBuf,
     pub recordings_dir: PathBuf,
+    /// The pre-database `recordings.json`; renamed once imported.
     pub index_path: PathBuf,
+    pub settings_path: PathBuf,
+    pub library: Library,
 }
 
 fn index_filename() -> &'static str {
     "recordings.json"
 }
 
+fn library_filename() -> &'static str {
+    "library.sqlite3"
+}
+
+fn settings_filename() -> &'static str {
+    "settings.json"
+}
+
 fn recordings_dirname() -> &'static str {
     "recordings"
 }
@@ -55,23 +319,50 @@ fn debug_log(msg: &str) {
 
 impl Storage {
     pub fn new(app: &tauri::AppHandle) -> Result<Self, StorageError> {
-        let app_data_dir = app.path().app_data_dir()?;
+        Self::with_app_data_dir(app.path().app_data_dir()?)
+    }
+
+    /// Storage rooted at an arbitrary directory; no Tauri runtime needed.
+    pub fn with_app_data_dir(app_data_dir: PathBuf) -> Result<Self, StorageError> {
         let recordings_dir = app_data_dir.join(recordings_dirname());
         let index_path = app_data_dir.join(index_filename());
+        let settings_path = app_data_dir.join(settings_filename());
 
         std::fs::create_dir_all(&recordings_dir)?;
+        let library = Library::open(&app_data_dir.join(library_filename()))?;
 
         Ok(Self {
             app_data_dir,
             recordings_dir,
             index_path,
+            settings_path,
+            library,
         })
     }
 
-    pub fn load_or_rebuild_index(&self) -> Result<Vec<RecordingEntry>, StorageError> {
-        if self.index_path.exists() {
+    /// Brings the library in line with the recordings folder at startup. An empty library
+    /// is filled from the old `recordings.json` if there is one, else by scanning the folder.
+    pub fn load_or_rebuild_index(&self) -> Result<(), StorageError> {
+        // Files first: a rename or repair a crash cut short is finished before anything
+        // is read, so a rebuild by scan sees the final names and readable headers.
+        let pending_rename = self.complete_pending_rename();
+        self.remove_stale_temp_files();
+        let interrupted = self.repair_interrupted_recordings()?;
+
+        let library = self.library.lock()?;
+        let mut loaded = None;
+        let mut imported = false;
+        if !library.is_empty()? {
+            loaded = Some(library.all()?);
+        } else if self.index_path.exists() {
             match self.load_index() {
-                Ok(index) => return Ok(index.recordings),
+                Ok(mut index) => {
+                    if migrations::migrate(&mut index) {
+                        debug_log(&format!("migrated index to version {}", index.version));
+                    }
+                    loaded = Some(index.recordings);
+                    imported = true;
+                }
                 Err(err) => {
                     debug_log(&format!(
                         "failed to load index, rebuilding by scan: {err}"
@@ -80,34 +371,106 @@ impl Storage {
             }
         }
 
-        let rebuilt = self.rebuild_by_scanning()?;
-        self.save_index(&rebuilt)?;
-        Ok(rebuilt)
-    }
+        let rebuilt = loaded.is_none();
+        let mut recordings = match loaded {
+            Some(recordings) => recordings,
+            None => self.rebuild_by_scanning()?,
+        };
+        let mut changed = rebuilt || imported;
+        if let Some((id, moves)) = &pending_rename {
+            changed |= self.apply_pending_rename(&mut recordings, id, moves);
+        }
 
-    pub fn save_index(&self, recordings: &[RecordingEntry]) -> Result<(), StorageError> {
-        let index = RecordingsIndex {
-            version: 1,
-            recordings: recordings.to_vec(),
+        // Removed by hand, or moved to the trash just before a crash.
+        let file_count = |recordings: &[RecordingEntry]| -> usize {
+            recordings.iter().map(|e| e.filenames().len()).sum()
         };
+        let before = file_count(&recordings);
+        recordings.retain_mut(|e| e.retain_files(|f| self.recording_path(f).exists()));
+        if file_count(&recordings) != before {
+            debug_log(&format!(
+                "dropped {} indexed files missing from the recordings folder",
+                before - file_count(&recordings)
+            ));
+            changed = true;
+        }
 
-        let tmp = self.index_path.with_extension("json.tmp");
-        {
-            let f = File::create(&tmp)?;
-            let mut w = BufWriter::new(f);
-            serde_json::to_writer_pretty(&mut w, &index)?;
-            w.write_all(b"\n")?;
-            w.flush()?;
+        for filename in &interrupted {
+            match recordings
+                .iter_mut()
+                .find(|e| e.filenames().contains(&filename.as_str()))
+            {
+                // Indexed before the crash; only the marker was left behind.
+                Some(_) if !rebuilt => {}
+                Some(entry) => {
+                    entry.recovered = true;
+                    changed = true;
+                }
+                None => match self.scan_entry(&self.recording_path(filename)) {
+                    Ok(mut entry) => {
+                        entry.recovered = true;
+                        recordings.push(entry);
+                        changed = true;
+                    }
+                    Err(err) => debug_log(&format!("failed to recover {filename}: {err}")),
+                },
+            }
         }
 
-        // Windows rename behavior can be picky; remove first if needed.
-        if self.index_path.exists() {
-            let _ = std::fs::remove_file(&self.index_path);
+        if changed {
+            recordings = group_segments(recordings);
+            // The library keys rows by id: two entries with one id would become one row.
+            migrations::dedupe_ids(&mut recordings);
+            library.replace_all(&recordings)?;
+        }
+        drop(library);
+        if imported {
+            self.retire_json_index();
+        }
+        if pending_rename.is_some() {
+            self.clear_rename_journal();
+        }
+        for filename in &interrupted {
+            self.clear_in_progress(filename);
         }
-        std::fs::rename(&tmp, &self.index_path)?;
         Ok(())
     }
 
+    /// Keeps the imported `recordings.json` as a backup under another name, so it
+    /// isn't imported again should the library ever be empty.
+    fn retire_json_index(&self) {
+        let backup = self.index_path.with_extension("json.imported");
+        if let Err(err) = std::fs::rename(&self.index_path, &backup) {
+            debug_log(&format!("cannot rename imported index: {err}"));
+        }
+    }
+
+    /// Drops the in-progress marker once a recording is safely in the index.
+    pub fn clear_in_progress(&self, filename: &str) {
+        let _ = std::fs::remove_file(in_progress_marker_path(&self.recording_path(filename)));
+    }
+
+    /// Missing or unreadable settings fall back to defaults rather than blocking startup.
+    pub fn load_settings(&self) -> Settings {
+        if !self.settings_path.exists() {
+            return Settings::default();
+        }
+        let loaded = File::open(&self.settings_path)
+            .map_err(StorageError::from)
+            .and_then(|f| Ok(serde_json::from_reader(BufReader::new(f))?));
+        match loaded {
+            Ok(settings) => settings,
+            Err(err) => {
+                debug_log(&format!("failed to load settings, using defaults: {err}"));
+                Settings::default()
+            }
+        }
+    }
+
+    pub fn save_settings(&self, settings: &Settings) -> Result<(), StorageError> {
+        write_json_atomically(&self.settings_path, settings)
+    }
+
     pub fn recordings_folder(&self) -> &Path {
         &self.recordings_dir
     }
@@ -116,74 +479,541 @@ impl Storage {
         self.recordings_dir.join(filename)
     }
 
+    /// Writes the entry's title, notes, date, device and markers into its files, replacing
+    /// what was there, and updates the sizes to match. Each part of a segmented recording
+    /// gets the markers that fall inside it. WAV keeps them in RIFF chunks, FLAC and Opus
+    /// in Vorbis comments.
+    pub fn embed_metadata(&self, entry: &mut RecordingEntry) -> Result<(), StorageError> {
+        let meta = entry.embedded_metadata();
+        if entry.segments.is_empty() {
+            if let Some(size) = self.embed_in_file(&entry.filename, &meta)? {
+                entry.size_bytes = size;
+            }
+            return Ok(());
+        }
+
+        let mut start = 0;
+        for segment in &mut entry.segments {
+            let end = start + segment.frames;
+            let part = EmbeddedMetadata {
+                markers: meta
+                    .markers
+                    .iter()
+                    .filter(|m| (start..end).contains(&m.frame))
+                    .map(|m| Marker {
+                        frame: m.frame - start,
+                        ..m.clone()
+                    })
+                    .collect(),
+                ..meta.clone()
+            };
+            if let Some(size) = self.embed_in_file(&segment.filename, &part)? {
+                segment.size_bytes = size;
+            }
+            start = end;
+        }
+        entry.size_bytes = entry.segments.iter().map(|s| s.size_bytes).sum();
+        Ok(())
+    }
+
+    /// The file's new size, or `None` if its format carries no metadata.
+    fn embed_in_file(
+        &self,
+        filename: &str,
+        meta: &EmbeddedMetadata,
+    ) -> Result<Option<u64>, StorageError> {
+        let path = self.recording_path(filename);
+        match OutputFormat::from_path(&path) {
+            Some(OutputFormat::Wav) => metadata::write_wav(&path, meta)?,
+            Some(OutputFormat::Flac) => vorbis::write_flac(&path, meta)?,
+            Some(OutputFormat::Opus) => vorbis::write_opus(&path, meta)?,
+            None => return Ok(None),
+        }
+        Ok(Some(std::fs::metadata(&path)?.len()))
+    }
+
     fn load_index(&self) -> Result<RecordingsIndex, StorageError> {
         let f = File::open(&self.index_path)?;
         let r = BufReader::new(f);
         Ok(serde_json::from_reader(r)?)
     }
 
+    /// Deletes the `<file>.tmp` copies a FLAC conversion or tag rewrite leaves when it is
+    /// cut short. Neither touches the original until its copy is complete, so nothing is lost.
+    fn remove_stale_temp_files(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.recordings_dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let Some(target) = name.strip_suffix(".tmp") else {
+                continue;
+            };
+            if OutputFormat::from_path(Path::new(target)).is_none() {
+                continue;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => debug_log(&format!("removed leftover {name}")),
+                Err(err) => debug_log(&format!("failed to remove leftover {name}: {err}")),
+            }
+        }
+    }
+
+    /// Finds recordings whose marker outlived the app and repairs their headers.
+    /// Returns the filenames that are readable again.
+    fn repair_interrupted_recordings(&self) -> Result<Vec<String>, StorageError> {
+        let mut out = Vec::new();
+        for entry in std::fs::read_dir(&self.recordings_dir)? {
+            let entry = entry?;
+            let marker = entry.path();
+            let Some(filename) = marker
+                .file_name()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.strip_suffix(".inprogress"))
+            else {
+                continue;
+            };
+
+            let path = self.recording_path(filename);
+            if !path.exists() {
+                let _ = std::fs::remove_file(&marker);
+                continue;
+            }
+            match recovery::repair(&path) {
+                Ok(repaired) => {
+                    debug_log(&format!("recovered {filename} (header repaired: {repaired})"));
+                    out.push(filename.to_string());
+                }
+                Err(err) => {
+                    // Leave the file for manual inspection but stop retrying on every launch.
+                    debug_log(&format!("cannot recover {filename}: {err}"));
+                    let _ = std::fs::remove_file(&marker);
+                }
+            }
+        }
+        Ok(out)
+    }
+
     fn rebuild_by_scanning(&self) -> Result<Vec<RecordingEntry>, StorageError> {
         let mut out = Vec::new();
         for entry in std::fs::read_dir(&self.recordings_dir)? {
             let entry = entry?;
             let path = entry.path();
-            if path.extension().and_then(|e| e.to_str()) != Some("wav") {
+            if OutputFormat::from_path(&path).is_none() {
                 continue;
             }
 
-            let filename = match path.file_name().and_then(|s| s.to_str()) {
-                Some(s) => s.to_string(),
-                None => continue,
-            };
+            // One unreadable file should not cost the user the rest of their history.
+            match self.scan_entry(&path) {
+                Ok(entry) => out.push(entry),
+                Err(err) => debug_log(&format!("skipping {}: {err}", path.display())),
+            }
+        }
 
-            let size_bytes = std::fs::metadata(&path)?.len();
+        Ok(group_segments(out))
+    }
+
+    fn scan_entry(&self, path: &Path) -> Result<RecordingEntry, StorageError> {
+        let filename = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .map(str::to_string)
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "non UTF-8 file name")
+            })?;
+
+        let size_bytes = std::fs::metadata(path)?.len();
+
+        let info = read_audio_info(path)?;
+        let embedded = match OutputFormat::from_path(path) {
+            Some(OutputFormat::Flac) => vorbis::read_flac(path),
+            Some(OutputFormat::Opus) => vorbis::read_opus(path),
+            Some(OutputFormat::Wav) | None => metadata::read_wav(path),
+        };
+        // Metadata is a bonus; the audio is still worth indexing without it.
+        let embedded = embedded.unwrap_or_else(|err| {
+            debug_log(&format!("no metadata in {}: {err}", path.display()));
+            EmbeddedMetadata::default()
+        });
+        let created_at = crate::clock::normalize_timestamp(
+            &embedded
+                .created_at
+                .unwrap_or_else(|| filename_to_created_at(&filename)),
+        );
+
+        Ok(RecordingEntry {
+            // What version 1 keyed it by, so ids of files from then stay the same.
+            id: embedded
+                .id
+                .unwrap_or_else(|| file_stem(&filename).to_string()),
+            filename,
+            created_at,
+            duration_sec: info.duration_sec,
+            size_bytes,
+            segments: Vec::new(),
+            channels: info.channels,
+            recovered: false,
+            wall_clock_sec: None,
+            paused_spans: Vec::new(),
+            sample_rate: Some(info.sample_rate),
+            device_sample_rate: None,
+            title: embedded.title,
+            notes: embedded.notes,
+            tags: embedded.tags,
+            input_device: embedded.input_device,
+            markers: embedded.markers,
+        })
+    }
+}
+
+/// Folds `_partN` files into the recording they continue. Parts whose first
+/// file is gone stay entries of their own.
+fn group_segments(entries: Vec<RecordingEntry>) -> Vec<RecordingEntry> {
+    let (mut parts, mut out): (Vec<_>, Vec<_>) = entries.into_iter().partition(|e| {
+        e.segments.is_empty() && parse_segment_stem(file_stem(&e.filename)).is_some()
+    });
+    // In order, so each part's markers can be offset by the parts before it.
+    parts.sort_by_key(|e| parse_segment_stem(file_stem(&e.filename)).map(|(_, part)| part));
+    for part in parts {
+        let first = parse_segment_stem(file_stem(&part.filename)).map(|(first, _)| first);
+        match out
+            .iter_mut()
+            .find(|e| Some(file_stem(&e.filename)) == first)
+        {
+            Some(entry) => entry.attach_segment(&part),
+            None => out.push(part),
+        }
+    }
+    out
+}
 
-            let (duration_sec, created_at) = self.read_wav_duration_and_created_at(&path, &filename)?;
+struct AudioInfo {
+    duration_sec: f64,
+    channels: u16,
+    sample_rate: u32,
+}
 
-            let id = filename.trim_end_matches(".wav").to_string();
-            out.push(RecordingEntry {
-                id,
-                filename,
-                created_at,
-                duration_sec,
-                size_bytes,
+fn read_audio_info(path: &Path) -> Result<AudioInfo, StorageError> {
+    match OutputFormat::from_path(path) {
+        Some(OutputFormat::Flac) => {
+            let info = flac::read_stream_info(path)?;
+            return Ok(AudioInfo {
+                duration_sec: info.duration_sec(),
+                channels: info.channels,
+                sample_rate: info.sample_rate,
             });
         }
+        Some(OutputFormat::Opus) => {
+            let info = opus::read_stream_info(path)?;
+            return Ok(AudioInfo {
+                duration_sec: info.duration_sec(),
+                channels: info.channels,
+                sample_rate: info.input_sample_rate,
+            });
+        }
+        Some(OutputFormat::Wav) | None => {}
+    }
 
-        // Newest first (lexicographic works with YYYY-MM-DD_HH-mm-ss).
-        out.sort_by(|a, b| b.filename.cmp(&a.filename));
-        Ok(out)
+    let reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let total_samples = reader.len() as f64;
+    let channels = spec.channels.max(1) as f64;
+    let sample_rate = spec.sample_rate.max(1) as f64;
+    let frames = total_samples / channels;
+    Ok(AudioInfo {
+        duration_sec: frames / sample_rate,
+        channels: spec.channels,
+        sample_rate: spec.sample_rate,
+    })
+}
+
+/// `None` for text that is empty once trimmed, so a cleared field stays cleared.
+pub fn non_blank(text: Option<String>) -> Option<String> {
+    text.map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+/// Trims tags, drops empty ones and later duplicates (ignoring case), keeping the
+/// order they came in. Semicolons separate tags in WAV files, so they can't be part of one.
+pub fn normalize_tags<I, S>(tags: I) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut out: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.as_ref().replace(';', " ");
+        let tag = tag.trim();
+        if !tag.is_empty() && !out.iter().any(|t| t.to_lowercase() == tag.to_lowercase()) {
+            out.push(tag.to_string());
+        }
     }
+    out
+}
 
-    fn read_wav_duration_and_created_at(
-        &self,
-        path: &Path,
-        filename: &str,
-    ) -> Result<(f64, String), StorageError> {
-        let created_at = filename_to_created_at(filename);
+/// A fresh, collision-free recording id.
+pub fn new_recording_id() -> String {
+    ulid::Ulid::new().to_string()
+}
+
+fn file_stem(filename: &str) -> &str {
+    filename.rsplit_once('.').map_or(filename, |(stem, _)| stem)
+}
 
-        let reader = hound::WavReader::open(path)?;
-        let spec = reader.spec();
-        let total_samples = reader.len() as f64;
-        let channels = spec.channels.max(1) as f64;
-        let sample_rate = spec.sample_rate.max(1) as f64;
-        let frames = total_samples / channels;
-        let duration_sec = frames / sample_rate;
-        Ok((duration_sec, created_at))
+fn write_json_atomically<T: serde::Serialize>(path: &Path, value: &T) -> Result<(), StorageError> {
+    let tmp = path.with_extension("json.tmp");
+    {
+        let f = File::create(&tmp)?;
+        let mut w = BufWriter::new(f);
+        serde_json::to_writer_pretty(&mut w, value)?;
+        w.write_all(b"\n")?;
+        w.flush()?;
     }
+
+    // Windows rename behavior can be picky; remove first if needed.
+    if path.exists() {
+        let _ = std::fs::remove_file(path);
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(())
 }
 
 fn filename_to_created_at(filename: &str) -> String {
-    let stem = filename.trim_end_matches(".wav");
-    fallback_created_at_from_stem(stem)
+    fallback_created_at_from_stem(file_stem(filename))
 }
 
 fn fallback_created_at_from_stem(stem: &str) -> String {
-    // Expected: YYYY-MM-DD_HH-mm-ss  =>  YYYY-MM-DDTHH:mm:ss
+    // Expected: YYYY-MM-DD_HH-mm-ss  =>  YYYY-MM-DDTHH:mm:ss, possibly followed by a
+    // `-N` suffix that kept same-second recordings apart.
     let Some((d, t)) = stem.split_once('_') else {
         return stem.to_string();
     };
+    let t = t.get(..8).unwrap_or(t);
     format!("{d}T{}", t.replace('-', ":"))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A recording at 1 kHz rolled over into parts of one second each.
+    fn segmented(parts: &[&str], markers: &[u64]) -> RecordingEntry {
+        let segments: Vec<Segment> = parts
+            .iter()
+            .map(|filename| Segment {
+                filename: filename.to_string(),
+                duration_sec: 1.0,
+                size_bytes: 10,
+                frames: 1_000,
+            })
+            .collect();
+        RecordingEntry {
+            id: "id".into(),
+            filename: parts[0].into(),
+            created_at: "2026-01-02T03:04:05Z".into(),
+            duration_sec: parts.len() as f64,
+            size_bytes: 10 * parts.len() as u64,
+            segments,
+            channels: 1,
+            recovered: false,
+            wall_clock_sec: None,
+            paused_spans: Vec::new(),
+            sample_rate: Some(1_000),
+            device_sample_rate: None,
+            title: None,
+            notes: None,
+            tags: Vec::new(),
+            input_device: None,
+            markers: markers
+                .iter()
+                .map(|&frame| Marker {
+                    frame,
+                    position_sec: frame as f64 / 1_000.0,
+                    label: Some(format!("at {frame}")),
+                })
+                .collect(),
+        }
+    }
+
+    fn marker_frames(entry: &RecordingEntry) -> Vec<u64> {
+        entry.markers.iter().map(|m| m.frame).collect()
+    }
+
+    #[test]
+    fn a_missing_middle_part_is_dropped_and_later_markers_move_back() {
+        let mut entry = segmented(
+            &["a.wav", "a_part2.wav", "a_part3.wav"],
+            &[500, 1_500, 2_500, 3_000],
+        );
+        assert!(entry.retain_files(|f| f != "a_part2.wav"));
+        assert_eq!(entry.filenames(), ["a.wav", "a_part3.wav"]);
+        assert_eq!(entry.filename, "a.wav");
+        assert_eq!(entry.duration_sec, 2.0);
+        assert_eq!(entry.size_bytes, 20);
+        assert_eq!(marker_frames(&entry), [500, 1_500, 2_000]);
+        assert_eq!(entry.markers[1].position_sec, 1.5);
+        assert_eq!(entry.markers[1].label.as_deref(), Some("at 2500"));
+    }
+
+    #[test]
+    fn a_missing_first_part_hands_the_filename_on() {
+        let mut entry = segmented(&["a.wav", "a_part2.wav", "a_part3.wav"], &[500, 1_500]);
+        assert!(entry.retain_files(|f| f != "a.wav"));
+        assert_eq!(entry.filename, "a_part2.wav");
+        assert_eq!(entry.filenames(), ["a_part2.wav", "a_part3.wav"]);
+        assert_eq!(marker_frames(&entry), [500]);
+    }
+
+    #[test]
+    fn one_part_left_is_kept_as_a_single_file() {
+        let mut entry = segmented(&["a.wav", "a_part2.wav", "a_part3.wav"], &[2_200]);
+        assert!(entry.retain_files(|f| f == "a_part3.wav"));
+        assert!(entry.segments.is_empty());
+        assert_eq!(entry.filename, "a_part3.wav");
+        assert_eq!(entry.duration_sec, 1.0);
+        assert_eq!(marker_frames(&entry), [200]);
+    }
+
+    #[test]
+    fn nothing_left_drops_the_entry() {
+        let mut entry = segmented(&["a.wav", "a_part2.wav"], &[]);
+        assert!(!entry.retain_files(|_| false));
+        let mut single = segmented(&["b.wav"], &[]);
+        single.segments.clear();
+        assert!(!single.retain_files(|_| false));
+        assert!(single.retain_files(|f| f == "b.wav"));
+    }
+
+    #[test]
+    fn all_parts_present_changes_nothing() {
+        let mut entry = segmented(&["a.wav", "a_part2.wav"], &[999, 1_000, 2_000]);
+        let before = marker_frames(&entry);
+        assert!(entry.retain_files(|_| true));
+        assert_eq!(entry.segments.len(), 2);
+        assert_eq!(marker_frames(&entry), before);
+    }
+
+    /// Records a WAV of `frames` at 8 kHz mono into `storage` as fast as the writer takes it.
+    fn record_wav(
+        storage: &Storage,
+        frames: u32,
+        options: crate::audio::RecordingOptions,
+    ) -> crate::audio::FinishedRecording {
+        let source_path = storage.app_data_dir.join("source.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut w = hound::WavWriter::create(&source_path, spec).unwrap();
+        for i in 0..frames {
+            w.write_sample(((i % 80) as i16 - 40) * 400).unwrap();
+        }
+        w.finalize().unwrap();
+
+        let source = crate::audio::source::WavFileSource::open(&source_path)
+            .unwrap()
+            .realtime(false);
+        let session =
+            crate::audio::RecordingSession::start(&storage.recordings_dir, source, options)
+                .unwrap();
+        // Well under the ring's two seconds, so the whole file is queued long before this.
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        session.stop().unwrap()
+    }
+
+    #[test]
+    fn finished_recording_is_saved_to_the_library() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::with_app_data_dir(dir.path().to_path_buf()).unwrap();
+
+        let finished = record_wav(&storage, 8_000, Default::default());
+        assert_eq!(finished.channels, 1);
+        assert_eq!(finished.sample_rate, 8_000);
+        assert_eq!(finished.overrun_frames, 0);
+        assert_eq!(finished.duration_sec, 1.0);
+        assert_eq!(finished.segments.len(), 1);
+        let path = storage.recording_path(&finished.filename);
+        assert!(in_progress_marker_path(&path).exists());
+
+        let mut entry = RecordingEntry::from_finished(&finished);
+        assert!(entry.segments.is_empty());
+        entry.title = Some("Standup".into());
+        storage.embed_metadata(&mut entry).unwrap();
+        assert_eq!(entry.size_bytes, std::fs::metadata(&path).unwrap().len());
+        storage.library.lock().unwrap().save(&entry).unwrap();
+        for filename in entry.filenames() {
+            storage.clear_in_progress(filename);
+        }
+        assert!(!in_progress_marker_path(&path).exists());
+
+        let library = storage.library.lock().unwrap();
+        let saved = library.get(&entry.id).unwrap().unwrap();
+        assert_eq!(saved.filename, finished.filename);
+        assert_eq!(saved.duration_sec, 1.0);
+        assert_eq!(saved.sample_rate, Some(8_000));
+        assert_eq!(saved.title.as_deref(), Some("Standup"));
+
+        // The embedded tags leave the audio readable.
+        assert_eq!(hound::WavReader::open(&path).unwrap().duration(), 8_000);
+        let meta = metadata::read_wav(&path).unwrap();
+        assert_eq!(meta.id.as_deref(), Some(entry.id.as_str()));
+        assert_eq!(meta.title.as_deref(), Some("Standup"));
+    }
+
+    #[test]
+    fn rolled_over_recording_is_saved_with_its_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::with_app_data_dir(dir.path().to_path_buf()).unwrap();
+        let options = crate::audio::RecordingOptions {
+            max_segment_sec: Some(1),
+            ..Default::default()
+        };
+
+        let finished = record_wav(&storage, 12_000, options);
+        let frames: Vec<u64> = finished.segments.iter().map(|s| s.frames).collect();
+        assert_eq!(frames, vec![8_000, 4_000]);
+        assert_eq!(finished.duration_sec, 1.5);
+
+        let mut entry = RecordingEntry::from_finished(&finished);
+        assert_eq!(entry.filename, entry.segments[0].filename);
+        storage.embed_metadata(&mut entry).unwrap();
+        storage.library.lock().unwrap().save(&entry).unwrap();
+        for filename in entry.filenames() {
+            storage.clear_in_progress(filename);
+            assert!(!in_progress_marker_path(&storage.recording_path(filename)).exists());
+        }
+
+        let second = &entry.segments[1].filename;
+        let library = storage.library.lock().unwrap();
+        let saved = library.find_by_file(second).unwrap().unwrap();
+        assert_eq!(saved.id, entry.id);
+        assert_eq!(saved.segments.len(), 2);
+        let on_disk: u64 = entry
+            .filenames()
+            .iter()
+            .map(|f| std::fs::metadata(storage.recording_path(f)).unwrap().len())
+            .sum();
+        assert_eq!(saved.size_bytes, on_disk);
+    }
+
+    #[test]
+    fn leftover_temp_copies_are_removed_at_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::with_app_data_dir(dir.path().to_path_buf()).unwrap();
+        let file = |name: &str| storage.recordings_dir.join(name);
+        std::fs::write(file("a.flac.tmp"), b"half a flac").unwrap();
+        std::fs::write(file("b.opus.tmp"), b"half an opus").unwrap();
+        std::fs::write(file("notes.txt.tmp"), b"not ours").unwrap();
+
+        storage.load_or_rebuild_index().unwrap();
+        assert!(!file("a.flac.tmp").exists());
+        assert!(!file("b.opus.tmp").exists());
+        assert!(file("notes.txt.tmp").exists());
+    }
+}
+
 
@@ -0,0 +1,279 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::audio::OutputFormat;
+use crate::audio::{flac, opus};
+
+use super::StorageError;
+
+fn invalid(msg: &str) -> StorageError {
+    StorageError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string()))
+}
+
+/// Makes a recording whose writer never finalized readable again, whatever its format.
+pub fn repair(path: &Path) -> Result<bool, StorageError> {
+    match OutputFormat::from_path(path) {
+        Some(OutputFormat::Flac) => repair_flac(path),
+        Some(OutputFormat::Opus) => repair_ogg(path),
+        Some(OutputFormat::Wav) | None => repair_wav(path),
+    }
+}
+
+/// Makes a WAV whose writer never finalized readable again.
+///
+/// The header sizes are rewritten from the actual file length and any trailing
+/// partial frame is cut off, so everything that reached the disk is kept.
+/// Returns whether the file needed changes.
+pub fn repair_wav(path: &Path) -> Result<bool, StorageError> {
+    let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = f.metadata()?.len();
+
+    let mut riff = [0u8; 12];
+    f.read_exact(&mut riff)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(invalid("not a RIFF/WAVE file"));
+    }
+
+    let mut block_align: Option<u64> = None;
+    let mut pos = 12u64;
+    loop {
+        if pos + 8 > file_len {
+            return Err(invalid("no data chunk"));
+        }
+        f.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 8];
+        f.read_exact(&mut header)?;
+        let id = &header[0..4];
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+
+        if id == b"fmt " {
+            let mut fmt = [0u8; 14];
+            f.read_exact(&mut fmt)?;
+            block_align = Some(u16::from_le_bytes([fmt[12], fmt[13]]).max(1) as u64);
+        } else if id == b"data" {
+            let block_align = block_align.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+            let data_start = pos + 8;
+            // Finalized, possibly with metadata chunks after the audio.
+            let riff_size = u32::from_le_bytes([riff[4], riff[5], riff[6], riff[7]]) as u64;
+            if riff_size == file_len - 8
+                && size.is_multiple_of(block_align)
+                && data_start + size <= file_len
+            {
+                return Ok(false);
+            }
+            let available = file_len - data_start;
+            let data_len = (available - available % block_align).min(u32::MAX as u64);
+            let riff_len = data_start + data_len - 8;
+
+            if size == data_len && riff_len == file_len - 8 {
+                return Ok(false);
+            }
+
+            f.set_len(data_start + data_len)?;
+            f.seek(SeekFrom::Start(4))?;
+            f.write_all(&(riff_len as u32).to_le_bytes())?;
+            f.seek(SeekFrom::Start(pos + 4))?;
+            f.write_all(&(data_len as u32).to_le_bytes())?;
+            f.sync_all()?;
+            return Ok(true);
+        }
+
+        // Chunks are word aligned.
+        pos += 8 + size + (size & 1);
+    }
+}
+
+/// Sequential reads with a little lookahead, without holding the whole file.
+struct Window {
+    file: File,
+    buf: Vec<u8>,
+    /// File offset of `buf[0]`.
+    start: u64,
+    eof: bool,
+}
+
+impl Window {
+    const CHUNK: usize = 64 * 1024;
+
+    /// Buffered bytes from `at` on, at least `len` of them unless the file ends first.
+    /// `at` never moves backwards.
+    fn peek(&mut self, at: u64, len: usize) -> std::io::Result<&[u8]> {
+        let consumed = (at - self.start) as usize;
+        if consumed >= Self::CHUNK {
+            self.buf.drain(..consumed);
+            self.start = at;
+        }
+        let offset = (at - self.start) as usize;
+        while self.buf.len() < offset + len && !self.eof {
+            let old = self.buf.len();
+            self.buf.resize(old + Self::CHUNK, 0);
+            let n = self.file.read(&mut self.buf[old..])?;
+            self.buf.truncate(old + n);
+            self.eof = n == 0;
+        }
+        Ok(&self.buf[offset.min(self.buf.len())..])
+    }
+}
+
+/// Cuts a FLAC whose writer never finalized back to its last complete frame and
+/// records the real length in STREAMINFO.
+///
+/// Frames are found by walking from the first one: a frame ends where its CRC-16
+/// checks out and the next frame's header (with the next frame number) begins.
+/// Returns whether the file needed changes.
+pub fn repair_flac(path: &Path) -> Result<bool, StorageError> {
+    let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = f.metadata()?.len();
+    let info = flac::read_stream_info(path)?;
+    let first_frame = flac::first_frame_offset(&mut f)?;
+
+    let mut window = Window {
+        file: f.try_clone()?,
+        buf: Vec::new(),
+        start: first_frame,
+        eof: false,
+    };
+    window.file.seek(SeekFrom::Start(first_frame))?;
+
+    let mut good_end = first_frame;
+    let mut total_samples = 0u64;
+    let mut pos = first_frame;
+    'frames: loop {
+        let head = window.peek(pos, flac::MAX_FRAME_HEADER_LEN)?;
+        let Some(frame) = flac::parse_frame_header(head) else {
+            break;
+        };
+        let mut crc = 0u16;
+        let mut at = pos;
+        loop {
+            let bytes = window.peek(at, flac::MAX_FRAME_HEADER_LEN)?;
+            let past_header = at > pos + frame.len as u64;
+            if bytes.is_empty() {
+                if crc == 0 && past_header {
+                    good_end = at;
+                    total_samples += frame.block_len;
+                }
+                break 'frames;
+            }
+            if crc == 0 && past_header {
+                let next = flac::parse_frame_header(bytes);
+                if next.is_some_and(|n| n.frame_number == frame.frame_number + 1) {
+                    good_end = at;
+                    total_samples += frame.block_len;
+                    pos = at;
+                    continue 'frames;
+                }
+            }
+            crc = flac::crc16_update(crc, bytes[0]);
+            at += 1;
+        }
+    }
+
+    if good_end == file_len && info.total_samples == total_samples {
+        return Ok(false);
+    }
+    f.set_len(good_end)?;
+    flac::rewrite_total_samples(&mut f, total_samples)?;
+    f.sync_all()?;
+    Ok(true)
+}
+
+/// Cuts an Ogg stream back to its last complete page with a good checksum.
+///
+/// Every page carries its own granule position, so the duration read from the
+/// last page is right again once the torn one is gone. Returns whether the file
+/// needed changes.
+pub fn repair_ogg(path: &Path) -> Result<bool, StorageError> {
+    let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = f.metadata()?.len();
+
+    let mut good_end = 0u64;
+    {
+        let mut reader = BufReader::new(&mut f);
+        while good_end < file_len {
+            match opus::read_page(&mut reader) {
+                Ok(page) => good_end += page.len,
+                Err(_) => break,
+            }
+        }
+    }
+
+    if good_end == file_len {
+        return Ok(false);
+    }
+    if good_end == 0 {
+        return Err(invalid("no complete Ogg page"));
+    }
+    f.set_len(good_end)?;
+    f.sync_all()?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Half a second at 48 kHz mono, with a page break every 100 ms.
+    fn opus_file(dir: &Path) -> (std::path::PathBuf, Vec<u8>) {
+        let mut bytes = Vec::new();
+        opus::write_test_stream(&mut bytes, 24_000, 4_800);
+        let path = dir.join("a.opus");
+        std::fs::write(&path, &bytes).unwrap();
+        (path, bytes)
+    }
+
+    fn page_ends(bytes: &[u8]) -> Vec<u64> {
+        let mut r = bytes;
+        let mut end = 0;
+        let mut ends = Vec::new();
+        while !r.is_empty() {
+            end += opus::read_page(&mut r).unwrap().len;
+            ends.push(end);
+        }
+        ends
+    }
+
+    #[test]
+    fn repair_ogg_leaves_a_whole_stream_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, bytes) = opus_file(dir.path());
+        assert!(!repair_ogg(&path).unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn repair_ogg_cuts_a_torn_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, bytes) = opus_file(dir.path());
+        let ends = page_ends(&bytes);
+        let keep = ends[ends.len() - 2];
+        std::fs::write(&path, &bytes[..keep as usize + 30]).unwrap();
+
+        assert!(repair_ogg(&path).unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), &bytes[..keep as usize]);
+        assert!(opus::read_stream_info(&path).unwrap().duration_sec() > 0.3);
+    }
+
+    #[test]
+    fn repair_ogg_cuts_from_a_corrupt_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut bytes) = opus_file(dir.path());
+        let ends = page_ends(&bytes);
+        // Flip a byte in the body of the fourth page; it and everything after goes.
+        bytes[ends[3] as usize - 1] ^= 0x55;
+        std::fs::write(&path, &bytes).unwrap();
+
+        assert!(repair_ogg(&path).unwrap());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), ends[2]);
+        assert!(!repair_ogg(&path).unwrap());
+    }
+
+    #[test]
+    fn repair_ogg_gives_up_without_a_whole_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, bytes) = opus_file(dir.path());
+        std::fs::write(&path, &bytes[..20]).unwrap();
+        assert!(repair_ogg(&path).is_err());
+    }
+}
@@ -0,0 +1,368 @@
+//! Renaming a recording's files to `<date>_<time> <title>`.
+//!
+//! The planned renames are journaled in `rename.json` before any file moves, and the
+//! journal is removed once the library points at the new names. A launch that finds the
+//! journal finishes the job, so a crash in between never leaves the library pointing at
+//! files that are gone.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use super::{
+    debug_log, file_stem, write_json_atomically, LibraryGuard, RecordingEntry, Storage,
+    StorageError,
+};
+use crate::audio::{filename_format, parse_segment_stem, segment_path};
+use crate::clock;
+
+/// Longest title part of a file name, in characters.
+const MAX_TITLE_CHARS: usize = 60;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct RenameJournal {
+    id: String,
+    /// `(from, to)` file names within the recordings folder.
+    moves: Vec<(String, String)>,
+}
+
+fn journal_filename() -> &'static str {
+    "rename.json"
+}
+
+/// Keeps letters, digits, spaces and dashes; anything else, path separators and dots
+/// included, becomes a space. Underscores go too, so a title can't pass for `_partN`.
+pub fn sanitize_title(title: &str) -> String {
+    let cleaned: String = title
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                ' '
+            }
+        })
+        .collect();
+    let words: Vec<&str> = cleaned.split_whitespace().collect();
+    words
+        .join(" ")
+        .chars()
+        .take(MAX_TITLE_CHARS)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// `<date>_<time>` in the recording's own wall time, followed by the sanitised title if
+/// there is one left. Still starts like a default name, so a rescan recovers the date.
+fn template_stem(entry: &RecordingEntry, title: &str) -> String {
+    let date = clock::parse_timestamp(&entry.created_at, clock::local_offset())
+        .and_then(|t| t.format(filename_format()).ok())
+        .unwrap_or_else(|| file_stem(&entry.filename).to_string());
+    match sanitize_title(title) {
+        t if t.is_empty() => date,
+        t => format!("{date} {t}"),
+    }
+}
+
+/// A plain file name: no separators, no `..`, nothing that leaves the folder.
+fn is_plain_filename(name: &str) -> bool {
+    Path::new(name).file_name().and_then(|n| n.to_str()) == Some(name) && !name.starts_with('.')
+}
+
+/// The new name of each of `entry`'s files if the first one gets `stem`.
+fn planned_moves(entry: &RecordingEntry, stem: &str) -> Vec<(String, String)> {
+    let first_ext = Path::new(&entry.filename)
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let first = Path::new(&format!("{stem}{first_ext}")).to_path_buf();
+    entry
+        .filenames()
+        .into_iter()
+        .map(|from| {
+            let to = match parse_segment_stem(file_stem(from)) {
+                Some((_, part)) => {
+                    let mut to = segment_path(&first, part);
+                    if let Some(ext) = Path::new(from).extension() {
+                        to.set_extension(ext);
+                    }
+                    to.to_string_lossy().to_string()
+                }
+                None => match Path::new(from).extension() {
+                    Some(ext) => format!("{stem}.{}", ext.to_string_lossy()),
+                    None => stem.to_string(),
+                },
+            };
+            (from.to_string(), to)
+        })
+        .collect()
+}
+
+impl Storage {
+    fn rename_journal_path(&self) -> std::path::PathBuf {
+        self.app_data_dir.join(journal_filename())
+    }
+
+    /// Renames the files of the recording with `id` after `title` (the entry's own title
+    /// when `None`). Names taken by anything else in the folder, in any format, get a
+    /// `-2`, `-3`, … suffix. The id stays.
+    pub fn rename_recording(
+        &self,
+        library: &LibraryGuard,
+        id: &str,
+        title: Option<&str>,
+    ) -> Result<RecordingEntry, StorageError> {
+        let mut entry = library
+            .get(id)?
+            .ok_or_else(|| StorageError::NotFound(id.to_string()))?;
+        let base = template_stem(&entry, title.or(entry.title.as_deref()).unwrap_or(""));
+
+        let own: HashSet<&str> = entry.filenames().into_iter().map(file_stem).collect();
+        let mut taken = HashSet::new();
+        for file in std::fs::read_dir(&self.recordings_dir)? {
+            let name = file?.file_name().to_string_lossy().to_string();
+            let stem = file_stem(name.trim_end_matches(".inprogress")).to_string();
+            if !own.contains(stem.as_str()) {
+                // macOS volumes ignore case, so neither may the check.
+                taken.insert(stem.to_lowercase());
+            }
+        }
+
+        let moves = (1u32..)
+            .map(|n| match n {
+                1 => base.clone(),
+                n => format!("{base}-{n}"),
+            })
+            .map(|stem| planned_moves(&entry, &stem))
+            .find(|moves| {
+                moves
+                    .iter()
+                    .all(|(_, to)| !taken.contains(&file_stem(to).to_lowercase()))
+            })
+            .unwrap_or_default();
+        let moves: Vec<_> = moves.into_iter().filter(|(from, to)| from != to).collect();
+        if moves.is_empty() {
+            return Ok(entry);
+        }
+        if let Some((_, to)) = moves.iter().find(|(_, to)| !is_plain_filename(to)) {
+            return Err(StorageError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("refusing to rename to {to}"),
+            )));
+        }
+
+        write_json_atomically(
+            &self.rename_journal_path(),
+            &RenameJournal {
+                id: id.to_string(),
+                moves: moves.clone(),
+            },
+        )?;
+        // Until the library is updated, a failure puts back what already moved.
+        let mut done = Vec::new();
+        for (from, to) in &moves {
+            let to_path = self.recording_path(to);
+            // A change of case only finds the file itself on a case-insensitive volume.
+            let result = if to_path.exists() && !to.eq_ignore_ascii_case(from) {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{to} already exists"),
+                ))
+            } else {
+                std::fs::rename(self.recording_path(from), &to_path)
+            };
+            if let Err(err) = result {
+                for (from, to) in done {
+                    let _ = std::fs::rename(self.recording_path(to), self.recording_path(from));
+                }
+                let _ = std::fs::remove_file(self.rename_journal_path());
+                return Err(err.into());
+            }
+            done.push((from, to));
+        }
+
+        // If the library can't be updated, the journal stays and the next launch
+        // points it at the new names.
+        apply_moves(&mut entry, &moves);
+        library.save(&entry)?;
+        let _ = std::fs::remove_file(self.rename_journal_path());
+        Ok(entry)
+    }
+
+    /// Finishes a rename a crash interrupted: moves files still under their old name and
+    /// returns the journal, to be applied to the index with `apply_pending_rename`.
+    pub(super) fn complete_pending_rename(&self) -> Option<(String, Vec<(String, String)>)> {
+        let path = self.rename_journal_path();
+        if !path.exists() {
+            return None;
+        }
+        let journal: RenameJournal = match File::open(&path)
+            .map_err(StorageError::from)
+            .and_then(|f| Ok(serde_json::from_reader(BufReader::new(f))?))
+        {
+            Ok(journal) => journal,
+            Err(err) => {
+                debug_log(&format!("discarding unreadable rename journal: {err}"));
+                let _ = std::fs::remove_file(&path);
+                return None;
+            }
+        };
+        for (from, to) in &journal.moves {
+            if !is_plain_filename(from) || !is_plain_filename(to) {
+                continue;
+            }
+            let (from_path, to_path) = (self.recording_path(from), self.recording_path(to));
+            if from_path.exists() && !to_path.exists() {
+                if let Err(err) = std::fs::rename(&from_path, &to_path) {
+                    debug_log(&format!("cannot finish renaming {from}: {err}"));
+                }
+            }
+        }
+        Some((journal.id, journal.moves))
+    }
+
+    /// Points the journaled recording at its new files, where they exist. Returns whether
+    /// the index changed; the journal is removed once that is saved.
+    pub(super) fn apply_pending_rename(
+        &self,
+        recordings: &mut [RecordingEntry],
+        id: &str,
+        moves: &[(String, String)],
+    ) -> bool {
+        let Some(entry) = recordings.iter_mut().find(|e| e.id == id) else {
+            return false;
+        };
+        let moved: Vec<_> = moves
+            .iter()
+            .filter(|(_, to)| self.recording_path(to).exists())
+            .cloned()
+            .collect();
+        apply_moves(entry, &moved)
+    }
+
+    pub(super) fn clear_rename_journal(&self) {
+        let _ = std::fs::remove_file(self.rename_journal_path());
+    }
+}
+
+/// Points `entry` at the renamed files, keeping their sizes.
+fn apply_moves(entry: &mut RecordingEntry, moves: &[(String, String)]) -> bool {
+    let mut changed = false;
+    for (from, to) in moves {
+        let size = entry
+            .segments
+            .iter()
+            .find(|s| &s.filename == from)
+            .map_or(entry.size_bytes, |s| s.size_bytes);
+        changed |= entry.replace_file(from, to, size);
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(created_at: &str, files: &[&str]) -> RecordingEntry {
+        let segments: Vec<String> = files
+            .iter()
+            .map(|f| {
+                format!(r#"{{"filename": "{f}", "durationSec": 1.0, "sizeBytes": 1, "frames": 1}}"#)
+            })
+            .collect();
+        let segments = if files.len() > 1 {
+            segments.join(",")
+        } else {
+            String::new()
+        };
+        serde_json::from_str(&format!(
+            r#"{{"id": "x", "filename": "{}", "createdAt": "{created_at}",
+                "durationSec": 1.0, "sizeBytes": 1, "segments": [{segments}]}}"#,
+            files[0]
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn sanitize_keeps_letters_digits_spaces_and_dashes() {
+        assert_eq!(
+            sanitize_title("Team / sync: Q3.notes"),
+            "Team sync Q3 notes"
+        );
+        assert_eq!(sanitize_title("  Réunion   café-1  "), "Réunion café-1");
+        assert_eq!(sanitize_title("../../etc/passwd"), "etc passwd");
+        assert_eq!(sanitize_title("C:\\Users\\me"), "C Users me");
+        assert_eq!(sanitize_title(".hidden"), "hidden");
+        assert_eq!(sanitize_title(" \t\n"), "");
+        assert_eq!(sanitize_title("?!*"), "");
+    }
+
+    #[test]
+    fn sanitize_breaks_up_part_suffixes() {
+        let title = sanitize_title("notes_part2");
+        assert_eq!(title, "notes part2");
+        assert_eq!(
+            parse_segment_stem(&format!("2024-05-01_10-00-00 {title}")),
+            None
+        );
+    }
+
+    #[test]
+    fn sanitize_cuts_long_titles_without_a_trailing_space() {
+        let title = sanitize_title(&format!("{} tail", "a".repeat(MAX_TITLE_CHARS - 1)));
+        assert_eq!(title, "a".repeat(MAX_TITLE_CHARS - 1));
+        let long = sanitize_title(&"é".repeat(MAX_TITLE_CHARS * 2));
+        assert_eq!(long.chars().count(), MAX_TITLE_CHARS);
+    }
+
+    #[test]
+    fn template_stem_uses_the_recordings_own_wall_time() {
+        let e = entry("2024-05-01T10:00:00-07:00", &["2024-05-01_10-00-00.wav"]);
+        assert_eq!(
+            template_stem(&e, "Stand/up"),
+            "2024-05-01_10-00-00 Stand up"
+        );
+        assert_eq!(template_stem(&e, "..."), "2024-05-01_10-00-00");
+        let unreadable = entry("someday", &["old name.wav"]);
+        assert_eq!(template_stem(&unreadable, "New"), "old name New");
+    }
+
+    #[test]
+    fn planned_moves_renames_a_single_file() {
+        let e = entry("2024-05-01T10:00:00Z", &["2024-05-01_10-00-00.flac"]);
+        assert_eq!(
+            planned_moves(&e, "2024-05-01_10-00-00 Standup"),
+            [(
+                "2024-05-01_10-00-00.flac".to_string(),
+                "2024-05-01_10-00-00 Standup.flac".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn planned_moves_keeps_part_numbers_and_each_parts_format() {
+        let e = entry(
+            "2024-05-01T10:00:00Z",
+            &["a.flac", "a_part2.wav", "a_part3.flac"],
+        );
+        let moves = planned_moves(&e, "b Title");
+        let to: Vec<&str> = moves.iter().map(|(_, to)| to.as_str()).collect();
+        assert_eq!(
+            to,
+            ["b Title.flac", "b Title_part2.wav", "b Title_part3.flac"]
+        );
+        let from: Vec<&str> = moves.iter().map(|(from, _)| from.as_str()).collect();
+        assert_eq!(from, e.filenames());
+    }
+
+    #[test]
+    fn only_plain_names_stay_in_the_folder() {
+        assert!(is_plain_filename("2024-05-01_10-00-00 Standup.wav"));
+        assert!(!is_plain_filename("../a.wav"));
+        assert!(!is_plain_filename("sub/a.wav"));
+        assert!(!is_plain_filename(".a.wav"));
+        assert!(!is_plain_filename(""));
+    }
+}
@@ -0,0 +1,262 @@
+//! Deleted recordings, kept in an app-managed folder until restored or purged.
+//!
+//! Moving a recording between the library and the trash touches two indexes. The one
+//! gaining the recording is saved before its files move and the one losing it after,
+//! and each side drops entries whose files aren't where it expects them on load. A
+//! crash at any step therefore leaves the recording listed exactly once.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use time::Duration;
+
+use super::{
+    debug_log, new_recording_id, write_json_atomically, LibraryGuard, RecordingEntry, Storage,
+    StorageError,
+};
+use crate::clock;
+
+const TRASH_INDEX_VERSION: u32 = 1;
+
+/// How long deleted recordings stay restorable.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TrashSettings {
+    /// Days after which a deleted recording is removed for good. 0 keeps it until the
+    /// trash is emptied.
+    pub retention_days: u32,
+}
+
+impl Default for TrashSettings {
+    fn default() -> Self {
+        Self { retention_days: 30 }
+    }
+}
+
+impl TrashSettings {
+    /// When a recording deleted at `deleted_at` will be purged, if ever.
+    pub fn purge_at(&self, deleted_at: &str) -> Option<String> {
+        self.expiry(deleted_at).map(clock::format_rfc3339)
+    }
+
+    fn expiry(&self, deleted_at: &str) -> Option<time::OffsetDateTime> {
+        if self.retention_days == 0 {
+            return None;
+        }
+        clock::parse_timestamp(deleted_at, clock::local_offset())
+            .map(|t| t + Duration::days(self.retention_days as i64))
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashedRecording {
+    /// The index entry as it was when deleted; file names are relative to `folder`.
+    pub entry: RecordingEntry,
+    /// RFC 3339, like `created_at`.
+    pub deleted_at: String,
+    /// Directory under the trash holding the files, so equal names never collide.
+    pub folder: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct TrashIndex {
+    version: u32,
+    items: Vec<TrashedRecording>,
+}
+
+fn trash_dirname() -> &'static str {
+    "trash"
+}
+
+fn trash_index_filename() -> &'static str {
+    "trash.json"
+}
+
+/// The recording id when it is a plain file name component, else a fresh one.
+fn folder_name(id: &str) -> String {
+    let safe = !id.is_empty()
+        && id != "."
+        && id != ".."
+        && !id.contains(['/', '\\'])
+        && Path::new(id).file_name().is_some();
+    if safe {
+        id.to_string()
+    } else {
+        new_recording_id()
+    }
+}
+
+impl Storage {
+    pub fn trash_dir(&self) -> PathBuf {
+        self.app_data_dir.join(trash_dirname())
+    }
+
+    fn trash_index_path(&self) -> PathBuf {
+        self.app_data_dir.join(trash_index_filename())
+    }
+
+    fn trashed_path(&self, item: &TrashedRecording, filename: &str) -> PathBuf {
+        self.trash_dir().join(&item.folder).join(filename)
+    }
+
+    /// Deleted recordings, newest deletion first. Items whose files never made it into
+    /// the trash (a crash mid-delete) are dropped; their recording is still in the library.
+    pub fn load_trash(&self) -> Result<Vec<TrashedRecording>, StorageError> {
+        let path = self.trash_index_path();
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let index: TrashIndex = serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+        let total = index.items.len();
+        let mut items: Vec<_> = index
+            .items
+            .into_iter()
+            .filter(|item| self.trashed_path(item, &item.entry.filename).exists())
+            .collect();
+        if items.len() != total {
+            debug_log(&format!(
+                "dropped {} trash entries without files",
+                total - items.len()
+            ));
+            self.save_trash(&items)?;
+        }
+        items.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at));
+        Ok(items)
+    }
+
+    fn save_trash(&self, items: &[TrashedRecording]) -> Result<(), StorageError> {
+        write_json_atomically(
+            &self.trash_index_path(),
+            &TrashIndex {
+                version: TRASH_INDEX_VERSION,
+                items: items.to_vec(),
+            },
+        )
+    }
+
+    /// Moves the recording with `id` out of the library and its files into the trash.
+    pub fn move_to_trash(
+        &self,
+        library: &LibraryGuard,
+        id: &str,
+    ) -> Result<TrashedRecording, StorageError> {
+        let entry = library
+            .get(id)?
+            .ok_or_else(|| StorageError::NotFound(id.to_string()))?;
+        let mut trash = self.load_trash()?;
+        let mut folder = folder_name(id);
+        if trash.iter().any(|t| t.folder == folder) || self.trash_dir().join(&folder).exists() {
+            folder = new_recording_id();
+        }
+        let item = TrashedRecording {
+            entry,
+            deleted_at: clock::format_rfc3339(clock::now_local()),
+            folder,
+        };
+
+        trash.push(item.clone());
+        self.save_trash(&trash)?;
+
+        std::fs::create_dir_all(self.trash_dir().join(&item.folder))?;
+        let moves: Vec<_> = item
+            .entry
+            .filenames()
+            .into_iter()
+            .map(|f| (self.recording_path(f), self.trashed_path(&item, f)))
+            .collect();
+        move_files(&moves)?;
+
+        if let Err(err) = library.remove(id) {
+            // Still in the library; put the files back to match it.
+            let back: Vec<_> = moves.into_iter().map(|(from, to)| (to, from)).collect();
+            if let Err(undo) = move_files(&back) {
+                debug_log(&format!("cannot undo delete of {id}: {undo}"));
+            }
+            return Err(err);
+        }
+        Ok(item)
+    }
+
+    /// Moves a trashed recording's files back and adds it to the library again. Fails if
+    /// a file of the same name is in the way.
+    pub fn restore_from_trash(
+        &self,
+        library: &LibraryGuard,
+        id: &str,
+    ) -> Result<RecordingEntry, StorageError> {
+        let mut trash = self.load_trash()?;
+        let t = trash
+            .iter()
+            .position(|item| item.entry.id == id)
+            .ok_or_else(|| StorageError::NotFound(id.to_string()))?;
+        let item = trash[t].clone();
+        let filenames = item.entry.filenames();
+        if let Some(taken) = filenames.iter().find(|f| self.recording_path(f).exists()) {
+            return Err(StorageError::FileExists(taken.to_string()));
+        }
+
+        library.save(&item.entry)?;
+
+        let moves: Vec<_> = filenames
+            .iter()
+            .map(|f| (self.trashed_path(&item, f), self.recording_path(f)))
+            .collect();
+        if let Err(err) = move_files(&moves) {
+            if let Err(undo) = library.remove(id) {
+                debug_log(&format!("cannot undo restore of {id}: {undo}"));
+            }
+            return Err(err);
+        }
+
+        trash.remove(t);
+        self.save_trash(&trash)?;
+        let _ = std::fs::remove_dir(self.trash_dir().join(&item.folder));
+        Ok(item.entry)
+    }
+
+    /// Permanently deletes trashed recordings for which `purge` returns true and
+    /// returns how many went. Files go first, so a crash leaves nothing half-listed.
+    pub fn purge_trash(
+        &self,
+        mut purge: impl FnMut(&TrashedRecording) -> bool,
+    ) -> Result<usize, StorageError> {
+        let trash = self.load_trash()?;
+        let (gone, kept): (Vec<_>, Vec<_>) = trash.into_iter().partition(|item| purge(item));
+        if gone.is_empty() {
+            return Ok(0);
+        }
+        for item in &gone {
+            let dir = self.trash_dir().join(&item.folder);
+            if let Err(err) = std::fs::remove_dir_all(&dir) {
+                debug_log(&format!("cannot remove {}: {err}", dir.display()));
+            }
+        }
+        self.save_trash(&kept)?;
+        Ok(gone.len())
+    }
+
+    /// Purges what has been in the trash longer than `settings` allow.
+    pub fn purge_expired_trash(&self, settings: &TrashSettings) -> Result<usize, StorageError> {
+        let now = clock::now_local();
+        self.purge_trash(|item| {
+            settings
+                .expiry(&item.deleted_at)
+                .is_some_and(|at| at <= now)
+        })
+    }
+}
+
+/// Renames every `(from, to)` pair, moving the finished ones back if one fails.
+fn move_files(moves: &[(PathBuf, PathBuf)]) -> Result<(), StorageError> {
+    for (done, (from, to)) in moves.iter().enumerate() {
+        if let Err(err) = std::fs::rename(from, to) {
+            for (from, to) in &moves[..done] {
+                let _ = std::fs::rename(to, from);
+            }
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
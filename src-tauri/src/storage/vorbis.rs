@@ -0,0 +1,549 @@
+//! Vorbis comments, the metadata FLAC and Opus share: a `VORBIS_COMMENT` block in
+//! FLAC, the `OpusTags` header packet in Ogg Opus. They carry what `metadata` puts
+//! in WAV chunks, with markers as chapters (`CHAPTER001=00:01:02.500` plus
+//! `CHAPTER001NAME`) as players and taggers show them.
+//!
+//! Chapter times have millisecond precision, so a marker read back from a file
+//! may sit a few samples from where it was set.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use ogg::reading::PacketReader;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+
+use super::{EmbeddedMetadata, StorageError};
+use crate::audio::{flac, opus, Marker};
+
+const VENDOR: &str = "Kiklet";
+const ID_FIELD: &str = "KIKLET_ID";
+const DEVICE_FIELD: &str = "KIKLET_INPUT_DEVICE";
+const FLAC_PADDING: u8 = 1;
+const FLAC_VORBIS_COMMENT: u8 = 4;
+/// Room left after the comments so later edits fit without moving the audio.
+const FLAC_PADDING_LEN: usize = 8192;
+/// Largest body a FLAC metadata block can have.
+const FLAC_MAX_BLOCK_LEN: usize = (1 << 24) - 1;
+/// Largest packet that fits on one Ogg page.
+const OGG_MAX_PAGE_BODY: usize = 255 * 255 - 1;
+
+fn invalid(msg: &str) -> StorageError {
+    StorageError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        msg.to_string(),
+    ))
+}
+
+/// `HH:MM:SS.mmm`, as the chapter extension writes times.
+fn chapter_time(sec: f64) -> String {
+    let ms = (sec.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn parse_chapter_time(time: &str) -> Option<f64> {
+    let mut sec = 0.0;
+    for part in time.trim().split(':') {
+        if !part.bytes().all(|b| b.is_ascii_digit() || b == b'.') {
+            return None;
+        }
+        sec = sec * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(sec)
+}
+
+fn comments(meta: &EmbeddedMetadata) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    let mut push = |key: &str, value: Option<&str>| {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            out.push((key.to_string(), value.to_string()));
+        }
+    };
+    push("TITLE", meta.title.as_deref());
+    push("DESCRIPTION", meta.notes.as_deref());
+    for tag in &meta.tags {
+        push("KEYWORDS", Some(tag));
+    }
+    push("DATE", meta.created_at.as_deref());
+    push(ID_FIELD, meta.id.as_deref());
+    push(DEVICE_FIELD, meta.input_device.as_deref());
+    for (i, marker) in meta.markers.iter().enumerate() {
+        let key = format!("CHAPTER{:03}", i + 1);
+        push(&key, Some(&chapter_time(marker.position_sec)));
+        push(&format!("{key}NAME"), marker.label.as_deref());
+    }
+    out
+}
+
+/// Vendor string and comments, with the little-endian lengths both formats use.
+fn comment_body(meta: &EmbeddedMetadata) -> Vec<u8> {
+    let comments = comments(meta);
+    let mut body = Vec::new();
+    body.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+    body.extend_from_slice(VENDOR.as_bytes());
+    body.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (key, value) in comments {
+        let comment = format!("{key}={value}");
+        body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        body.extend_from_slice(comment.as_bytes());
+    }
+    body
+}
+
+/// Reads the length-prefixed fields of a comment body in turn.
+struct Fields<'a> {
+    body: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn u32(&mut self) -> Result<u32, StorageError> {
+        let bytes = self
+            .body
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| invalid("truncated Vorbis comment"))?;
+        self.pos += 4;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], StorageError> {
+        let len = self.u32()? as usize;
+        let bytes = self
+            .body
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("truncated Vorbis comment"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+fn parse_comment_body(body: &[u8], sample_rate: u32) -> Result<EmbeddedMetadata, StorageError> {
+    let mut fields = Fields { body, pos: 0 };
+    let _vendor = fields.bytes()?;
+    let count = fields.u32()?;
+
+    let mut meta = EmbeddedMetadata::default();
+    let mut keywords = Vec::new();
+    let mut chapters: Vec<(String, Option<f64>, Option<String>)> = Vec::new();
+    for _ in 0..count {
+        let comment = String::from_utf8_lossy(fields.bytes()?).into_owned();
+        let Some((key, value)) = comment.split_once('=') else {
+            continue;
+        };
+        let key = key.to_ascii_uppercase();
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        match key.as_str() {
+            "TITLE" => meta.title = Some(value.to_string()),
+            "DESCRIPTION" | "COMMENT" if meta.notes.is_none() => {
+                meta.notes = Some(value.to_string())
+            }
+            "KEYWORDS" => keywords.extend(value.split(';').map(str::to_string)),
+            "DATE" => meta.created_at = Some(value.to_string()),
+            ID_FIELD => meta.id = Some(value.to_string()),
+            DEVICE_FIELD => meta.input_device = Some(value.to_string()),
+            _ => {
+                let Some(chapter) = key.strip_prefix("CHAPTER") else {
+                    continue;
+                };
+                let (number, name) = match chapter.strip_suffix("NAME") {
+                    Some(number) => (number, true),
+                    None => (chapter, false),
+                };
+                if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+                    continue;
+                }
+                let at = match chapters.iter().position(|c| c.0 == number) {
+                    Some(at) => at,
+                    None => {
+                        chapters.push((number.to_string(), None, None));
+                        chapters.len() - 1
+                    }
+                };
+                if name {
+                    chapters[at].2 = Some(value.to_string());
+                } else {
+                    chapters[at].1 = parse_chapter_time(value);
+                }
+            }
+        }
+    }
+    meta.tags = super::normalize_tags(keywords);
+    meta.markers = chapters
+        .into_iter()
+        .filter_map(|(_, sec, label)| {
+            let sec = sec?;
+            Some(Marker {
+                frame: (sec * sample_rate as f64).round() as u64,
+                position_sec: sec,
+                label,
+            })
+        })
+        .collect();
+    meta.markers.sort_by_key(|m| m.frame);
+    Ok(meta)
+}
+
+/// A metadata block: its type and where its body sits in the file.
+struct FlacBlock {
+    kind: u8,
+    start: u64,
+    len: usize,
+}
+
+/// The metadata blocks and the offset of the first audio frame.
+fn flac_blocks<R: Read + Seek>(r: &mut R) -> Result<(Vec<FlacBlock>, u64), StorageError> {
+    let mut magic = [0u8; 4];
+    r.seek(SeekFrom::Start(0))?;
+    r.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        return Err(invalid("not a FLAC file"));
+    }
+    let mut blocks = Vec::new();
+    let mut pos = 4u64;
+    loop {
+        let mut header = [0u8; 4];
+        r.seek(SeekFrom::Start(pos))?;
+        r.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        blocks.push(FlacBlock {
+            kind: header[0] & 0x7F,
+            start: pos + 4,
+            len,
+        });
+        pos += 4 + len as u64;
+        if header[0] & 0x80 != 0 {
+            return Ok((blocks, pos));
+        }
+    }
+}
+
+fn push_flac_block(out: &mut Vec<u8>, kind: u8, body: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(body);
+}
+
+/// Replaces the file's comments, keeping its other metadata blocks. The new ones
+/// are written over the old and the padding when they fit; otherwise the audio is
+/// copied into a new file that leaves room for next time.
+pub fn write_flac(path: &Path, meta: &EmbeddedMetadata) -> Result<(), StorageError> {
+    let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+    let (blocks, audio_start) = flac_blocks(&mut BufReader::new(&mut f))?;
+    let comment = comment_body(meta);
+    if comment.len() > FLAC_MAX_BLOCK_LEN {
+        return Err(invalid("metadata too large for a FLAC block"));
+    }
+
+    let mut header = b"fLaC".to_vec();
+    for block in blocks
+        .iter()
+        .filter(|b| b.kind != FLAC_PADDING && b.kind != FLAC_VORBIS_COMMENT)
+    {
+        let mut body = vec![0u8; block.len];
+        f.seek(SeekFrom::Start(block.start))?;
+        f.read_exact(&mut body)?;
+        push_flac_block(&mut header, block.kind, &body);
+    }
+    push_flac_block(&mut header, FLAC_VORBIS_COMMENT, &comment);
+
+    let room = audio_start as usize;
+    if header.len() == room || header.len() + 4 <= room {
+        let padding = room.saturating_sub(header.len() + 4);
+        if header.len() < room {
+            push_flac_block(&mut header, FLAC_PADDING, &vec![0; padding]);
+        }
+        mark_last_flac_block(&mut header);
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(&header)?;
+        f.sync_all()?;
+        return Ok(());
+    }
+
+    push_flac_block(&mut header, FLAC_PADDING, &[0; FLAC_PADDING_LEN]);
+    mark_last_flac_block(&mut header);
+    f.seek(SeekFrom::Start(audio_start))?;
+    replace_with(path, &header, &mut f)
+}
+
+/// Sets the last-block flag on the final block of `header` and clears it elsewhere.
+fn mark_last_flac_block(header: &mut [u8]) {
+    let mut pos = 4;
+    while pos < header.len() {
+        let len = u32::from_be_bytes([0, header[pos + 1], header[pos + 2], header[pos + 3]]);
+        let next = pos + 4 + len as usize;
+        header[pos] &= 0x7F;
+        if next >= header.len() {
+            header[pos] |= 0x80;
+        }
+        pos = next;
+    }
+}
+
+/// Writes `head` and then the rest of `tail` to a new file next to `path`, and
+/// moves it over `path` once it is on disk.
+fn replace_with(path: &Path, head: &[u8], tail: &mut File) -> Result<(), StorageError> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let copied = (|| -> Result<(), StorageError> {
+        let mut out = File::create(&tmp)?;
+        {
+            let mut w = BufWriter::new(&mut out);
+            w.write_all(head)?;
+            std::io::copy(&mut BufReader::new(tail), &mut w)?;
+            w.flush()?;
+        }
+        out.sync_all()?;
+        Ok(())
+    })();
+    if let Err(err) = copied {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err);
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Reads the comments of a FLAC file; one without any reads as empty.
+pub fn read_flac(path: &Path) -> Result<EmbeddedMetadata, StorageError> {
+    let sample_rate = flac::read_stream_info(path)?.sample_rate;
+    let mut r = BufReader::new(File::open(path)?);
+    let (blocks, _) = flac_blocks(&mut r)?;
+    let Some(block) = blocks.iter().find(|b| b.kind == FLAC_VORBIS_COMMENT) else {
+        return Ok(EmbeddedMetadata::default());
+    };
+    let mut body = vec![0u8; block.len];
+    r.seek(SeekFrom::Start(block.start))?;
+    r.read_exact(&mut body)?;
+    parse_comment_body(&body, sample_rate)
+}
+
+/// Replaces the `OpusTags` packet. Both header pages are written afresh and the
+/// audio pages copied after them into a new file, which then replaces the old.
+pub fn write_opus(path: &Path, meta: &EmbeddedMetadata) -> Result<(), StorageError> {
+    let mut f = File::open(path)?;
+    let (head, tags_end) = {
+        let mut r = BufReader::new(&mut f);
+        let head = opus::read_page(&mut r)?;
+        let tags = opus::read_page(&mut r)?;
+        if !head.body.starts_with(b"OpusHead") || !tags.body.starts_with(b"OpusTags") {
+            return Err(invalid("not an Ogg Opus file"));
+        }
+        // A page where the packet doesn't end has no granule position.
+        if tags.granule == u64::MAX {
+            return Err(invalid("OpusTags spans several Ogg pages"));
+        }
+        let tags_end = head.len + tags.len;
+        (head, tags_end)
+    };
+
+    let mut packet = b"OpusTags".to_vec();
+    packet.extend_from_slice(&comment_body(meta));
+    if packet.len() > OGG_MAX_PAGE_BODY {
+        return Err(invalid("metadata too large for an Ogg page"));
+    }
+    let mut header = Vec::new();
+    {
+        let mut pages = PacketWriter::new(&mut header);
+        // Written as the first two pages of the stream, so sequence numbers and
+        // flags come out as they were and the audio pages need no changes.
+        pages.write_packet(
+            head.body.into_boxed_slice(),
+            head.serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        pages.write_packet(
+            packet.into_boxed_slice(),
+            head.serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+    }
+    f.seek(SeekFrom::Start(tags_end))?;
+    replace_with(path, &header, &mut f)
+}
+
+/// Reads the comments from an Ogg Opus file's `OpusTags` packet.
+pub fn read_opus(path: &Path) -> Result<EmbeddedMetadata, StorageError> {
+    let sample_rate = opus::read_stream_info(path)?.input_sample_rate;
+    let mut packets = PacketReader::new(BufReader::new(File::open(path)?));
+    packets.read_packet_expected().map_err(ogg_error)?;
+    let tags = packets.read_packet_expected().map_err(ogg_error)?;
+    let body = tags
+        .data
+        .strip_prefix(b"OpusTags")
+        .ok_or_else(|| invalid("no OpusTags packet"))?;
+    parse_comment_body(body, sample_rate)
+}
+
+fn ogg_error(err: ogg::reading::OggReadError) -> StorageError {
+    match err {
+        ogg::reading::OggReadError::ReadError(err) => StorageError::Io(err),
+        other => invalid(&other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> EmbeddedMetadata {
+        EmbeddedMetadata {
+            id: Some("01J0000000000000000000000A".into()),
+            title: Some("Standup".into()),
+            notes: Some("Ran long = again".into()),
+            tags: vec!["work".into(), "daily".into()],
+            created_at: Some("2026-03-14T09:30:00+01:00".into()),
+            input_device: Some("USB Mic, left".into()),
+            markers: vec![
+                Marker {
+                    frame: 72_000,
+                    position_sec: 1.5,
+                    label: Some("Intro".into()),
+                },
+                Marker {
+                    frame: 240_000,
+                    position_sec: 5.0,
+                    label: None,
+                },
+            ],
+        }
+    }
+
+    fn flac_file(dir: &Path) -> (std::path::PathBuf, Vec<i32>) {
+        let path = dir.join("a.flac");
+        let samples = flac::write_test_file(&path, 48_000);
+        (path, samples)
+    }
+
+    fn decoded(path: &Path) -> Vec<i32> {
+        let mut reader = claxon::FlacReader::open(path).unwrap();
+        reader.samples().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn chapter_times_round_trip() {
+        assert_eq!(chapter_time(0.0), "00:00:00.000");
+        assert_eq!(chapter_time(3723.4567), "01:02:03.457");
+        assert_eq!(parse_chapter_time("01:02:03.457"), Some(3723.457));
+        assert_eq!(parse_chapter_time("02:03.5"), Some(123.5));
+        assert_eq!(parse_chapter_time("later"), None);
+        assert_eq!(parse_chapter_time("-00:01"), None);
+    }
+
+    #[test]
+    fn comment_body_round_trips() {
+        let read = parse_comment_body(&comment_body(&meta()), 48_000).unwrap();
+        assert_eq!(read, meta());
+    }
+
+    #[test]
+    fn comments_from_other_tools_are_read() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&4u32.to_le_bytes());
+        body.extend_from_slice(b"lavf");
+        let comments = [
+            "title=From elsewhere",
+            "comment=Some notes",
+            "KEYWORDS=a; b",
+            "KEYWORDS=A",
+            "CHAPTER002=00:00:02.000",
+            "CHAPTER001NAME=First",
+            "CHAPTER001=00:00:01.000",
+            "no separator",
+            "ARTIST=ignored",
+        ];
+        body.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for c in comments {
+            body.extend_from_slice(&(c.len() as u32).to_le_bytes());
+            body.extend_from_slice(c.as_bytes());
+        }
+        let meta = parse_comment_body(&body, 16_000).unwrap();
+        assert_eq!(meta.title.as_deref(), Some("From elsewhere"));
+        assert_eq!(meta.notes.as_deref(), Some("Some notes"));
+        assert_eq!(meta.tags, ["a", "b"]);
+        assert_eq!(meta.id, None);
+        let markers: Vec<_> = meta
+            .markers
+            .iter()
+            .map(|m| (m.frame, m.label.as_deref()))
+            .collect();
+        assert_eq!(markers, [(16_000, Some("First")), (32_000, None)]);
+    }
+
+    #[test]
+    fn truncated_comments_are_an_error() {
+        let body = comment_body(&meta());
+        assert!(parse_comment_body(&body[..body.len() - 3], 48_000).is_err());
+        assert!(parse_comment_body(&body[..6], 48_000).is_err());
+    }
+
+    #[test]
+    fn flac_comments_round_trip_without_touching_the_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, samples) = flac_file(dir.path());
+        assert_eq!(read_flac(&path).unwrap(), EmbeddedMetadata::default());
+
+        // The first write makes room; the second fits into it.
+        write_flac(&path, &meta()).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(read_flac(&path).unwrap(), meta());
+        let shorter = EmbeddedMetadata {
+            notes: None,
+            markers: Vec::new(),
+            ..meta()
+        };
+        write_flac(&path, &shorter).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(read_flac(&path).unwrap(), shorter);
+
+        assert_eq!(decoded(&path), samples);
+        assert_eq!(flac::read_stream_info(&path).unwrap().total_samples, 48_000);
+        assert!(!dir.path().join("a.flac.tmp").exists());
+    }
+
+    #[test]
+    fn flac_comments_outgrowing_the_padding_move_the_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, samples) = flac_file(dir.path());
+        write_flac(&path, &meta()).unwrap();
+        let long = EmbeddedMetadata {
+            notes: Some("n".repeat(FLAC_PADDING_LEN * 2)),
+            ..meta()
+        };
+        write_flac(&path, &long).unwrap();
+        assert_eq!(read_flac(&path).unwrap(), long);
+        assert_eq!(decoded(&path), samples);
+    }
+
+    #[test]
+    fn opus_comments_round_trip_without_touching_the_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.opus");
+        opus::write_test_stream(File::create(&path).unwrap(), 48_000, 48_000);
+        let before = std::fs::read(&path).unwrap();
+        let info = opus::read_stream_info(&path).unwrap();
+        assert_eq!(read_opus(&path).unwrap(), EmbeddedMetadata::default());
+
+        write_opus(&path, &meta()).unwrap();
+        assert_eq!(read_opus(&path).unwrap(), meta());
+        write_opus(&path, &EmbeddedMetadata::default()).unwrap();
+        assert_eq!(read_opus(&path).unwrap(), EmbeddedMetadata::default());
+
+        // Same headers and audio pages, byte for byte, once the tags are back to none.
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        let after = opus::read_stream_info(&path).unwrap();
+        assert_eq!(after.last_granule, info.last_granule);
+        assert!(!dir.path().join("a.opus.tmp").exists());
+    }
+}
@@ -6,21 +6,99 @@ type RecordingItem = {
   filename: string;
   createdAt: string;
   durationSec: number;
+  wallClockSec: number | null;
   sizeBytes: number;
+  channels: number;
+  sampleRate: number | null;
+  deviceSampleRate: number | null;
+  recovered: boolean;
+  title: string | null;
+  notes: string | null;
+  tags: string[];
+  inputDevice: string | null;
+  markers: Marker[];
   path: string;
+  segments: Segment[];
+};
+
+type Segment = {
+  filename: string;
+  durationSec: number;
+  sizeBytes: number;
+  path: string;
+};
+
+type Marker = {
+  frame: number;
+  positionSec: number;
+  label: string | null;
+};
+
+type RecordingPage = {
+  items: RecordingItem[];
+  total: number;
+};
+
+type TagItem = {
+  tag: string;
+  count: number;
+};
+
+type TrashItem = {
+  id: string;
+  filename: string;
+  createdAt: string;
+  title: string | null;
+  durationSec: number;
+  sizeBytes: number;
+  deletedAt: string;
+  purgeAt: string | null;
+};
+
+type InputDevice = {
+  id: string;
+  name: string;
+  isDefault: boolean;
+};
+
+type InputLevel = {
+  rms: number;
+  peak: number;
+};
+
+type Settings = {
+  preferredInputDevice: string | null;
 };
 
 const els = {
   status: document.getElementById("status") as HTMLDivElement,
   btnStart: document.getElementById("btn-start") as HTMLButtonElement,
+  btnPause: document.getElementById("btn-pause") as HTMLButtonElement,
   btnStop: document.getElementById("btn-stop") as HTMLButtonElement,
   btnFolder: document.getElementById("btn-folder") as HTMLButtonElement,
+  device: document.getElementById("device") as HTMLSelectElement,
+  btnTest: document.getElementById("btn-test") as HTMLButtonElement,
+  meterFill: document.getElementById("meter-fill") as HTMLDivElement,
   items: document.getElementById("items") as HTMLDivElement,
   count: document.getElementById("count") as HTMLDivElement,
   audio: document.getElementById("audio") as HTMLAudioElement,
+  search: document.getElementById("search") as HTMLInputElement,
+  tagFilter: document.getElementById("tag-filter") as HTMLSelectElement,
+  btnMore: document.getElementById("btn-more") as HTMLButtonElement,
+  trash: document.getElementById("trash") as HTMLElement,
+  trashItems: document.getElementById("trash-items") as HTMLDivElement,
+  btnEmptyTrash: document.getElementById("btn-empty-trash") as HTMLButtonElement,
 };
 
 let isRecording = false;
+let isPaused = false;
+let lastError: string | null = null;
+let settings: Settings = { preferredInputDevice: null };
+// The list grows a page at a time; a new search starts over at one page.
+const PAGE_SIZE = 50;
+let listPage: RecordingPage = { items: [], total: 0 };
+// Bumped by every list load; a load that finds it changed was overtaken and drops its rows.
+let listGeneration = 0;
 
 function fmtDuration(sec: number): string {
   if (!Number.isFinite(sec) || sec < 0) return "0s";
@@ -43,21 +121,175 @@ function fmtBytes(bytes: number): string {
   return `${v.toFixed(dp)} ${units[u]}`;
 }
 
-function setRecordingState(next: boolean) {
-  isRecording = next;
-  els.status.textContent = isRecording ? "Recording…" : "Idle";
+function renderStatus() {
+  els.status.textContent = isRecording
+    ? isPaused
+      ? "Paused"
+      : "Recording…"
+    : lastError
+      ? `Stopped: ${lastError}`
+      : "Idle";
   els.btnStart.disabled = isRecording;
   els.btnStop.disabled = !isRecording;
+  els.btnPause.disabled = !isRecording;
+  els.btnPause.textContent = isPaused ? "Resume" : "Pause";
+}
+
+function renderLevel(level: InputLevel) {
+  // -60..0 dBFS mapped onto the bar width.
+  const db = 20 * Math.log10(Math.max(level.peak, 1e-6));
+  const pct = Math.min(Math.max((db + 60) / 60, 0), 1) * 100;
+  els.meterFill.style.width = `${pct.toFixed(0)}%`;
+}
+
+function setRecordingState(next: boolean) {
+  if (next) lastError = null;
+  isRecording = next;
+  if (!isRecording) {
+    isPaused = false;
+    renderLevel({ rms: 0, peak: 0 });
+  }
+  renderStatus();
+}
+
+function setPausedState(next: boolean) {
+  isPaused = next;
+  renderStatus();
+}
+
+function fmtChannels(channels: number): string {
+  if (channels <= 1) return "mono";
+  if (channels === 2) return "stereo";
+  return `${channels} ch`;
+}
+
+function fmtRate(hz: number): string {
+  return `${+(hz / 1000).toFixed(2)} kHz`;
+}
+
+// createdAt is RFC 3339 with an offset; shown in the viewer's local time.
+function createdDate(r: RecordingItem): Date | null {
+  const d = new Date(r.createdAt);
+  return Number.isNaN(d.getTime()) ? null : d;
+}
+
+function fmtTime(r: RecordingItem): string {
+  const d = createdDate(r);
+  return d
+    ? d.toLocaleTimeString([], { hour: "2-digit", minute: "2-digit", second: "2-digit" })
+    : r.createdAt;
+}
+
+function fmtDay(d: Date | null): string {
+  return d
+    ? d.toLocaleDateString([], { weekday: "short", year: "numeric", month: "short", day: "numeric" })
+    : "Unknown date";
 }
 
 function itemSub(r: RecordingItem): string {
-  return `${fmtDuration(r.durationSec)} • ${fmtBytes(r.sizeBytes)}`;
+  let duration = fmtDuration(r.durationSec);
+  if (r.wallClockSec !== null && r.wallClockSec - r.durationSec >= 1) {
+    duration += ` (of ${fmtDuration(r.wallClockSec)})`;
+  }
+  const parts = [duration, fmtBytes(r.sizeBytes), fmtChannels(r.channels)];
+  if (r.title !== null) parts.unshift(fmtTime(r));
+  if (r.sampleRate !== null) {
+    let rate = fmtRate(r.sampleRate);
+    if (r.deviceSampleRate !== null && r.deviceSampleRate !== r.sampleRate) {
+      rate += ` (from ${fmtRate(r.deviceSampleRate)})`;
+    }
+    parts.push(rate);
+  }
+  if (r.segments.length > 1) parts.push(`${r.segments.length} parts`);
+  if (r.inputDevice !== null) parts.push(r.inputDevice);
+  if (r.markers.length > 0) {
+    parts.push(`${r.markers.length} marker${r.markers.length === 1 ? "" : "s"}`);
+  }
+  if (r.recovered) parts.push("recovered");
+  if (r.tags.length > 0) parts.push(r.tags.map((t) => `#${t}`).join(" "));
+  return parts.join(" • ");
+}
+
+// Plays the parts of a recording back to back.
+async function playSegments(segments: Segment[], index = 0) {
+  const segment = segments[index];
+  if (!segment) return;
+  els.audio.src = convertFileSrc(segment.path);
+  els.audio.onended =
+    index + 1 < segments.length ? () => void playSegments(segments, index + 1) : null;
+  try {
+    await els.audio.play();
+  } catch {
+    // ignore autoplay restrictions; user can press play manually
+  }
 }
 
-function renderItems(items: RecordingItem[]) {
-  els.count.textContent = `${items.length}`;
+// Swaps a row's text for inputs; saving refreshes the list through `recordings_updated`.
+function editMetadata(r: RecordingItem, main: HTMLDivElement) {
+  main.textContent = "";
+  const form = document.createElement("form");
+  form.className = "item-edit";
+
+  const title = document.createElement("input");
+  title.placeholder = "Title";
+  title.value = r.title ?? "";
+
+  const notes = document.createElement("textarea");
+  notes.placeholder = "Notes";
+  notes.rows = 2;
+  notes.value = r.notes ?? "";
+
+  const tags = document.createElement("input");
+  tags.placeholder = "Tags, comma separated";
+  tags.value = r.tags.join(", ");
+
+  const buttons = document.createElement("div");
+  buttons.className = "item-actions";
+  const btnSave = document.createElement("button");
+  btnSave.className = "linkbtn";
+  btnSave.type = "submit";
+  btnSave.textContent = "Save";
+  const btnCancel = document.createElement("button");
+  btnCancel.className = "linkbtn";
+  btnCancel.type = "button";
+  btnCancel.textContent = "Cancel";
+  btnCancel.addEventListener("click", () => void refresh());
+  buttons.appendChild(btnSave);
+  buttons.appendChild(btnCancel);
+
+  form.addEventListener("submit", async (event) => {
+    event.preventDefault();
+    await invoke("update_recording_metadata", {
+      id: r.id,
+      title: title.value,
+      notes: notes.value,
+      tags: tags.value.split(","),
+    });
+  });
+
+  form.appendChild(title);
+  form.appendChild(notes);
+  form.appendChild(tags);
+  form.appendChild(buttons);
+  main.appendChild(form);
+  title.focus();
+}
+
+function renderItems(page: RecordingPage, filtered: boolean) {
+  const items = page.items;
+  els.count.textContent =
+    items.length < page.total ? `${items.length} of ${page.total}` : `${page.total}`;
+  els.btnMore.hidden = items.length >= page.total;
   els.items.textContent = "";
 
+  if (items.length === 0 && filtered) {
+    const empty = document.createElement("div");
+    empty.className = "item";
+    empty.innerHTML =
+      '<div class="item-main"><div class="item-title">No matching recordings</div></div><div class="item-actions"></div>';
+    els.items.appendChild(empty);
+    return;
+  }
   if (items.length === 0) {
     const empty = document.createElement("div");
     empty.className = "item";
@@ -67,7 +299,17 @@ function renderItems(items: RecordingItem[]) {
     return;
   }
 
+  let day: string | null = null;
   for (const r of items) {
+    const rowDay = fmtDay(createdDate(r));
+    if (rowDay !== day) {
+      day = rowDay;
+      const header = document.createElement("div");
+      header.className = "day-header";
+      header.textContent = rowDay;
+      els.items.appendChild(header);
+    }
+
     const row = document.createElement("div");
     row.className = "item";
 
@@ -76,7 +318,7 @@ function renderItems(items: RecordingItem[]) {
 
     const title = document.createElement("div");
     title.className = "item-title";
-    title.textContent = r.createdAt.replace("T", " ");
+    title.textContent = r.title ?? fmtTime(r);
 
     const sub = document.createElement("div");
     sub.className = "item-sub";
@@ -92,15 +334,7 @@ function renderItems(items: RecordingItem[]) {
     btnPlay.className = "linkbtn";
     btnPlay.type = "button";
     btnPlay.textContent = "Play";
-    btnPlay.addEventListener("click", async () => {
-      const src = convertFileSrc(r.path);
-      els.audio.src = src;
-      try {
-        await els.audio.play();
-      } catch {
-        // ignore autoplay restrictions; user can press play manually
-      }
-    });
+    btnPlay.addEventListener("click", () => playSegments(r.segments));
 
     const btnReveal = document.createElement("button");
     btnReveal.className = "linkbtn";
@@ -110,8 +344,35 @@ function renderItems(items: RecordingItem[]) {
       await invoke("reveal_in_finder", { path: r.path });
     });
 
+    const btnEdit = document.createElement("button");
+    btnEdit.className = "linkbtn";
+    btnEdit.type = "button";
+    btnEdit.textContent = "Edit";
+    btnEdit.addEventListener("click", () => editMetadata(r, main));
+
+    // Names the file after the title, so it can be found outside Kiklet too.
+    const btnRename = document.createElement("button");
+    btnRename.className = "linkbtn";
+    btnRename.type = "button";
+    btnRename.textContent = "Rename File";
+    btnRename.hidden = r.title === null;
+    btnRename.addEventListener("click", async () => {
+      await invoke("rename_recording", { id: r.id });
+    });
+
+    const btnDelete = document.createElement("button");
+    btnDelete.className = "linkbtn";
+    btnDelete.type = "button";
+    btnDelete.textContent = "Delete";
+    btnDelete.addEventListener("click", async () => {
+      await invoke("delete_recording", { id: r.id });
+    });
+
     actions.appendChild(btnPlay);
     actions.appendChild(btnReveal);
+    actions.appendChild(btnEdit);
+    actions.appendChild(btnRename);
+    actions.appendChild(btnDelete);
 
     row.appendChild(main);
     row.appendChild(actions);
@@ -119,15 +380,177 @@ function renderItems(items: RecordingItem[]) {
   }
 }
 
+function renderTrash(items: TrashItem[]) {
+  els.trash.hidden = items.length === 0;
+  els.trashItems.textContent = "";
+
+  for (const t of items) {
+    const row = document.createElement("div");
+    row.className = "item";
+
+    const main = document.createElement("div");
+    main.className = "item-main";
+
+    const title = document.createElement("div");
+    title.className = "item-title";
+    title.textContent = t.title ?? t.filename;
+
+    const sub = document.createElement("div");
+    sub.className = "item-sub";
+    const parts = [fmtDuration(t.durationSec), fmtBytes(t.sizeBytes)];
+    parts.push(`deleted ${new Date(t.deletedAt).toLocaleDateString()}`);
+    if (t.purgeAt !== null) parts.push(`gone ${new Date(t.purgeAt).toLocaleDateString()}`);
+    sub.textContent = parts.join(" • ");
+
+    main.appendChild(title);
+    main.appendChild(sub);
+
+    const actions = document.createElement("div");
+    actions.className = "item-actions";
+
+    const btnRestore = document.createElement("button");
+    btnRestore.className = "linkbtn";
+    btnRestore.type = "button";
+    btnRestore.textContent = "Restore";
+    btnRestore.addEventListener("click", async () => {
+      await invoke("restore_recording", { id: t.id });
+    });
+    actions.appendChild(btnRestore);
+
+    row.appendChild(main);
+    row.appendChild(actions);
+    els.trashItems.appendChild(row);
+  }
+}
+
+function renderTagFilter(tags: TagItem[]) {
+  const selected = els.tagFilter.value;
+  els.tagFilter.textContent = "";
+  const all = document.createElement("option");
+  all.value = "";
+  all.textContent = "All tags";
+  els.tagFilter.appendChild(all);
+  for (const t of tags) {
+    const opt = document.createElement("option");
+    opt.value = t.tag;
+    opt.textContent = `${t.tag} (${t.count})`;
+    els.tagFilter.appendChild(opt);
+  }
+  // A tag that went away while selected falls back to all.
+  els.tagFilter.value = tags.some((t) => t.tag === selected) ? selected : "";
+  els.tagFilter.hidden = tags.length === 0;
+}
+
+function isFiltered(): boolean {
+  return els.search.value.trim() !== "" || els.tagFilter.value !== "";
+}
+
+// Fetches the page starting at `offset` for the current search and tag filter.
+async function fetchPage(offset: number): Promise<RecordingPage> {
+  const text = els.search.value.trim();
+  const tag = els.tagFilter.value;
+  return (await invoke("list_recordings", {
+    query: { text: text || null, tag: tag || null, offset, limit: PAGE_SIZE },
+  })) as RecordingPage;
+}
+
 async function refresh() {
-  const items = (await invoke("list_recordings")) as RecordingItem[];
-  renderItems(items);
+  const generation = ++listGeneration;
+  renderTagFilter((await invoke("list_tags")) as TagItem[]);
+  renderTrash((await invoke("list_trash")) as TrashItem[]);
+  // Reload as many rows as were shown, a page per request, so the list keeps its
+  // length however far it was paged.
+  const shown = Math.max(listPage.items.length, PAGE_SIZE);
+  let items: RecordingItem[] = [];
+  let total = 0;
+  while (items.length < shown) {
+    const page = await fetchPage(items.length);
+    if (generation !== listGeneration) return;
+    items = items.concat(page.items);
+    total = page.total;
+    if (page.items.length < PAGE_SIZE) break;
+  }
+  listPage = { items, total };
+  renderItems(listPage, isFiltered());
+}
+
+async function search() {
+  listPage = { items: [], total: 0 };
+  await refresh();
+}
+
+async function showMore() {
+  const generation = ++listGeneration;
+  const page = await fetchPage(listPage.items.length);
+  if (generation !== listGeneration) return;
+  listPage = { items: listPage.items.concat(page.items), total: page.total };
+  renderItems(listPage, isFiltered());
+}
+
+async function emptyTrash() {
+  await invoke("empty_trash");
+  await refresh();
+}
+
+async function refreshDevices() {
+  settings = (await invoke("get_settings")) as Settings;
+  let devices: InputDevice[] = [];
+  try {
+    devices = (await invoke("list_input_devices")) as InputDevice[];
+  } catch {
+    // no audio host available; keep the "System default" entry only
+  }
+
+  els.device.textContent = "";
+  const def = document.createElement("option");
+  def.value = "";
+  def.textContent = "System default";
+  els.device.appendChild(def);
+
+  const preferred = settings.preferredInputDevice;
+  if (preferred && !devices.some((d) => d.id === preferred)) {
+    // Keep a disconnected preference visible instead of silently dropping it.
+    devices.push({ id: preferred, name: `${preferred} (unavailable)`, isDefault: false });
+  }
+  for (const d of devices) {
+    const opt = document.createElement("option");
+    opt.value = d.id;
+    opt.textContent = d.isDefault ? `${d.name} (default)` : d.name;
+    els.device.appendChild(opt);
+  }
+  els.device.value = preferred ?? "";
+}
+
+async function testDevice() {
+  els.btnTest.disabled = true;
+  try {
+    const level = (await invoke("get_input_level", {
+      deviceId: els.device.value || null,
+    })) as InputLevel;
+    renderLevel(level);
+    if (!isRecording) {
+      setTimeout(() => {
+        if (!isRecording) renderLevel({ rms: 0, peak: 0 });
+      }, 1500);
+    }
+  } finally {
+    els.btnTest.disabled = false;
+  }
+}
+
+async function selectDevice() {
+  const next = { ...settings, preferredInputDevice: els.device.value || null };
+  settings = (await invoke("update_settings", { settings: next })) as Settings;
 }
 
 async function start() {
   await invoke("start_recording");
 }
 
+async function togglePause() {
+  await invoke(isPaused ? "resume_recording" : "pause_recording");
+}
+
 async function stop() {
   await invoke("stop_recording");
 }
@@ -138,15 +561,35 @@ async function openFolder() {
 
 window.addEventListener("DOMContentLoaded", async () => {
   els.btnStart.addEventListener("click", start);
+  els.btnPause.addEventListener("click", togglePause);
   els.btnStop.addEventListener("click", stop);
   els.btnFolder.addEventListener("click", openFolder);
+  els.btnEmptyTrash.addEventListener("click", emptyTrash);
+  els.search.addEventListener("input", search);
+  els.tagFilter.addEventListener("change", search);
+  els.btnMore.addEventListener("click", showMore);
+  els.device.addEventListener("change", selectDevice);
+  els.btnTest.addEventListener("click", testDevice);
+  // Pick up headsets plugged in while the window was hidden.
+  window.addEventListener("focus", refreshDevices);
 
   setRecordingState(false);
+  await refreshDevices();
   await refresh();
 
   await listen<boolean>("recording_state", (event) => {
     setRecordingState(Boolean(event.payload));
   });
+  await listen<boolean>("recording_paused", (event) => {
+    setPausedState(Boolean(event.payload));
+  });
+  await listen<string>("recording_error", (event) => {
+    lastError = String(event.payload);
+    renderStatus();
+  });
+  await listen<InputLevel>("input_level", (event) => {
+    renderLevel(event.payload);
+  });
   await listen("recordings_updated", async () => {
     await refresh();
   });